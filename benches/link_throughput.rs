@@ -0,0 +1,272 @@
+//! Measures how quickly packets cross a queue between two threads, through a
+//! crossbeam channel, which AsyncElementLink used to be built on, and through
+//! the SPSC ring it is built on now, both waiting the same way on a full or
+//! empty queue. It then measures how quickly packets make it down a chain of
+//! links built the old way, on a crossbeam channel with wakers handed over
+//! through channels of their own, and down a chain of AsyncElementLinks, as
+//! well as how much a chain of ElementLinks gains from being built without
+//! boxing.
+//!
+//! Run with `cargo bench --bench link_throughput`.
+
+use crossbeam::channel::{bounded, Receiver, Sender, TryRecvError, TrySendError};
+use futures::future::{BoxFuture, FutureExt};
+use futures::{ready, Stream, StreamExt};
+use route_rs::api::{ring, AsyncElement, AsyncElementLink, Element, ElementLink, ElementStream, PopError, PushError, Verdict};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PACKETS: usize = 4_000_000;
+const QUEUE_CAPACITY: usize = 256;
+const CHAIN_LENGTH: usize = 4;
+const CHAIN_PACKETS: usize = 1_000_000;
+
+fn report(name: &str, packets: usize, elapsed: Duration) {
+    let rate = packets as f64 / elapsed.as_secs_f64();
+    println!("{:<28} {:>8.2} Mpackets/s ({:?})", name, rate / 1e6, elapsed);
+}
+
+fn crossbeam_channel() -> Duration {
+    let (sender, receiver) = bounded::<usize>(QUEUE_CAPACITY);
+    let start = Instant::now();
+    let producer = thread::spawn(move || {
+        let mut packet = 0;
+        while packet < PACKETS {
+            match sender.try_send(packet) {
+                Ok(()) => packet += 1,
+                Err(TrySendError::Full(_)) => thread::yield_now(),
+                Err(TrySendError::Disconnected(_)) => panic!("consumer went away")
+            }
+        }
+    });
+    let mut received = 0;
+    loop {
+        match receiver.try_recv() {
+            Ok(_) => received += 1,
+            Err(TryRecvError::Empty) => thread::yield_now(),
+            Err(TryRecvError::Disconnected) => break
+        }
+    }
+    producer.join().unwrap();
+    assert_eq!(received, PACKETS);
+    start.elapsed()
+}
+
+fn spsc_ring() -> Duration {
+    let (mut producer, mut consumer) = ring::<usize>(QUEUE_CAPACITY);
+    let start = Instant::now();
+    let producer = thread::spawn(move || {
+        let mut packet = 0;
+        while packet < PACKETS {
+            match producer.try_push(packet) {
+                Ok(()) => packet += 1,
+                Err(PushError::Full(_)) => thread::yield_now(),
+                Err(PushError::Closed(_)) => panic!("consumer went away")
+            }
+        }
+    });
+    let mut received = 0;
+    loop {
+        match consumer.try_pop() {
+            Ok(_) => received += 1,
+            Err(PopError::Empty) => thread::yield_now(),
+            Err(PopError::Closed) => break
+        }
+    }
+    producer.join().unwrap();
+    assert_eq!(received, PACKETS);
+    start.elapsed()
+}
+
+struct Identity;
+
+impl AsyncElement for Identity {
+    type Input = usize;
+    type Output = usize;
+
+    fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+        Verdict::Pass(packet)
+    }
+}
+
+impl Element for Identity {
+    type Input = usize;
+    type Output = usize;
+
+    fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+        Verdict::Pass(packet)
+    }
+}
+
+/// The link AsyncElementLink used to be: a crossbeam channel between consumer
+/// and provider, with each side handing the other its waker through a
+/// channel of one before it sleeps.
+struct ChannelLink {
+    consumer: ChannelConsumer,
+    provider: ChannelProvider
+}
+
+impl ChannelLink {
+    fn new(input_stream: ElementStream<usize>, element: Identity, queue_capacity: usize) -> Self {
+        let (to_provider, from_consumer) = bounded::<Option<usize>>(queue_capacity);
+        let (await_provider, wake_provider) = bounded::<Waker>(1);
+        let (await_consumer, wake_consumer) = bounded::<Waker>(1);
+        ChannelLink {
+            consumer: ChannelConsumer { input_stream, to_provider, element, await_provider: await_consumer, wake_provider },
+            provider: ChannelProvider { from_consumer, await_consumer: await_provider, wake_consumer }
+        }
+    }
+}
+
+struct ChannelConsumer {
+    input_stream: ElementStream<usize>,
+    to_provider: Sender<Option<usize>>,
+    element: Identity,
+    await_provider: Sender<Waker>,
+    wake_provider: Receiver<Waker>
+}
+
+impl Drop for ChannelConsumer {
+    fn drop(&mut self) {
+        if let Err(err) = self.to_provider.try_send(None) {
+            panic!("Consumer: Drop: try_send to_provider, fail?: {:?}", err);
+        }
+        if let Ok(waker) = self.wake_provider.try_recv() {
+            waker.wake();
+        }
+    }
+}
+
+impl Future for ChannelConsumer {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let consumer = self.get_mut();
+        loop {
+            if consumer.to_provider.is_full() {
+                if consumer.await_provider.try_send(cx.waker().clone()).is_err() {
+                    cx.waker().wake_by_ref();
+                }
+                return Poll::Pending
+            }
+            match ready!(consumer.input_stream.as_mut().poll_next(cx)) {
+                None => return Poll::Ready(()),
+                Some(packet) => {
+                    if let Verdict::Pass(packet) = AsyncElement::process(&mut consumer.element, packet) {
+                        consumer.to_provider.send(Some(packet)).unwrap();
+                        if let Ok(waker) = consumer.wake_provider.try_recv() {
+                            waker.wake();
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+struct ChannelProvider {
+    from_consumer: Receiver<Option<usize>>,
+    await_consumer: Sender<Waker>,
+    wake_consumer: Receiver<Waker>
+}
+
+impl Drop for ChannelProvider {
+    fn drop(&mut self) {
+        if let Ok(waker) = self.wake_consumer.try_recv() {
+            waker.wake();
+        }
+    }
+}
+
+impl Stream for ChannelProvider {
+    type Item = usize;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        match self.from_consumer.try_recv() {
+            Ok(Some(packet)) => {
+                if let Ok(waker) = self.wake_consumer.try_recv() {
+                    waker.wake();
+                }
+                Poll::Ready(Some(packet))
+            },
+            Ok(None) | Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => {
+                if self.await_consumer.try_send(cx.waker().clone()).is_err() {
+                    cx.waker().wake_by_ref();
+                }
+                Poll::Pending
+            }
+        }
+    }
+}
+
+fn boxed_sync_chain() -> Duration {
+    futures::executor::block_on(async {
+        let start = Instant::now();
+        let mut stream: ElementStream<usize> = Box::pin(futures::stream::iter(0..PACKETS));
+        for _ in 0..CHAIN_LENGTH {
+            stream = Box::pin(ElementLink::new(stream, Identity));
+        }
+        assert_eq!(stream.count().await, PACKETS);
+        start.elapsed()
+    })
+}
+
+fn static_sync_chain() -> Duration {
+    futures::executor::block_on(async {
+        let start = Instant::now();
+        let stream = futures::stream::iter(0..PACKETS);
+        let stream = ElementLink::new(stream, Identity);
+        let stream = ElementLink::new(stream, Identity);
+        let stream = ElementLink::new(stream, Identity);
+        let stream = ElementLink::new(stream, Identity);
+        assert_eq!(stream.count().await, PACKETS);
+        start.elapsed()
+    })
+}
+
+/// Sends CHAIN_PACKETS down a chain of links, each made by `link` out of the
+/// stream before it and handing back its consumer and provider, with the
+/// consumers spawned onto the same runtime.
+fn link_chain<L>(link: L) -> Duration
+    where L: Fn(ElementStream<usize>) -> (BoxFuture<'static, ()>, ElementStream<usize>)
+{
+    let runtime = tokio::runtime::Builder::new_multi_thread().build().unwrap();
+    runtime.block_on(async {
+        let start = Instant::now();
+        let mut stream: ElementStream<usize> = Box::pin(futures::stream::iter(0..CHAIN_PACKETS));
+        let mut consumers = Vec::with_capacity(CHAIN_LENGTH);
+        for _ in 0..CHAIN_LENGTH {
+            let (consumer, provider) = link(stream);
+            consumers.push(tokio::spawn(consumer));
+            stream = provider;
+        }
+        assert_eq!(stream.count().await, CHAIN_PACKETS);
+        for consumer in consumers {
+            consumer.await.unwrap();
+        }
+        start.elapsed()
+    })
+}
+
+fn channel_link(stream: ElementStream<usize>) -> (BoxFuture<'static, ()>, ElementStream<usize>) {
+    let link = ChannelLink::new(stream, Identity, QUEUE_CAPACITY);
+    (link.consumer.boxed(), Box::pin(link.provider))
+}
+
+fn async_element_link(stream: ElementStream<usize>) -> (BoxFuture<'static, ()>, ElementStream<usize>) {
+    let link = AsyncElementLink::new(stream, Identity, QUEUE_CAPACITY);
+    (link.consumer.boxed(), Box::pin(link.provider))
+}
+
+fn main() {
+    report("crossbeam channel", PACKETS, crossbeam_channel());
+    report("spsc ring", PACKETS, spsc_ring());
+    report("channel link chain", CHAIN_PACKETS, link_chain(channel_link));
+    report("AsyncElementLink chain", CHAIN_PACKETS, link_chain(async_element_link));
+    report("boxed ElementLink chain", PACKETS, boxed_sync_chain());
+    report("static ElementLink chain", PACKETS, static_sync_chain());
+}
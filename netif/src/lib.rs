@@ -1,6 +1,5 @@
 //! This crate defines an interface for accessing a raw network interface.
 //! Actual implementations are found in sister crates.
-#![feature(async_await)]
 
 mod memory;
 
@@ -10,10 +9,10 @@ use futures::{Stream, Sink};
 // TODO a real error type
 pub use failure::Error;
 
-pub trait Receiver<'memory> : Stream<Item=Packet<'memory>, Error=Error> {
+pub trait Receiver<'memory> : Stream<Item=Result<Packet<'memory>, Error>> {
 }
 
-pub trait Sender<'memory> : Sink<SinkItem=PacketMut<'memory>, SinkError=Error> {
+pub trait Sender<'memory> : Sink<PacketMut<'memory>, Error=Error> {
 }
 
 pub trait NetIf {
@@ -0,0 +1,208 @@
+use std::fmt;
+
+/// The type of an element's configuration argument.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArgType {
+    Int,
+    Float,
+    Bool,
+    /// Any string. Surrounding double quotes are stripped.
+    Str
+}
+
+impl fmt::Display for ArgType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArgType::Int => write!(f, "integer"),
+            ArgType::Float => write!(f, "float"),
+            ArgType::Bool => write!(f, "boolean"),
+            ArgType::Str => write!(f, "string")
+        }
+    }
+}
+
+/// A parsed configuration argument.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ArgValue {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Str(String)
+}
+
+impl ArgValue {
+    pub fn arg_type(&self) -> ArgType {
+        match self {
+            ArgValue::Int(_) => ArgType::Int,
+            ArgValue::Float(_) => ArgType::Float,
+            ArgValue::Bool(_) => ArgType::Bool,
+            ArgValue::Str(_) => ArgType::Str
+        }
+    }
+
+    fn parse(arg_type: ArgType, text: &str) -> Option<ArgValue> {
+        match arg_type {
+            ArgType::Int => text.parse().ok().map(ArgValue::Int),
+            ArgType::Float => text.parse().ok().map(ArgValue::Float),
+            ArgType::Bool => match text {
+                "true" => Some(ArgValue::Bool(true)),
+                "false" => Some(ArgValue::Bool(false)),
+                _ => None
+            },
+            ArgType::Str => {
+                let unquoted = text.strip_prefix('"').and_then(|text| text.strip_suffix('"')).unwrap_or(text);
+                Some(ArgValue::Str(unquoted.to_string()))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Param {
+    name: String,
+    arg_type: ArgType,
+    default: Option<ArgValue>
+}
+
+/// The arguments an element class takes, in order. Required arguments must
+/// come before optional ones, which take their default when left out.
+#[derive(Clone, Debug, Default)]
+pub struct Schema {
+    params: Vec<Param>
+}
+
+impl Schema {
+    pub fn new() -> Self {
+        Schema::default()
+    }
+
+    pub fn required(mut self, name: &str, arg_type: ArgType) -> Self {
+        assert!(self.params.iter().all(|param| param.default.is_none()),
+            "required argument {} follows an optional one", name);
+        self.params.push(Param { name: name.to_string(), arg_type, default: None });
+        self
+    }
+
+    pub fn optional(mut self, name: &str, arg_type: ArgType, default: ArgValue) -> Self {
+        assert_eq!(default.arg_type(), arg_type, "default for {} does not match its type", name);
+        self.params.push(Param { name: name.to_string(), arg_type, default: Some(default) });
+        self
+    }
+
+    /// Checks `args` against the schema and parses each of them, filling in
+    /// defaults for any optional arguments left out.
+    pub fn parse(&self, args: &[String]) -> Result<Args, String> {
+        if args.len() > self.params.len() {
+            return Err(format!("expected at most {} arguments, got {}", self.params.len(), args.len()))
+        }
+        let mut values = Vec::with_capacity(self.params.len());
+        for (index, param) in self.params.iter().enumerate() {
+            let value = match (args.get(index), &param.default) {
+                (Some(text), _) => ArgValue::parse(param.arg_type, text).ok_or_else(|| {
+                    format!("{}: expected {}, got '{}'", param.name, param.arg_type, text)
+                })?,
+                (None, Some(default)) => default.clone(),
+                (None, None) => return Err(format!("missing argument {}", param.name))
+            };
+            values.push((param.name.clone(), value));
+        }
+        Ok(Args { values })
+    }
+}
+
+impl fmt::Display for Schema {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, param) in self.params.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            match param.default {
+                None => write!(f, "{}: {}", param.name, param.arg_type)?,
+                Some(_) => write!(f, "[{}: {}]", param.name, param.arg_type)?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Arguments that have been checked against a Schema. Asking for an argument
+/// the schema does not have, or as the wrong type, is a bug in the element's
+/// constructor, and panics.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Args {
+    values: Vec<(String, ArgValue)>
+}
+
+impl Args {
+    pub fn get(&self, name: &str) -> &ArgValue {
+        self.values.iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value)
+            .unwrap_or_else(|| panic!("no argument named {}", name))
+    }
+
+    pub fn int(&self, name: &str) -> i64 {
+        match self.get(name) {
+            ArgValue::Int(value) => *value,
+            value => panic!("argument {} is not an integer: {:?}", name, value)
+        }
+    }
+
+    pub fn float(&self, name: &str) -> f64 {
+        match self.get(name) {
+            ArgValue::Float(value) => *value,
+            value => panic!("argument {} is not a float: {:?}", name, value)
+        }
+    }
+
+    pub fn bool(&self, name: &str) -> bool {
+        match self.get(name) {
+            ArgValue::Bool(value) => *value,
+            value => panic!("argument {} is not a boolean: {:?}", name, value)
+        }
+    }
+
+    pub fn str(&self, name: &str) -> &str {
+        match self.get(name) {
+            ArgValue::Str(value) => value,
+            value => panic!("argument {} is not a string: {:?}", name, value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_typed_arguments_with_defaults() {
+        let schema = Schema::new()
+            .required("COUNT", ArgType::Int)
+            .required("NAME", ArgType::Str)
+            .optional("RATE", ArgType::Float, ArgValue::Float(1.5))
+            .optional("ACTIVE", ArgType::Bool, ArgValue::Bool(true));
+
+        let parsed = schema.parse(&args(&["-3", "\"eth0\"", "0.25"])).unwrap();
+        assert_eq!(parsed.int("COUNT"), -3);
+        assert_eq!(parsed.str("NAME"), "eth0");
+        assert_eq!(parsed.float("RATE"), 0.25);
+        assert!(parsed.bool("ACTIVE"));
+        assert_eq!(schema.to_string(), "COUNT: integer, NAME: string, [RATE: float], [ACTIVE: boolean]");
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        let schema = Schema::new()
+            .required("COUNT", ArgType::Int)
+            .optional("ACTIVE", ArgType::Bool, ArgValue::Bool(false));
+
+        assert_eq!(schema.parse(&args(&[])).unwrap_err(), "missing argument COUNT");
+        assert_eq!(schema.parse(&args(&["ten"])).unwrap_err(), "COUNT: expected integer, got 'ten'");
+        assert_eq!(schema.parse(&args(&["1", "yes"])).unwrap_err(), "ACTIVE: expected boolean, got 'yes'");
+        assert_eq!(schema.parse(&args(&["1", "true", "2"])).unwrap_err(), "expected at most 2 arguments, got 3");
+    }
+}
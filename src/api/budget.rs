@@ -0,0 +1,249 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::api::Counter;
+
+/// Reports how many bytes a packet takes up, so that queues can be bounded
+/// by bytes as well as by packets. A queue of ten jumbo frames holds a good
+/// deal more than a queue of ten ACKs.
+pub trait PacketSize {
+    fn packet_size(&self) -> usize;
+}
+
+impl PacketSize for Vec<u8> {
+    fn packet_size(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<P: PacketSize> PacketSize for Arc<P> {
+    fn packet_size(&self) -> usize {
+        (**self).packet_size()
+    }
+}
+
+#[derive(Debug)]
+struct Budget {
+    limit: usize,
+    used: AtomicUsize,
+    drops: Counter
+}
+
+/// A MemoryBudget caps the bytes held in the queues of every link it is
+/// handed to, across the whole router. A packet that would take the total
+/// over the limit is dropped instead of queued, whatever the link's
+/// OverflowPolicy, since sleeping would not free up the memory held by
+/// other links. Cloning a MemoryBudget is cheap, and clones share the limit.
+#[derive(Clone, Debug)]
+pub struct MemoryBudget(Arc<Budget>);
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget(Arc::new(Budget { limit, used: AtomicUsize::new(0), drops: Counter::new() }))
+    }
+
+    pub fn limit(&self) -> usize {
+        self.0.limit
+    }
+
+    /// Bytes held in queues right now.
+    pub fn used(&self) -> usize {
+        self.0.used.load(Ordering::Relaxed)
+    }
+
+    /// Number of packets dropped for going over the budget, on any link.
+    pub fn drop_counter(&self) -> Counter {
+        self.0.drops.clone()
+    }
+
+    fn try_reserve(&self, bytes: usize) -> bool {
+        let limit = self.0.limit;
+        let reserved = self.0.used.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+            used.checked_add(bytes).filter(|total| *total <= limit)
+        });
+        if reserved.is_err() {
+            self.0.drops.incr();
+        }
+        reserved.is_ok()
+    }
+
+    fn release(&self, bytes: usize) {
+        self.0.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// The bytes held in one link's queue, and the budget they count against.
+struct Queued {
+    bytes: AtomicUsize,
+    budget: Option<MemoryBudget>
+}
+
+impl Drop for Queued {
+    /// Both ends of the queue are gone, so whatever packets are left in it
+    /// go with them, and we give their bytes back to the budget. These are
+    /// packets the consumer pushed as the provider went away, too late for
+    /// the provider to drain them.
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.release(*self.bytes.get_mut());
+        }
+    }
+}
+
+/// The bytes in one link's queue, shared between its consumer, which adds
+/// packets, and its provider, which takes them off.
+pub(crate) struct QueueBytes<Packet> {
+    size: fn(&Packet) -> usize,
+    queued: Arc<Queued>,
+    capacity: Option<usize>
+}
+
+impl<Packet> Clone for QueueBytes<Packet> {
+    fn clone(&self) -> Self {
+        QueueBytes {
+            size: self.size,
+            queued: Arc::clone(&self.queued),
+            capacity: self.capacity
+        }
+    }
+}
+
+impl<Packet: PacketSize> QueueBytes<Packet> {
+    pub(crate) fn new() -> Self {
+        let queued = Queued { bytes: AtomicUsize::new(0), budget: None };
+        QueueBytes { size: Packet::packet_size, queued: Arc::new(queued), capacity: None }
+    }
+}
+
+impl<Packet> QueueBytes<Packet> {
+    pub(crate) fn with_capacity(self, capacity: usize) -> Self {
+        QueueBytes { capacity: Some(capacity), ..self }
+    }
+
+    /// Counts the queue against `budget`. This is done while the link is
+    /// being built, before any packet is queued.
+    pub(crate) fn with_budget(self, budget: MemoryBudget) -> Self {
+        let queued = Queued { bytes: AtomicUsize::new(0), budget: Some(budget) };
+        QueueBytes { queued: Arc::new(queued), ..self }
+    }
+
+    /// Whether the queue is bounded by bytes, rather than only counted
+    /// against a budget.
+    pub(crate) fn is_bounded(&self) -> bool {
+        self.capacity.is_some()
+    }
+
+    pub(crate) fn size_of(&self, packet: &Packet) -> usize {
+        (self.size)(packet)
+    }
+
+    /// Whether `size` more bytes fit in the queue. A packet bigger than the
+    /// whole queue still goes onto an empty one, or it would never leave.
+    pub(crate) fn fits(&self, size: usize) -> bool {
+        match self.capacity {
+            Some(capacity) => {
+                let queued = self.queued.bytes.load(Ordering::Relaxed);
+                queued == 0 || queued + size <= capacity
+            },
+            None => true
+        }
+    }
+
+    /// Takes `size` bytes out of the budget, if there is one, for a packet
+    /// about to be queued. Returns false if that would go over budget.
+    pub(crate) fn reserve(&self, size: usize) -> bool {
+        if let Some(budget) = &self.queued.budget {
+            if !budget.try_reserve(size) {
+                return false
+            }
+        }
+        self.queued.bytes.fetch_add(size, Ordering::Relaxed);
+        true
+    }
+
+    /// Gives back the bytes of a packet that left the queue, or never made
+    /// it on.
+    pub(crate) fn release(&self, size: usize) {
+        self.queued.bytes.fetch_sub(size, Ordering::Relaxed);
+        if let Some(budget) = &self.queued.budget {
+            budget.release(size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{AsyncElement, AsyncElementLink, OverflowPolicy, Verdict};
+    use crate::utils::test::packet_generators::immediate_stream;
+    use futures::StreamExt;
+
+    struct Identity;
+
+    impl AsyncElement for Identity {
+        type Input = Vec<u8>;
+        type Output = Vec<u8>;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            Verdict::Pass(packet)
+        }
+    }
+
+    fn packets(sizes: Vec<usize>) -> Vec<Vec<u8>> {
+        sizes.into_iter().map(|size| vec![0; size]).collect()
+    }
+
+    #[tokio::test]
+    async fn bounds_queue_by_bytes() {
+        // Room for a hundred packets, but only 3000 bytes, which two jumbo
+        // frames fill up before the provider takes any of them.
+        let input = packets(vec![1500, 1500, 1500, 64, 64, 64]);
+        let link = AsyncElementLink::new(immediate_stream(input), Identity, 100)
+            .overflow(OverflowPolicy::TailDrop)
+            .byte_capacity(3000);
+        let overflows = link.consumer.overflow_counter();
+        tokio::spawn(link.consumer).await.unwrap();
+
+        let sizes: Vec<usize> = link.provider.map(|packet| packet.len()).collect().await;
+        assert_eq!(sizes, vec![1500, 1500]);
+        assert_eq!(overflows.get(), 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "HeadDrop cannot be combined with a byte capacity")]
+    fn refuses_head_drop_with_byte_capacity() {
+        // Dropping the oldest packets by count would never make room in a
+        // queue that is full by bytes.
+        let _ = AsyncElementLink::new(immediate_stream(packets(vec![64])), Identity, 10)
+            .byte_capacity(3000)
+            .overflow(OverflowPolicy::HeadDrop);
+    }
+
+    #[test]
+    fn gives_back_bytes_left_queued_when_both_ends_are_gone() {
+        // As a packet pushed while the provider drained on its way out
+        // would be: reserved, but never taken off the queue.
+        let budget = MemoryBudget::new(2000);
+        let consumer = QueueBytes::<Vec<u8>>::new().with_budget(budget.clone());
+        let provider = consumer.clone();
+        assert!(consumer.reserve(1500));
+        drop(provider);
+        assert_eq!(budget.used(), 1500);
+        drop(consumer);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[tokio::test]
+    async fn shares_budget_across_links() {
+        let budget = MemoryBudget::new(2000);
+        let first = AsyncElementLink::new(immediate_stream(packets(vec![1000; 3])), Identity, 10).memory_budget(&budget);
+        let second = AsyncElementLink::new(immediate_stream(packets(vec![600; 3])), Identity, 10).memory_budget(&budget);
+        tokio::spawn(first.consumer).await.unwrap();
+        tokio::spawn(second.consumer).await.unwrap();
+
+        // The first link takes all it can, leaving the second link no room.
+        assert_eq!(budget.used(), 2000);
+        assert_eq!(budget.drop_counter().get(), 4);
+        assert_eq!(first.provider.count().await, 2);
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -0,0 +1,332 @@
+use futures::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use crate::api::{ElementStream, LinkMetrics, ring, RingProducer, RingConsumer, PushError};
+
+/// A ClassifyElement inspects each packet and picks which output port it
+/// should leave on, much like Click's `Classifier`. Ports are numbered from
+/// 0 up to the number of ports the `ClassifyElementLink` was built with.
+pub trait ClassifyElement {
+    type Packet: Sized;
+
+    fn classify(&mut self, packet: &Self::Packet) -> usize;
+}
+
+/// The ClassifyElementLink is the multi-output sibling of the AsyncElementLink.
+/// It contains one consumer, which pulls packets from the input stream and
+/// classifies them, and one provider per output port. Every port has its own
+/// bounded queue, a ring like the AsyncElementLink's, so a slow branch only
+/// applies back-pressure to the packets destined for it.
+pub struct ClassifyElementLink<E: ClassifyElement> {
+    pub consumer: ClassifyElementConsumer<E>,
+    pub providers: Vec<ClassifyElementProvider<E>>
+}
+
+impl<E: ClassifyElement> ClassifyElementLink<E> {
+    pub fn new(input_stream: ElementStream<E::Packet>, element: E, queue_capacity: usize, num_ports: usize) -> Self {
+        assert!(num_ports > 0, "ClassifyElementLink needs at least one output port");
+
+        let mut to_providers = Vec::with_capacity(num_ports);
+        let mut providers = Vec::with_capacity(num_ports);
+        let mut metrics = Vec::with_capacity(num_ports);
+
+        for _ in 0..num_ports {
+            let (to_provider, from_consumer) = ring::<E::Packet>(queue_capacity);
+            let port_metrics = LinkMetrics::queued(queue_capacity);
+
+            to_providers.push(to_provider);
+            providers.push(ClassifyElementProvider::new(from_consumer, port_metrics.clone()));
+            metrics.push(port_metrics);
+        }
+
+        ClassifyElementLink {
+            consumer: ClassifyElementConsumer::new(input_stream, to_providers, element, metrics),
+            providers
+        }
+    }
+}
+
+/// The ClassifyElementConsumer polls its input stream, classifies each packet
+/// and pushes it onto the queue of the chosen port. When the chosen queue is
+/// full, the packet is held back and the consumer sleeps until that port's
+/// provider makes room. Like the AsyncElementConsumer, it keeps working in a
+/// loop for as long as it can make forward progress.
+pub struct ClassifyElementConsumer<E: ClassifyElement> {
+    input_stream: ElementStream<E::Packet>,
+    to_providers: Vec<RingProducer<E::Packet>>,
+    element: E,
+    pending: Option<(usize, E::Packet)>,
+    metrics: Vec<LinkMetrics>,
+    /// When we went to sleep on the full queue of the pending packet's port.
+    stalled_since: Option<Instant>
+}
+
+impl<E: ClassifyElement> ClassifyElementConsumer<E> {
+    fn new(
+        input_stream: ElementStream<E::Packet>,
+        to_providers: Vec<RingProducer<E::Packet>>,
+        element: E,
+        metrics: Vec<LinkMetrics>)
+    -> Self {
+        ClassifyElementConsumer {
+            input_stream,
+            to_providers,
+            element,
+            pending: None,
+            metrics,
+            stalled_since: None
+        }
+    }
+
+    /// The metrics of the queue for `port`, shared with its provider.
+    pub fn metrics(&self, port: usize) -> LinkMetrics {
+        self.metrics[port].clone()
+    }
+
+    /// Tries to push `packet` onto the queue for `port`. If the queue is full
+    /// the packet is handed back so it can be stashed until there is room. If
+    /// the port's provider has gone away, the packet is dropped.
+    fn try_push(&mut self, port: usize, packet: E::Packet) -> Result<(), E::Packet> {
+        if self.to_providers[port].is_closed() {
+            self.metrics[port].record_drop();
+            return Ok(())
+        }
+        if self.to_providers[port].is_full() {
+            return Err(packet)
+        }
+        // The provider may take the packet off the queue as soon as it is
+        // pushed, so it is counted on beforehand. Only we push, so having
+        // found room above, the push can only fail if the provider has gone
+        // since.
+        self.metrics[port].record_enqueue();
+        match self.to_providers[port].try_push(packet) {
+            Ok(()) => Ok(()),
+            Err(PushError::Full(_)) | Err(PushError::Closed(_)) => {
+                self.metrics[port].record_enqueue_failed();
+                self.metrics[port].record_drop();
+                Ok(())
+            }
+        }
+    }
+
+    fn poll_work(&mut self, cx: &mut Context) -> Poll<()> {
+        loop {
+            let (port, packet) = match self.pending.take() {
+                Some(pending) => pending,
+                None => {
+                    match self.input_stream.as_mut().poll_next(cx) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(None) => return Poll::Ready(()),
+                        Poll::Ready(Some(packet)) => {
+                            let port = self.element.classify(&packet);
+                            assert!(port < self.to_providers.len(),
+                                "ClassifyElement picked port {}, but link only has {} ports", port, self.to_providers.len());
+                            (port, packet)
+                        }
+                    }
+                }
+            };
+
+            if let Err(packet) = self.try_push(port, packet) {
+                self.pending = Some((port, packet));
+                let to_provider = &mut self.to_providers[port];
+                to_provider.park(cx.waker());
+                if !to_provider.is_full() || to_provider.is_closed() {
+                    continue
+                }
+                self.metrics[port].record_consumer_sleep();
+                self.stalled_since = Some(Instant::now());
+                return Poll::Pending
+            }
+        }
+    }
+}
+
+impl<E: ClassifyElement> Drop for ClassifyElementConsumer<E> {
+    /// Dropping our end of every port's ring closes it. Each provider drains
+    /// whatever is left in its queue and then sees the ring closed.
+    fn drop(&mut self) {
+        self.to_providers.clear();
+    }
+}
+
+impl<E: ClassifyElement> Unpin for ClassifyElementConsumer<E> {}
+
+impl<E: ClassifyElement> Future for ClassifyElementConsumer<E> {
+    type Output = ();
+
+    /// Implement Poll for Future for ClassifyElementConsumer
+    ///
+    /// Before pulling anything new, we retry the packet we held back on the
+    /// previous poll, if any. From there on, there are three cases:
+    /// ###
+    /// #1 The queue for the packet's port is full, we stash the packet, park
+    /// on that port's ring so its provider awakens us once it makes room, and
+    /// sleep.
+    ///
+    /// #2 The input_stream returns a Pending, we sleep, with the assumption
+    /// that whomever produced the Pending will awaken the task in the Future.
+    ///
+    /// #3 We get a Ready(None), in which case we return Ready(()) and enter
+    /// tear-down, which closes every port's queue.
+    /// ###
+    /// The packets we push are published to the providers in batches, so
+    /// whichever way we return, we publish what is left on every port first.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let consumer = self.get_mut();
+        if let (Some(since), Some((port, _))) = (consumer.stalled_since.take(), &consumer.pending) {
+            consumer.metrics[*port].record_consumer_stall(since);
+        }
+        let poll = consumer.poll_work(cx);
+        for to_provider in consumer.to_providers.iter_mut() {
+            to_provider.flush();
+        }
+        poll
+    }
+}
+
+/// The ClassifyElementProvider is the stream for a single output port of a
+/// ClassifyElementLink. It behaves exactly like the AsyncElementProvider, but
+/// only ever sees packets that were classified onto its port.
+pub struct ClassifyElementProvider<E: ClassifyElement> {
+    from_consumer: RingConsumer<E::Packet>,
+    metrics: LinkMetrics,
+    stalled_since: Option<Instant>
+}
+
+impl<E: ClassifyElement> ClassifyElementProvider<E> {
+    fn new(from_consumer: RingConsumer<E::Packet>, metrics: LinkMetrics) -> Self {
+        ClassifyElementProvider {
+            from_consumer,
+            metrics,
+            stalled_since: None
+        }
+    }
+
+    /// The metrics of this port's queue, shared with the consumer.
+    pub fn metrics(&self) -> LinkMetrics {
+        self.metrics.clone()
+    }
+}
+
+impl<E: ClassifyElement> Unpin for ClassifyElementProvider<E> {}
+
+impl<E: ClassifyElement> Stream for ClassifyElementProvider<E> {
+    type Item = E::Packet;
+
+    /// Implement Poll for Stream for ClassifyElementProvider
+    ///
+    /// Follows the same cases as the AsyncElementProvider: hand out a packet,
+    /// forward tear-down once the consumer has closed its end of the ring and
+    /// we have drained it, or park on the ring and sleep until the consumer
+    /// has more work for us, all by way of `RingConsumer::poll_pop`.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let provider = self.get_mut();
+        if let Some(since) = provider.stalled_since.take() {
+            provider.metrics.record_provider_stall(since);
+        }
+        match provider.from_consumer.poll_pop(cx) {
+            Poll::Ready(Some(packet)) => {
+                provider.metrics.record_dequeue();
+                Poll::Ready(Some(packet))
+            },
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => {
+                provider.metrics.record_provider_sleep();
+                provider.stalled_since = Some(Instant::now());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test::packet_generators::{immediate_stream, LinearIntervalGenerator};
+    use crate::utils::test::packet_collectors::{ExhaustiveDrain, ExhaustiveCollector};
+    use core::time;
+    use std::sync::{Arc, Mutex};
+
+    struct EvenOddClassifier;
+
+    impl ClassifyElement for EvenOddClassifier {
+        type Packet = i32;
+
+        fn classify(&mut self, packet: &Self::Packet) -> usize {
+            (packet % 2) as usize
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn even_odd_immediate_yield() {
+        let packet_generator = immediate_stream(0..=20);
+
+        let mut elem0_link = ClassifyElementLink::new(Box::pin(packet_generator), EvenOddClassifier, 10, 2);
+
+        let odd_provider = elem0_link.providers.pop().unwrap();
+        let even_provider = elem0_link.providers.pop().unwrap();
+        let elem0_drain = elem0_link.consumer;
+
+        let even_packets = Arc::new(Mutex::new(Vec::new()));
+        let odd_packets = Arc::new(Mutex::new(Vec::new()));
+        let even_collector = ExhaustiveCollector::new(0, Box::pin(even_provider), Arc::clone(&even_packets));
+        let odd_collector = ExhaustiveCollector::new(1, Box::pin(odd_provider), Arc::clone(&odd_packets));
+
+        let elem0_drain = tokio::spawn(elem0_drain);
+        let even_collector = tokio::spawn(even_collector);
+        let odd_collector = tokio::spawn(odd_collector);
+        elem0_drain.await.unwrap();
+        even_collector.await.unwrap();
+        odd_collector.await.unwrap();
+
+        assert_eq!(*even_packets.lock().unwrap(), (0..=20).filter(|p| p % 2 == 0).collect::<Vec<i32>>());
+        assert_eq!(*odd_packets.lock().unwrap(), (0..=20).filter(|p| p % 2 == 1).collect::<Vec<i32>>());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn even_odd_interval_yield() {
+        let packet_generator = LinearIntervalGenerator::new(time::Duration::from_millis(10), 20);
+
+        let mut elem0_link = ClassifyElementLink::new(Box::pin(packet_generator), EvenOddClassifier, 1, 2);
+
+        let odd_provider = elem0_link.providers.pop().unwrap();
+        let even_provider = elem0_link.providers.pop().unwrap();
+        let elem0_drain = elem0_link.consumer;
+
+        let even_consumer = ExhaustiveDrain::new(0, Box::pin(even_provider));
+        let odd_consumer = ExhaustiveDrain::new(1, Box::pin(odd_provider));
+
+        let elem0_drain = tokio::spawn(elem0_drain);
+        let even_consumer = tokio::spawn(even_consumer);
+        let odd_consumer = tokio::spawn(odd_consumer);
+        elem0_drain.await.unwrap();
+        even_consumer.await.unwrap();
+        odd_consumer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn dropped_provider_does_not_stall_other_ports() {
+        let packet_generator = immediate_stream(0..=20);
+
+        let mut elem0_link = ClassifyElementLink::new(Box::pin(packet_generator), EvenOddClassifier, 2, 2);
+        let odd_provider = elem0_link.providers.pop().unwrap();
+        let even_provider = elem0_link.providers.pop().unwrap();
+        let odd_metrics = odd_provider.metrics();
+        let elem0_drain = tokio::spawn(elem0_link.consumer);
+
+        // Let the odd port fill up before its provider goes away.
+        tokio::task::yield_now().await;
+        drop(odd_provider);
+
+        let even_packets: Vec<i32> = futures::StreamExt::collect(even_provider).await;
+        elem0_drain.await.unwrap();
+
+        assert_eq!(even_packets, (0..=20).filter(|p| p % 2 == 0).collect::<Vec<i32>>());
+        // 1 and 3 were queued when the provider went, the other eight odd
+        // packets had nowhere to go.
+        assert_eq!(odd_metrics.drop_counter().get(), 8);
+    }
+}
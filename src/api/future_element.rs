@@ -0,0 +1,470 @@
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::{FuturesOrdered, FuturesUnordered, Stream, StreamExt};
+use std::any::type_name;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use crate::api::{
+    ElementStream, Verdict, Counter, LinkMetrics, Timers, OverflowPolicy, PacketSize, MemoryBudget, run_timer
+};
+use crate::api::budget::QueueBytes;
+use crate::api::queue::{queue, QueueSender, QueueReceiver};
+
+/// A FutureElement is an AsyncElement whose work happens somewhere else,
+/// such as classifying packets by asking a database. Rather than a Verdict,
+/// `process` returns a future resolving to one, so that the consumer can
+/// keep many packets in flight while it waits, instead of blocking its task
+/// on each call in turn.
+///
+/// The lifecycle is the same as for an AsyncElement. Since the futures
+/// outlive the call to `process`, their output has to be `Send + 'static`.
+pub trait FutureElement {
+    type Input: Sized;
+    type Output: Sized + Send + 'static;
+
+    fn process(&mut self, packet: Self::Input) -> BoxFuture<'static, Verdict<Self::Output>>;
+
+    fn initialize(&mut self, _timers: &Timers) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn cleanup(&mut self) {}
+
+    fn run_timer(&mut self, _timer: usize) -> Verdict<Self::Output> {
+        Verdict::Drop
+    }
+}
+
+/// Processing a packet, resolving to None if it timed out.
+type InFlight<Output> = BoxFuture<'static, Option<Verdict<Output>>>;
+
+/// The packets being processed, which hand on their results either in the
+/// order the packets came in, or as soon as each is done.
+enum Processing<Output> {
+    Ordered(FuturesOrdered<InFlight<Output>>),
+    Unordered(FuturesUnordered<InFlight<Output>>)
+}
+
+impl<Output> Processing<Output> {
+    fn len(&self) -> usize {
+        match self {
+            Processing::Ordered(in_flight) => in_flight.len(),
+            Processing::Unordered(in_flight) => in_flight.len()
+        }
+    }
+
+    fn push(&mut self, future: InFlight<Output>) {
+        match self {
+            Processing::Ordered(in_flight) => in_flight.push_back(future),
+            Processing::Unordered(in_flight) => in_flight.push(future)
+        }
+    }
+
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<Option<Verdict<Output>>>> {
+        match self {
+            Processing::Ordered(in_flight) => in_flight.poll_next_unpin(cx),
+            Processing::Unordered(in_flight) => in_flight.poll_next_unpin(cx)
+        }
+    }
+}
+
+/// The FutureElementLink is built like an AsyncElementLink, out of a consumer
+/// that processes packets, and a provider that hands them on from a queue,
+/// the same queue as the AsyncElementLink's, with the same overflow policies
+/// and byte accounting. The consumer keeps up to `concurrency` packets in
+/// flight at once.
+///
+/// By default, packets leave in the order they came in, so a slow packet
+/// holds up those behind it. `unordered` lets each packet go as soon as it
+/// is processed instead. `timeout` drops packets that take too long to
+/// process. Only processing counts against it: a packet that is done, but
+/// waiting behind a slower one or for room in the queue, is not timed out.
+pub struct FutureElementLink<E: FutureElement> {
+    pub consumer: FutureElementConsumer<E>,
+    pub provider: FutureElementProvider<E>
+}
+
+impl<E: FutureElement> FutureElementLink<E> {
+    pub fn new(input_stream: ElementStream<E::Input>, element: E, queue_capacity: usize, concurrency: usize) -> Self {
+        assert!(concurrency > 0, "FutureElementLink needs to keep at least one packet in flight");
+        let (to_provider, from_consumer) = queue(queue_capacity, None);
+
+        FutureElementLink {
+            consumer: FutureElementConsumer {
+                input_stream,
+                input_finished: false,
+                to_provider,
+                element,
+                concurrency,
+                processing: Processing::Ordered(FuturesOrdered::new()),
+                timeout: None,
+                timeouts: Counter::new(),
+                timers: Timers::new(),
+                initialized: false
+            },
+            provider: FutureElementProvider { from_consumer }
+        }
+    }
+
+    /// Hands on each packet as soon as it has been processed.
+    pub fn unordered(mut self) -> Self {
+        self.consumer.processing = Processing::Unordered(FuturesUnordered::new());
+        self
+    }
+
+    /// Drops packets that take longer than `timeout` to process.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.consumer.timeout = Some(timeout);
+        self
+    }
+
+    /// Has the consumer follow `policy` when the queue is full, as
+    /// `AsyncElementLink::overflow` does.
+    pub fn overflow(mut self, policy: OverflowPolicy) -> Self {
+        self.consumer.to_provider.set_policy(policy);
+        self
+    }
+
+    /// Bounds the queue by bytes, as `AsyncElementLink::byte_capacity` does.
+    pub fn byte_capacity(self, byte_capacity: usize) -> Self
+        where E::Output: PacketSize
+    {
+        let bytes = self.queue_bytes().with_capacity(byte_capacity);
+        self.count_bytes(bytes)
+    }
+
+    /// Counts the bytes in the queue against `budget`, as
+    /// `AsyncElementLink::memory_budget` does.
+    pub fn memory_budget(self, budget: &MemoryBudget) -> Self
+        where E::Output: PacketSize
+    {
+        let bytes = self.queue_bytes().with_budget(budget.clone());
+        self.count_bytes(bytes)
+    }
+
+    fn queue_bytes(&self) -> QueueBytes<E::Output>
+        where E::Output: PacketSize
+    {
+        self.consumer.to_provider.bytes().unwrap_or_else(QueueBytes::new)
+    }
+
+    fn count_bytes(mut self, bytes: QueueBytes<E::Output>) -> Self {
+        self.consumer.to_provider.set_bytes(bytes.clone());
+        self.provider.from_consumer.set_bytes(bytes);
+        self
+    }
+
+    /// Initializes the element, as `ElementLink::initialize` does.
+    pub fn initialize(&mut self) -> Result<(), String> {
+        self.consumer.initialize()
+    }
+}
+
+/// The FutureElementConsumer pulls packets from its input stream as long as
+/// it has fewer than `concurrency` in flight, and pushes the processed
+/// packets onto the to_provider queue. It is handed to, and is polled by the
+/// runtime.
+pub struct FutureElementConsumer<E: FutureElement> {
+    input_stream: ElementStream<E::Input>,
+    input_finished: bool,
+    to_provider: QueueSender<E::Output>,
+    element: E,
+    concurrency: usize,
+    processing: Processing<E::Output>,
+    timeout: Option<Duration>,
+    timeouts: Counter,
+    timers: Timers,
+    initialized: bool
+}
+
+impl<E: FutureElement> FutureElementConsumer<E> {
+    /// Initializes the element, as `ElementLink::initialize` does.
+    pub fn initialize(&mut self) -> Result<(), String> {
+        if !self.initialized {
+            self.element.initialize(&self.timers)?;
+            self.initialized = true;
+        }
+        Ok(())
+    }
+
+    /// Number of packets dropped for taking longer than the timeout. These
+    /// are counted as drops too.
+    pub fn timeout_counter(&self) -> Counter {
+        self.timeouts.clone()
+    }
+
+    /// Number of input packets the element has dropped so far.
+    pub fn drop_counter(&self) -> Counter {
+        self.to_provider.metrics().drop_counter()
+    }
+
+    /// The metrics of the link, shared with its provider.
+    pub fn metrics(&self) -> LinkMetrics {
+        self.to_provider.metrics().clone()
+    }
+
+    /// Number of packets dropped by the link's OverflowPolicy so far, as
+    /// `AsyncElementConsumer::overflow_counter` counts them.
+    pub fn overflow_counter(&self) -> Counter {
+        self.to_provider.overflow_counter()
+    }
+
+    fn start(&mut self, packet: E::Input) {
+        let future = self.element.process(packet);
+        let in_flight = match self.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, future).map(Result::ok).boxed(),
+            None => future.map(Some).boxed()
+        };
+        self.processing.push(in_flight);
+    }
+
+    fn finish(&mut self, verdict: Verdict<E::Output>) {
+        let queue = &mut self.to_provider;
+        match verdict {
+            Verdict::Pass(packet) => queue.pending.push_back(packet),
+            Verdict::Drop => queue.metrics().record_drop(),
+            Verdict::Many(packets) => {
+                if packets.is_empty() {
+                    queue.metrics().record_drop();
+                }
+                queue.pending.extend(packets);
+            }
+        }
+    }
+
+    fn poll_work(&mut self, cx: &mut Context) -> Poll<()> {
+        loop {
+            let finished = self.input_finished && self.processing.len() == 0;
+            if let Some(poll) = self.to_provider.poll_push(cx, finished) {
+                return poll
+            }
+            // Under HeadDrop, we may still have packets stashed here, which
+            // we need to be woken for once there is room.
+            let stashed = !self.to_provider.pending.is_empty();
+
+            while !self.input_finished && self.processing.len() < self.concurrency {
+                match self.input_stream.as_mut().poll_next(cx) {
+                    Poll::Ready(Some(packet)) => self.start(packet),
+                    Poll::Ready(None) => self.input_finished = true,
+                    Poll::Pending => break
+                }
+            }
+
+            match self.processing.poll_next(cx) {
+                Poll::Ready(Some(Some(verdict))) => {
+                    self.finish(verdict);
+                    if stashed {
+                        self.to_provider.drop_oldest();
+                    }
+                },
+                Poll::Ready(Some(None)) => {
+                    self.timeouts.incr();
+                    self.to_provider.metrics().record_drop();
+                },
+                Poll::Ready(None) if self.input_finished => {
+                    if !stashed {
+                        return Poll::Ready(())
+                    }
+                },
+                Poll::Ready(None) | Poll::Pending => {
+                    if stashed {
+                        self.to_provider.park(cx);
+                    }
+                    return Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+impl<E: FutureElement> Drop for FutureElementConsumer<E> {
+    /// Closes the to_provider queue, as the AsyncElementConsumer does.
+    /// Packets still in flight are dropped along with their futures.
+    fn drop(&mut self) {
+        self.to_provider.close();
+        if self.initialized {
+            self.element.cleanup();
+        }
+    }
+}
+
+impl<E: FutureElement> Unpin for FutureElementConsumer<E> {}
+
+impl<E: FutureElement> Future for FutureElementConsumer<E> {
+    type Output = ();
+
+    /// Implement Poll for Future for FutureElementConsumer
+    ///
+    /// Processed packets are pushed onto the queue first, then we pull more
+    /// input while there is room in flight, and then poll the packets in
+    /// flight. There are four cases:
+    /// ###
+    /// #1 The to_provider queue is full, we park on it so the provider
+    /// awakens us when there is room, and sleep.
+    ///
+    /// #2 A packet in flight is done, we keep what the element made of it
+    /// aside to be pushed, or count it as dropped if it timed out, and go
+    /// around again.
+    ///
+    /// #3 Nothing in flight is done, and we either have as many packets in
+    /// flight as we may, or the input_stream returned a Pending. We sleep,
+    /// to be woken by whichever is ready first.
+    ///
+    /// #4 Nothing is in flight, and the input_stream returned Ready(None). We
+    /// return Ready(()) and enter tear-down.
+    /// ###
+    /// A full queue is handled by the link's OverflowPolicy, as in the
+    /// AsyncElementConsumer, so under anything but Block, case #1 only
+    /// arises under HeadDrop, once nothing is left to process. Whichever way
+    /// we return, we first publish what we pushed.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let consumer = self.get_mut();
+        if let Err(message) = consumer.initialize() {
+            panic!("{} failed to initialize: {}", type_name::<E>(), message);
+        }
+        while let Poll::Ready(timer) = consumer.timers.poll_expired(cx) {
+            run_timer(consumer.element.run_timer(timer), &mut consumer.to_provider.pending);
+        }
+        consumer.to_provider.record_stall();
+        let poll = consumer.poll_work(cx);
+        consumer.to_provider.flush();
+        poll
+    }
+}
+
+/// The FutureElementProvider hands on the packets the consumer processed,
+/// exactly like the AsyncElementProvider.
+pub struct FutureElementProvider<E: FutureElement> {
+    from_consumer: QueueReceiver<E::Output>
+}
+
+impl<E: FutureElement> FutureElementProvider<E> {
+    /// The metrics of the link, shared with its consumer.
+    pub fn metrics(&self) -> LinkMetrics {
+        self.from_consumer.metrics().clone()
+    }
+}
+
+impl<E: FutureElement> Unpin for FutureElementProvider<E> {}
+
+impl<E: FutureElement> Stream for FutureElementProvider<E> {
+    type Item = E::Output;
+
+    /// Implement Poll for Stream for FutureElementProvider
+    ///
+    /// Same as the AsyncElementProvider, whose queue we share.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.get_mut().from_consumer.poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test::packet_generators::immediate_stream;
+    use crate::utils::test::packet_collectors::ExhaustiveCollector;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Takes `delay` times the packet's value in milliseconds to pass each
+    /// packet on, keeping track of how many packets it has in flight.
+    struct Lookup {
+        delay: fn(i32) -> u64,
+        in_flight: Arc<AtomicUsize>,
+        most_in_flight: Arc<AtomicUsize>
+    }
+
+    impl FutureElement for Lookup {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> BoxFuture<'static, Verdict<Self::Output>> {
+            let delay = Duration::from_millis((self.delay)(packet));
+            let in_flight = Arc::clone(&self.in_flight);
+            let most_in_flight = Arc::clone(&self.most_in_flight);
+            async move {
+                let now_in_flight = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                most_in_flight.fetch_max(now_in_flight, Ordering::SeqCst);
+                tokio::time::sleep(delay).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                Verdict::Pass(packet)
+            }.boxed()
+        }
+    }
+
+    fn lookup(delay: fn(i32) -> u64) -> (Lookup, Arc<AtomicUsize>) {
+        let most_in_flight = Arc::new(AtomicUsize::new(0));
+        let lookup = Lookup { delay, in_flight: Arc::new(AtomicUsize::new(0)), most_in_flight: Arc::clone(&most_in_flight) };
+        (lookup, most_in_flight)
+    }
+
+    async fn run<E: FutureElement<Output = i32> + Send + 'static>(link: FutureElementLink<E>) -> Vec<i32>
+        where E::Input: Send
+    {
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        let collector = ExhaustiveCollector::new(0, Box::pin(link.provider), Arc::clone(&packets));
+        let consumer = tokio::spawn(link.consumer);
+        let collector = tokio::spawn(collector);
+        consumer.await.unwrap();
+        collector.await.unwrap();
+        let packets = packets.lock().unwrap().clone();
+        packets
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn keeps_packets_in_flight_in_order() {
+        // Earlier packets take longer, so they would come out last if we
+        // did not hold later ones back.
+        let (element, most_in_flight) = lookup(|packet| 20 - (packet % 10) as u64);
+        let link = FutureElementLink::new(immediate_stream(0..20), element, 4, 8);
+
+        assert_eq!(run(link).await, (0..20).collect::<Vec<i32>>());
+        assert!(most_in_flight.load(Ordering::SeqCst) > 1);
+        assert!(most_in_flight.load(Ordering::SeqCst) <= 8);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn drops_packets_that_time_out() {
+        // With time paused, the clock only moves on once every task is
+        // asleep, so the 80ms packet makes the 90ms timeout however loaded
+        // the machine is.
+        let (element, _) = lookup(|packet| packet as u64 * 20);
+        let link = FutureElementLink::new(immediate_stream(0..10), element, 10, 10)
+            .unordered()
+            .timeout(Duration::from_millis(90));
+        let timeouts = link.consumer.timeout_counter();
+        let drops = link.consumer.drop_counter();
+
+        let mut packets = run(link).await;
+        packets.sort();
+        assert_eq!(packets, vec![0, 1, 2, 3, 4]);
+        assert_eq!(timeouts.get(), 5);
+        assert_eq!(drops.get(), 5);
+    }
+
+    /// Passes each packet on as soon as it is asked to.
+    struct Immediate;
+
+    impl FutureElement for Immediate {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> BoxFuture<'static, Verdict<Self::Output>> {
+            async move { Verdict::Pass(packet) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_tail_or_head_of_full_queue() {
+        for (policy, expected) in [(OverflowPolicy::TailDrop, vec![0, 1, 2, 3]), (OverflowPolicy::HeadDrop, vec![6, 7, 8, 9])] {
+            let link = FutureElementLink::new(immediate_stream(0..10), Immediate, 4, 2).overflow(policy);
+            let overflows = link.consumer.overflow_counter();
+            let consumer = tokio::spawn(link.consumer);
+            let packets: Vec<i32> = link.provider.collect().await;
+            consumer.await.unwrap();
+            assert_eq!(packets, expected);
+            assert_eq!(overflows.get(), 6);
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use crate::api::{Element, AsyncElement, ClassifyElement, Verdict, Timers, TransferState, SharedState};
+
+/// Describes one of an element's handlers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HandlerInfo {
+    pub name: String,
+    pub readable: bool,
+    pub writable: bool
+}
+
+impl HandlerInfo {
+    pub fn read(name: &str) -> Self {
+        HandlerInfo { name: name.to_string(), readable: true, writable: false }
+    }
+
+    pub fn write(name: &str) -> Self {
+        HandlerInfo { name: name.to_string(), readable: false, writable: true }
+    }
+
+    pub fn read_write(name: &str) -> Self {
+        HandlerInfo { name: name.to_string(), readable: true, writable: true }
+    }
+}
+
+/// Handlers are named values that can be read or written while the router
+/// runs, as in Click, such as a `count` to read, a `reset` to write, or an
+/// `active` flag to do both. Elements that want handlers implement this
+/// alongside `Element` or `AsyncElement`, and are wrapped in a Shared so
+/// the router can reach them while their link is running.
+///
+/// `read_handler` and `write_handler` are only called for handlers listed by
+/// `handlers` as readable or writable respectively. An Err carries a message
+/// explaining what went wrong, such as a value that does not parse.
+pub trait Handlers {
+    fn handlers(&self) -> Vec<HandlerInfo>;
+
+    fn read_handler(&self, name: &str) -> Result<String, String> {
+        Err(format!("{} is not readable", name))
+    }
+
+    fn write_handler(&mut self, name: &str, _value: &str) -> Result<(), String> {
+        Err(format!("{} is not writable", name))
+    }
+}
+
+/// The handlers of an element, as registered with the router.
+pub type SharedHandlers = Arc<Mutex<dyn Handlers + Send>>;
+
+/// A Shared element sits behind a mutex, so that one clone can be handed to
+/// a link while another is used to get at the element's handlers. The link
+/// takes the lock once per packet, so a handler call waits for at most one
+/// packet to finish processing, and never sees the element halfway through.
+pub struct Shared<E>(Arc<Mutex<E>>);
+
+impl<E> Shared<E> {
+    pub fn new(element: E) -> Self {
+        Shared(Arc::new(Mutex::new(element)))
+    }
+
+    /// Locks the element. If processing a packet panicked, we carry on with
+    /// the element as it was left, rather than poisoning every handler.
+    pub fn lock(&self) -> MutexGuard<'_, E> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<E: Handlers + Send + 'static> Shared<E> {
+    pub fn handlers(&self) -> SharedHandlers {
+        self.0.clone()
+    }
+}
+
+impl<E: TransferState + Send + 'static> Shared<E> {
+    pub fn state(&self) -> SharedState {
+        self.0.clone()
+    }
+}
+
+impl<E> Clone for Shared<E> {
+    fn clone(&self) -> Self {
+        Shared(Arc::clone(&self.0))
+    }
+}
+
+impl<E: Element> Element for Shared<E> {
+    type Input = E::Input;
+    type Output = E::Output;
+
+    fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+        self.lock().process(packet)
+    }
+
+    fn initialize(&mut self, timers: &Timers) -> Result<(), String> {
+        self.lock().initialize(timers)
+    }
+
+    fn cleanup(&mut self) {
+        self.lock().cleanup()
+    }
+
+    fn run_timer(&mut self, timer: usize) -> Verdict<Self::Output> {
+        self.lock().run_timer(timer)
+    }
+}
+
+impl<E: AsyncElement> AsyncElement for Shared<E> {
+    type Input = E::Input;
+    type Output = E::Output;
+
+    fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+        self.lock().process(packet)
+    }
+
+    fn initialize(&mut self, timers: &Timers) -> Result<(), String> {
+        self.lock().initialize(timers)
+    }
+
+    fn cleanup(&mut self) {
+        self.lock().cleanup()
+    }
+
+    fn run_timer(&mut self, timer: usize) -> Verdict<Self::Output> {
+        self.lock().run_timer(timer)
+    }
+}
+
+impl<E: ClassifyElement> ClassifyElement for Shared<E> {
+    type Packet = E::Packet;
+
+    fn classify(&mut self, packet: &Self::Packet) -> usize {
+        self.lock().classify(packet)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HandlerError {
+    /// The path was not of the form `element.handler`.
+    BadPath(String),
+    NoSuchElement(String),
+    NoSuchHandler(String),
+    NotReadable(String),
+    NotWritable(String),
+    /// The element refused the read or write.
+    Failed { path: String, message: String }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HandlerError::BadPath(path) => write!(f, "{} is not of the form element.handler", path),
+            HandlerError::NoSuchElement(element) => write!(f, "no element named {} has handlers", element),
+            HandlerError::NoSuchHandler(path) => write!(f, "no handler {}", path),
+            HandlerError::NotReadable(path) => write!(f, "handler {} is not readable", path),
+            HandlerError::NotWritable(path) => write!(f, "handler {} is not writable", path),
+            HandlerError::Failed { path, message } => write!(f, "{}: {}", path, message)
+        }
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+/// The HandlerTable finds the handlers of every element in a router by their
+/// `element.handler` path. Cloning it is cheap, and it stays usable after
+/// the router has been spawned.
+#[derive(Clone, Default)]
+pub struct HandlerTable {
+    elements: Vec<(String, SharedHandlers)>
+}
+
+impl HandlerTable {
+    pub fn new() -> Self {
+        HandlerTable::default()
+    }
+
+    pub fn add(&mut self, element: &str, handlers: SharedHandlers) {
+        self.elements.push((element.to_string(), handlers));
+    }
+
+    /// Every handler of every element, with names given as full paths.
+    pub fn list(&self) -> Vec<HandlerInfo> {
+        self.elements.iter().flat_map(|(element, handlers)| {
+            let handlers = handlers.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).handlers();
+            handlers.into_iter().map(move |info| HandlerInfo { name: format!("{}.{}", element, info.name), ..info })
+        }).collect()
+    }
+
+    /// Looks up the element a path refers to, and the handler's info. Element
+    /// names can contain dots, handler names can't.
+    fn find<'a>(&'a self, path: &'a str) -> Result<(&'a SharedHandlers, &'a str, HandlerInfo), HandlerError> {
+        let (element, handler) = path.rsplit_once('.').ok_or_else(|| HandlerError::BadPath(path.to_string()))?;
+        let handlers = self.elements.iter()
+            .find(|(name, _)| name == element)
+            .map(|(_, handlers)| handlers)
+            .ok_or_else(|| HandlerError::NoSuchElement(element.to_string()))?;
+        let info = handlers.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).handlers().into_iter()
+            .find(|info| info.name == handler)
+            .ok_or_else(|| HandlerError::NoSuchHandler(path.to_string()))?;
+        Ok((handlers, handler, info))
+    }
+
+    pub fn read(&self, path: &str) -> Result<String, HandlerError> {
+        let (handlers, handler, info) = self.find(path)?;
+        if !info.readable {
+            return Err(HandlerError::NotReadable(path.to_string()))
+        }
+        handlers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+            .read_handler(handler)
+            .map_err(|message| HandlerError::Failed { path: path.to_string(), message })
+    }
+
+    pub fn write(&self, path: &str, value: &str) -> Result<(), HandlerError> {
+        let (handlers, handler, info) = self.find(path)?;
+        if !info.writable {
+            return Err(HandlerError::NotWritable(path.to_string()))
+        }
+        handlers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+            .write_handler(handler, value)
+            .map_err(|message| HandlerError::Failed { path: path.to_string(), message })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::AsyncElementLink;
+    use crate::router::RouterGraph;
+    use crate::utils::test::packet_generators::immediate_stream;
+    use crate::utils::test::packet_collectors::ExhaustiveCollector;
+    use tokio::runtime::Handle;
+
+    /// Counts the packets going through it, and drops them all while it is
+    /// not active.
+    struct Gate {
+        count: u64,
+        active: bool
+    }
+
+    impl AsyncElement for Gate {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            self.count += 1;
+            if self.active { Verdict::Pass(packet) } else { Verdict::Drop }
+        }
+    }
+
+    impl Handlers for Gate {
+        fn handlers(&self) -> Vec<HandlerInfo> {
+            vec![HandlerInfo::read("count"), HandlerInfo::write("reset"), HandlerInfo::read_write("active")]
+        }
+
+        fn read_handler(&self, name: &str) -> Result<String, String> {
+            match name {
+                "count" => Ok(self.count.to_string()),
+                _ => Ok(self.active.to_string())
+            }
+        }
+
+        fn write_handler(&mut self, name: &str, value: &str) -> Result<(), String> {
+            match name {
+                "reset" => self.count = 0,
+                _ => self.active = value.parse().map_err(|_| format!("expected true or false, got {}", value))?
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reads_and_writes_handlers_by_path() {
+        let gate = Shared::new(Gate { count: 0, active: false });
+        let mut router = RouterGraph::new();
+        let src = router.add_source("src");
+        let link = AsyncElementLink::new(immediate_stream(0..10), gate.clone(), 10);
+        let (node, provider) = router.add_async_link("gate", src, link);
+        router.add_handlers(node, gate.handlers());
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        router.add_sink("drain", node, ExhaustiveCollector::new(0, Box::pin(provider), Arc::clone(&packets)));
+
+        let handlers = router.handlers();
+        assert_eq!(handlers.list(), vec![
+            HandlerInfo::read("gate.count"), HandlerInfo::write("gate.reset"), HandlerInfo::read_write("gate.active")
+        ]);
+        handlers.write("gate.active", "true").unwrap();
+        router.spawn(&Handle::current()).await.unwrap();
+
+        assert_eq!(packets.lock().unwrap().len(), 10);
+        assert_eq!(handlers.read("gate.count"), Ok("10".to_string()));
+        handlers.write("gate.reset", "").unwrap();
+        assert_eq!(handlers.read("gate.count"), Ok("0".to_string()));
+
+        assert_eq!(handlers.read("gate.reset"), Err(HandlerError::NotReadable("gate.reset".to_string())));
+        assert_eq!(handlers.write("gate.count", "1"), Err(HandlerError::NotWritable("gate.count".to_string())));
+        assert_eq!(handlers.read("gate.bogus"), Err(HandlerError::NoSuchHandler("gate.bogus".to_string())));
+        assert_eq!(handlers.read("src.count"), Err(HandlerError::NoSuchElement("src".to_string())));
+        assert_eq!(handlers.read("count"), Err(HandlerError::BadPath("count".to_string())));
+        assert_eq!(handlers.write("gate.active", "maybe").unwrap_err().to_string(),
+            "gate.active: expected true or false, got maybe");
+    }
+}
@@ -0,0 +1,307 @@
+use futures::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use crate::api::ElementStream;
+
+/// A JoinScheduler decides which of a JoinLink's inputs gets to hand over
+/// the next packet. The link asks for an input with `next_input`, polls it,
+/// and then reports back with either `on_packet` or `on_idle`, so the
+/// scheduler can keep whatever accounting it needs.
+pub trait JoinScheduler<Packet> {
+    /// Index of the input that should be polled next.
+    fn next_input(&mut self) -> usize;
+
+    /// The input at `index` yielded `packet`.
+    fn on_packet(&mut self, index: usize, packet: &Packet);
+
+    /// The input at `index` had nothing for us, either because it returned
+    /// Pending or because it has already ended.
+    fn on_idle(&mut self, index: usize);
+}
+
+/// Serves inputs in turn, one packet each.
+pub struct RoundRobin {
+    num_inputs: usize,
+    current: usize
+}
+
+impl RoundRobin {
+    pub fn new(num_inputs: usize) -> Self {
+        RoundRobin { num_inputs, current: 0 }
+    }
+
+    fn advance(&mut self) {
+        self.current = (self.current + 1) % self.num_inputs;
+    }
+}
+
+impl<Packet> JoinScheduler<Packet> for RoundRobin {
+    fn next_input(&mut self) -> usize {
+        self.current
+    }
+
+    fn on_packet(&mut self, _index: usize, _packet: &Packet) {
+        self.advance();
+    }
+
+    fn on_idle(&mut self, _index: usize) {
+        self.advance();
+    }
+}
+
+/// Always serves the lowest numbered input that has a packet ready. Input 0
+/// has the highest priority, so lower priority inputs can be starved.
+pub struct StrictPriority {
+    num_inputs: usize,
+    current: usize
+}
+
+impl StrictPriority {
+    pub fn new(num_inputs: usize) -> Self {
+        StrictPriority { num_inputs, current: 0 }
+    }
+}
+
+impl<Packet> JoinScheduler<Packet> for StrictPriority {
+    fn next_input(&mut self) -> usize {
+        self.current
+    }
+
+    fn on_packet(&mut self, _index: usize, _packet: &Packet) {
+        self.current = 0;
+    }
+
+    fn on_idle(&mut self, _index: usize) {
+        self.current = (self.current + 1) % self.num_inputs;
+    }
+}
+
+/// Deficit round-robin. Every time an input's turn comes up, its quantum is
+/// added to its deficit, and it is served until its deficit is used up. The
+/// cost of a packet is given by `cost`, so quanta can be expressed in packets
+/// or in bytes. Since we can not peek at the next packet, an input may go
+/// into debt by up to one packet, which is paid back on its next turn. An
+/// input that runs dry forfeits whatever deficit it had saved up.
+pub struct DeficitRoundRobin<Packet> {
+    quanta: Vec<usize>,
+    deficits: Vec<isize>,
+    cost: fn(&Packet) -> usize,
+    current: usize
+}
+
+impl<Packet> DeficitRoundRobin<Packet> {
+    pub fn new(quanta: Vec<usize>, cost: fn(&Packet) -> usize) -> Self {
+        assert!(!quanta.is_empty(), "DeficitRoundRobin needs a quantum for at least one input");
+        assert!(quanta.iter().all(|quantum| *quantum > 0), "DeficitRoundRobin quanta must be non-zero");
+        let mut deficits = vec![0; quanta.len()];
+        deficits[0] = quanta[0] as isize;
+        DeficitRoundRobin {
+            quanta,
+            deficits,
+            cost,
+            current: 0
+        }
+    }
+
+    fn advance(&mut self) {
+        self.current = (self.current + 1) % self.quanta.len();
+        self.deficits[self.current] += self.quanta[self.current] as isize;
+    }
+}
+
+impl<Packet> JoinScheduler<Packet> for DeficitRoundRobin<Packet> {
+    fn next_input(&mut self) -> usize {
+        self.current
+    }
+
+    fn on_packet(&mut self, index: usize, packet: &Packet) {
+        self.deficits[index] -= (self.cost)(packet) as isize;
+        if self.deficits[index] <= 0 {
+            self.advance();
+        }
+    }
+
+    fn on_idle(&mut self, index: usize) {
+        self.deficits[index] = self.deficits[index].min(0);
+        self.advance();
+    }
+}
+
+/// The JoinLink merges several input streams into one, which can then be
+/// handed to any other link as its input_stream. Like the ElementLink, it does
+/// no work of its own; the inputs are only polled when whoever is downstream
+/// polls the JoinLink. Which input is polled first is up to the scheduler.
+pub struct JoinLink<Packet> {
+    input_streams: Vec<ElementStream<Packet>>,
+    finished: Vec<bool>,
+    scheduler: Box<dyn JoinScheduler<Packet> + Send>
+}
+
+impl<Packet> JoinLink<Packet> {
+    pub fn new(input_streams: Vec<ElementStream<Packet>>, scheduler: Box<dyn JoinScheduler<Packet> + Send>) -> Self {
+        assert!(!input_streams.is_empty(), "JoinLink needs at least one input stream");
+        let finished = vec![false; input_streams.len()];
+        JoinLink {
+            input_streams,
+            finished,
+            scheduler
+        }
+    }
+
+    /// Shorthand for a JoinLink that serves its inputs round-robin.
+    pub fn round_robin(input_streams: Vec<ElementStream<Packet>>) -> Self {
+        let num_inputs = input_streams.len();
+        JoinLink::new(input_streams, Box::new(RoundRobin::new(num_inputs)))
+    }
+}
+
+impl<Packet> Stream for JoinLink<Packet> {
+    type Item = Packet;
+
+    /// Implement Poll for Stream for JoinLink
+    ///
+    /// We keep asking the scheduler for an input to poll until one of them
+    /// yields a packet, or until every input has come up empty in a row. An
+    /// input can come up empty in three ways:
+    /// ###
+    /// #1 It has already returned Ready(None) on an earlier poll, so we skip it.
+    ///
+    /// #2 It returns Ready(None) now, we mark it as finished. The JoinLink as a
+    /// whole only returns Ready(None) once every input is finished.
+    ///
+    /// #3 It returns Pending, which registers our waker with that input, so we
+    /// will be awoken once it has more work for us.
+    /// ###
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let num_inputs = self.input_streams.len();
+        let mut idle_in_a_row = 0;
+
+        while idle_in_a_row < num_inputs {
+            let index = self.scheduler.next_input();
+            if self.finished[index] {
+                self.scheduler.on_idle(index);
+                idle_in_a_row += 1;
+                continue;
+            }
+
+            match self.input_streams[index].as_mut().poll_next(cx) {
+                Poll::Ready(Some(packet)) => {
+                    self.scheduler.on_packet(index, &packet);
+                    return Poll::Ready(Some(packet))
+                },
+                Poll::Ready(None) => {
+                    self.finished[index] = true;
+                    self.scheduler.on_idle(index);
+                    idle_in_a_row += 1;
+                },
+                Poll::Pending => {
+                    self.scheduler.on_idle(index);
+                    idle_in_a_row += 1;
+                }
+            }
+        }
+
+        if self.finished.iter().all(|finished| *finished) {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::AsyncElementLink;
+    use crate::api::{AsyncElement, Verdict};
+    use crate::utils::test::packet_generators::{immediate_stream, LinearIntervalGenerator};
+    use crate::utils::test::packet_collectors::ExhaustiveCollector;
+    use core::time;
+    use std::sync::{Arc, Mutex};
+
+    use futures::StreamExt;
+    use futures::executor::block_on;
+
+    fn unit_cost(_packet: &i32) -> usize {
+        1
+    }
+
+    #[test]
+    fn round_robin_alternates_inputs() {
+        let join = JoinLink::round_robin(vec![
+            immediate_stream(vec![0, 0, 0]),
+            immediate_stream(vec![1, 1, 1, 1, 1])
+        ]);
+
+        let packets = block_on(join.collect::<Vec<i32>>());
+        assert_eq!(packets, vec![0, 1, 0, 1, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn strict_priority_drains_first_input() {
+        let join = JoinLink::new(vec![
+            immediate_stream(vec![0, 0, 0]),
+            immediate_stream(vec![1, 1])
+        ], Box::new(StrictPriority::new(2)));
+
+        let packets = block_on(join.collect::<Vec<i32>>());
+        assert_eq!(packets, vec![0, 0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn deficit_round_robin_weights_inputs() {
+        let join = JoinLink::new(vec![
+            immediate_stream(vec![0; 6]),
+            immediate_stream(vec![1; 3])
+        ], Box::new(DeficitRoundRobin::new(vec![2, 1], unit_cost)));
+
+        let packets = block_on(join.collect::<Vec<i32>>());
+        assert_eq!(packets, vec![0, 0, 1, 0, 0, 1, 0, 0, 1]);
+    }
+
+    #[test]
+    #[should_panic(expected = "DeficitRoundRobin needs a quantum for at least one input")]
+    fn deficit_round_robin_needs_quanta() {
+        DeficitRoundRobin::new(vec![], unit_cost);
+    }
+
+    struct AsyncIdentityElement;
+
+    impl AsyncElement for AsyncIdentityElement {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            Verdict::Pass(packet)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn join_two_async_elements_interval_yield() {
+        let default_channel_size = 10;
+
+        let elem0_link = AsyncElementLink::new(
+            Box::pin(LinearIntervalGenerator::new(time::Duration::from_millis(10), 10)), AsyncIdentityElement, default_channel_size);
+        let elem1_link = AsyncElementLink::new(
+            Box::pin(immediate_stream(100..110)), AsyncIdentityElement, default_channel_size);
+
+        let join = JoinLink::round_robin(vec![Box::pin(elem0_link.provider), Box::pin(elem1_link.provider)]);
+
+        let elem0_drain = elem0_link.consumer;
+        let elem1_drain = elem1_link.consumer;
+
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        let collector = ExhaustiveCollector::new(0, Box::pin(join), Arc::clone(&packets));
+
+        let elem0_drain = tokio::spawn(elem0_drain);
+        let elem1_drain = tokio::spawn(elem1_drain);
+        let collector = tokio::spawn(collector);
+        elem0_drain.await.unwrap();
+        elem1_drain.await.unwrap();
+        collector.await.unwrap();
+
+        let mut packets = packets.lock().unwrap().clone();
+        packets.sort();
+        assert_eq!(packets, (0..=10).chain(100..110).collect::<Vec<i32>>());
+    }
+}
@@ -0,0 +1,112 @@
+use hdrhistogram::Histogram;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Latencies above this are recorded as this.
+const MAX_LATENCY: Duration = Duration::from_secs(60);
+
+/// A LatencyHistogram records durations, with nanosecond resolution, in an
+/// HDR histogram keeping three significant figures. It is shared like a
+/// Counter, so it can be read while the link recording into it runs.
+#[derive(Clone, Debug)]
+pub struct LatencyHistogram(Arc<Mutex<Histogram<u64>>>);
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        let histogram = Histogram::new_with_bounds(1, MAX_LATENCY.as_nanos() as u64, 3)
+            .expect("latency histogram bounds are valid");
+        LatencyHistogram(Arc::new(Mutex::new(histogram)))
+    }
+
+    pub fn record(&self, latency: Duration) {
+        let nanos = latency.as_nanos().clamp(1, MAX_LATENCY.as_nanos()) as u64;
+        self.0.lock().unwrap().saturating_record(nanos);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.0.lock().unwrap().len()
+    }
+
+    /// The latency that `percentile` percent of recorded latencies are at or
+    /// below, with `percentile` between 0 and 100.
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        Duration::from_nanos(self.0.lock().unwrap().value_at_percentile(percentile))
+    }
+
+    pub fn snapshot(&self) -> LatencySnapshot {
+        let histogram = self.0.lock().unwrap();
+        let at = |percentile| Duration::from_nanos(histogram.value_at_percentile(percentile));
+        LatencySnapshot {
+            count: histogram.len(),
+            min: Duration::from_nanos(histogram.min()),
+            p50: at(50.0),
+            p90: at(90.0),
+            p99: at(99.0),
+            p999: at(99.9),
+            max: Duration::from_nanos(histogram.max()),
+            mean: Duration::from_nanos(histogram.mean() as u64)
+        }
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram::new()
+    }
+}
+
+/// A summary of a LatencyHistogram at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    pub count: u64,
+    pub min: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration,
+    pub mean: Duration
+}
+
+/// The latencies recorded by an instrumented link: how long each call to
+/// the element's `process` took, and, for links with a queue, how long each
+/// packet sat in the queue before the provider handed it on.
+#[derive(Clone, Debug, Default)]
+pub struct LinkLatency {
+    pub processing: LatencyHistogram,
+    pub queueing: LatencyHistogram
+}
+
+impl LinkLatency {
+    pub fn new() -> Self {
+        LinkLatency::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether `actual` is within the 0.1% resolution of the histogram, with
+    /// some slack, of `expected`.
+    fn close_to(actual: Duration, expected: Duration) -> bool {
+        actual.as_nanos().abs_diff(expected.as_nanos()) * 100 <= expected.as_nanos()
+    }
+
+    #[test]
+    fn records_percentiles() {
+        let histogram = LatencyHistogram::new();
+        for micros in 1..=1000 {
+            histogram.record(Duration::from_micros(micros));
+        }
+        histogram.record(Duration::from_secs(3600));
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 1001);
+        assert_eq!(snapshot.min, Duration::from_micros(1));
+        assert!(close_to(snapshot.p50, Duration::from_micros(501)));
+        assert!(close_to(snapshot.p99, Duration::from_micros(991)));
+        assert!(close_to(snapshot.max, MAX_LATENCY));
+        assert_eq!(histogram.percentile(50.0), snapshot.p50);
+    }
+}
@@ -0,0 +1,148 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use crate::api::Counter;
+
+#[derive(Debug, Default)]
+struct Metrics {
+    packets_in: AtomicU64,
+    packets_out: AtomicU64,
+    queue_depth: AtomicUsize,
+    queue_high_water: AtomicUsize,
+    consumer_sleeps: AtomicU64,
+    provider_sleeps: AtomicU64,
+    consumer_stalled_nanos: AtomicU64,
+    provider_stalled_nanos: AtomicU64
+}
+
+/// LinkMetrics are the counters kept by a link, or by a single output port
+/// of a link with several. Like a Counter, they are shared, so a handle taken
+/// before the link is moved into a stream chain or spawned stays live.
+///
+/// For links with a queue, packets in are those pushed onto the queue by the
+/// consumer, and packets out are those taken off it by the provider. The
+/// consumer sleeps when the queue is full, and the provider when it is empty;
+/// we count both, along with how long they stayed asleep.
+#[derive(Clone, Debug)]
+pub struct LinkMetrics {
+    metrics: Arc<Metrics>,
+    drops: Counter,
+    queue_capacity: Option<usize>
+}
+
+impl LinkMetrics {
+    /// Metrics for a link without a queue, such as an ElementLink.
+    pub fn new() -> Self {
+        LinkMetrics {
+            metrics: Arc::new(Metrics::default()),
+            drops: Counter::new(),
+            queue_capacity: None
+        }
+    }
+
+    /// Metrics for a link whose provider reads from a queue of
+    /// `queue_capacity` packets.
+    pub fn queued(queue_capacity: usize) -> Self {
+        LinkMetrics {
+            queue_capacity: Some(queue_capacity),
+            ..LinkMetrics::new()
+        }
+    }
+
+    pub fn drop_counter(&self) -> Counter {
+        self.drops.clone()
+    }
+
+    pub fn snapshot(&self) -> LinkSnapshot {
+        let metrics = &self.metrics;
+        LinkSnapshot {
+            packets_in: metrics.packets_in.load(Ordering::Relaxed),
+            packets_out: metrics.packets_out.load(Ordering::Relaxed),
+            drops: self.drops.get(),
+            queue_depth: metrics.queue_depth.load(Ordering::Relaxed),
+            queue_high_water: metrics.queue_high_water.load(Ordering::Relaxed),
+            queue_capacity: self.queue_capacity,
+            consumer_sleeps: metrics.consumer_sleeps.load(Ordering::Relaxed),
+            provider_sleeps: metrics.provider_sleeps.load(Ordering::Relaxed),
+            consumer_stalled: Duration::from_nanos(metrics.consumer_stalled_nanos.load(Ordering::Relaxed)),
+            provider_stalled: Duration::from_nanos(metrics.provider_stalled_nanos.load(Ordering::Relaxed))
+        }
+    }
+
+    pub(crate) fn record_in(&self) {
+        self.metrics.packets_in.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_out(&self) {
+        self.metrics.packets_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_drop(&self) {
+        self.drops.incr();
+    }
+
+    /// A packet went onto the queue.
+    pub(crate) fn record_enqueue(&self) {
+        self.record_in();
+        let depth = self.metrics.queue_depth.fetch_add(1, Ordering::Relaxed) + 1;
+        self.metrics.queue_high_water.fetch_max(depth, Ordering::Relaxed);
+    }
+
+    /// A packet recorded with `record_enqueue` did not make it onto the
+    /// queue after all.
+    pub(crate) fn record_enqueue_failed(&self) {
+        self.metrics.packets_in.fetch_sub(1, Ordering::Relaxed);
+        self.metrics.queue_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// A packet came off the queue.
+    pub(crate) fn record_dequeue(&self) {
+        self.record_out();
+        self.metrics.queue_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// A packet was dropped off the queue before the provider got to it.
+    pub(crate) fn record_evict(&self) {
+        self.metrics.queue_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_consumer_sleep(&self) {
+        self.metrics.consumer_sleeps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_provider_sleep(&self) {
+        self.metrics.provider_sleeps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_consumer_stall(&self, since: Instant) {
+        self.metrics.consumer_stalled_nanos.fetch_add(since.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_provider_stall(&self, since: Instant) {
+        self.metrics.provider_stalled_nanos.fetch_add(since.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Default for LinkMetrics {
+    fn default() -> Self {
+        LinkMetrics::new()
+    }
+}
+
+/// The values of a link's LinkMetrics at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkSnapshot {
+    pub packets_in: u64,
+    pub packets_out: u64,
+    pub drops: u64,
+    pub queue_depth: usize,
+    pub queue_high_water: usize,
+    /// None for links without a queue.
+    pub queue_capacity: Option<usize>,
+    /// Times the consumer went to sleep on a full queue.
+    pub consumer_sleeps: u64,
+    /// Times the provider went to sleep on an empty queue.
+    pub provider_sleeps: u64,
+    pub consumer_stalled: Duration,
+    pub provider_stalled: Duration
+}
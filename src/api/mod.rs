@@ -1,90 +1,392 @@
-use futures::{Future, Stream, Async, Poll, task};
-use crossbeam::crossbeam_channel::{bounded, Sender, Receiver, TryRecvError};
+use futures::{Stream, ready};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::any::type_name;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-pub type ElementStream<Input> = Box<dyn Stream<Item = Input, Error = ()> + Send>;
+mod metrics;
+pub use self::metrics::{LinkMetrics, LinkSnapshot};
 
+mod latency;
+pub use self::latency::{LatencyHistogram, LatencySnapshot, LinkLatency};
+
+mod handlers;
+pub use self::handlers::{Handlers, HandlerInfo, HandlerError, HandlerTable, Shared, SharedHandlers};
+
+mod timers;
+pub use self::timers::Timers;
+
+mod state;
+pub use self::state::{TransferState, SharedState};
+
+mod classify;
+pub use self::classify::{ClassifyElement, ClassifyElementLink, ClassifyElementConsumer, ClassifyElementProvider};
+
+mod join;
+pub use self::join::{JoinLink, JoinScheduler, RoundRobin, StrictPriority, DeficitRoundRobin};
+
+mod tee;
+pub use self::tee::{TeeLink, TeePolicy, TeeConsumer, TeeProvider};
+
+mod future_element;
+pub use self::future_element::{FutureElement, FutureElementLink, FutureElementConsumer, FutureElementProvider};
+
+mod worker_pool;
+pub use self::worker_pool::{WorkerPoolLink, WorkerPoolProvider, WorkerConsumer};
+
+mod sharded;
+pub use self::sharded::{FlowHash, ShardedLink};
+
+mod budget;
+pub use self::budget::{PacketSize, MemoryBudget};
+use self::budget::QueueBytes;
+
+mod spsc;
+pub use self::spsc::{ring, RingProducer, RingConsumer, PushError, PopError};
+
+mod overflow;
+pub use self::overflow::OverflowPolicy;
+
+mod queue;
+use self::queue::{queue, QueueSender, QueueReceiver};
+
+mod args;
+pub use self::args::{ArgType, ArgValue, Args, Schema};
+
+pub type ElementStream<Input> = Pin<Box<dyn Stream<Item = Input> + Send>>;
+
+/// The Verdict is what an element decided to do with the packet it was
+/// handed. Most elements simply `Pass` on one packet for every packet they
+/// get, but filters can `Drop` packets, and elements such as fragmenters can
+/// emit `Many` packets, which are handed downstream in order.
+#[derive(Debug, PartialEq)]
+pub enum Verdict<T> {
+    Drop,
+    Pass(T),
+    Many(Vec<T>)
+}
+
+impl<T> From<Option<T>> for Verdict<T> {
+    fn from(packet: Option<T>) -> Self {
+        match packet {
+            Some(packet) => Verdict::Pass(packet),
+            None => Verdict::Drop
+        }
+    }
+}
+
+/// A Counter is a packet count that is shared between a link and anyone who
+/// wants to read it, so it stays readable after the link has been moved into
+/// a stream chain or handed to the runtime.
+#[derive(Clone, Debug, Default)]
+pub struct Counter(Arc<AtomicU64>);
+
+impl Counter {
+    pub fn new() -> Self {
+        Counter::default()
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn incr(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Runs `process`, recording how long it took if the link is instrumented.
+fn timed<T>(latency: &Option<LinkLatency>, process: impl FnOnce() -> T) -> T {
+    match latency {
+        None => process(),
+        Some(latency) => {
+            let started = Instant::now();
+            let result = process();
+            latency.processing.record(started.elapsed());
+            result
+        }
+    }
+}
+
+/// Runs a timer that fired, adding whatever packets it emits to `pending`.
+fn run_timer<T>(verdict: Verdict<T>, pending: &mut VecDeque<T>) {
+    match verdict {
+        Verdict::Pass(packet) => pending.push_back(packet),
+        Verdict::Many(packets) => pending.extend(packets),
+        Verdict::Drop => {}
+    }
+}
+
+/// The lifecycle of an element, as in Click: `initialize` is called once,
+/// before the first packet, and can refuse to start the element, say if a
+/// device it needs does not exist. `cleanup` is called on tear-down, if
+/// `initialize` succeeded. Elements that want timers keep the Timers they
+/// are handed in `initialize`, and get their `run_timer` called with the id
+/// of each timer that fires, emitting packets as `process` would.
 pub trait Element {
     type Input: Sized;
     type Output: Sized;
 
-    fn process(&mut self, packet: Self::Input) -> Self::Output;
+    fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output>;
+
+    fn initialize(&mut self, _timers: &Timers) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn cleanup(&mut self) {}
+
+    fn run_timer(&mut self, _timer: usize) -> Verdict<Self::Output> {
+        Verdict::Drop
+    }
 }
 
-pub struct ElementLink<E: Element> {
-    input_stream: ElementStream<E::Input>,
-    element: E
+/// The ElementLink runs a synchronous element on whatever packets its
+/// input stream hands it, and is a stream of what the element made of them
+/// in turn. The input stream can be any Unpin stream of the element's input,
+/// so a chain of ElementLinks built in code is one concrete type, which the
+/// compiler can inline all the way through. Graphs built at runtime, where
+/// the type of the input is not known up front, use the boxed ElementStream,
+/// which is the default.
+pub struct ElementLink<E: Element, S = ElementStream<<E as Element>::Input>> {
+    input_stream: S,
+    element: E,
+    pending: VecDeque<E::Output>,
+    metrics: LinkMetrics,
+    latency: Option<LinkLatency>,
+    timers: Timers,
+    initialized: bool
 }
 
-impl<E: Element> ElementLink<E> {
-    pub fn new(input_stream: ElementStream<E::Input>, element: E) -> Self {
+impl<E: Element, S: Stream<Item = E::Input> + Unpin> ElementLink<E, S> {
+    pub fn new(input_stream: S, element: E) -> Self {
         ElementLink {
             input_stream,
-            element
+            element,
+            pending: VecDeque::new(),
+            metrics: LinkMetrics::new(),
+            latency: None,
+            timers: Timers::new(),
+            initialized: false
+        }
+    }
+
+    /// Initializes the element, if it has not been already. Links that are
+    /// not initialized up front are on their first poll, and panic if the
+    /// element refuses to start.
+    pub fn initialize(&mut self) -> Result<(), String> {
+        if !self.initialized {
+            self.element.initialize(&self.timers)?;
+            self.initialized = true;
         }
+        Ok(())
+    }
+
+    /// Like `new`, but times every call to the element's `process`. The
+    /// timing is off by default, since reading the clock for every packet
+    /// is not free.
+    pub fn instrumented(input_stream: S, element: E) -> Self {
+        let mut link = ElementLink::new(input_stream, element);
+        link.latency = Some(LinkLatency::new());
+        link
+    }
+
+    /// The processing latency of the element, if the link is instrumented.
+    pub fn latency(&self) -> Option<LinkLatency> {
+        self.latency.clone()
+    }
+
+    /// Number of input packets the element has dropped so far.
+    pub fn drop_counter(&self) -> Counter {
+        self.metrics.drop_counter()
+    }
+
+    /// Packets in and out of the element, and how many it dropped.
+    pub fn metrics(&self) -> LinkMetrics {
+        self.metrics.clone()
     }
 }
 
-impl<E: Element> Stream for ElementLink<E> {
+/// The element is never pinned, we only ever hand out `&mut` to it for
+/// `process`, so the link can be moved freely even if the element is `!Unpin`.
+impl<E: Element, S: Unpin> Unpin for ElementLink<E, S> {}
+
+impl<E: Element, S> Drop for ElementLink<E, S> {
+    fn drop(&mut self) {
+        if self.initialized {
+            self.element.cleanup();
+        }
+    }
+}
+
+impl<E: Element, S: Stream<Item = E::Input> + Unpin> Stream for ElementLink<E, S> {
     type Item = E::Output;
-    type Error = ();
 
     /*
-    4 cases: Async::Ready(Some), Async::Ready(None), Async::NotReady, Err
+    3 cases: Poll::Ready(Some), Poll::Ready(None), Poll::Pending
 
-    Async::Ready(Some): We have a packet ready to process from the upstream element. It's passed to
-    our core's process function for... processing
+    Before anything else, we initialize the element if that has not been done yet, and run any of
+    its timers that are due, queueing whatever packets they emit as if they were processed.
 
-    Async::Ready(None): The input_stream doesn't have anymore input. Semantically, it's like an
-    iterator has exhausted it's input. We should return "Ok(Async::Ready(None))" to signify to our
-    downstream components that there's no more input to process. Our Elements should rarely
-    return "Async::Ready(None)" since it will effectively kill the Stream chain.
+    Poll::Ready(Some): We have a packet ready to process from the upstream element. It's passed to
+    our core's process function for... processing. If the element drops it, we go back to the
+    input_stream for another packet rather than returning anything. If the element emits several
+    packets, we hand out the first and keep the rest, which are returned on the following polls
+    before we pull any more input.
 
-    Async::NotReady: There is more input for us to process, but we can't make any more progress right
-    now. The contract for Streams asks us to register with a Reactor so we will be woken up again by
-    an Executor, but we will be relying on Tokio to do that for us. This case is handled by the
-    "try_ready!" macro, which will automatically return "Ok(Async::NotReady)" if the input stream
-    gives us NotReady.
+    Poll::Ready(None): The input_stream doesn't have anymore input. Semantically, it's like an
+    iterator has exhausted it's input. We should return "Poll::Ready(None)" to signify to our
+    downstream components that there's no more input to process. Our Elements should rarely
+    return "Poll::Ready(None)" since it will effectively kill the Stream chain.
 
-    Err: is also handled by the "try_ready!" macro.
+    Poll::Pending: There is more input for us to process, but we can't make any more progress right
+    now. The contract for Streams asks us to register a Waker so we will be woken up again by
+    an Executor, but we are relying on the input_stream, which was handed the same Context, to do
+    that for us. This case is handled by the "ready!" macro, which will automatically return
+    "Poll::Pending" if the input stream gives us Pending.
     */
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let input_packet_option: Option<E::Input> = try_ready!(self.input_stream.poll());
-        match input_packet_option {
-            None => Ok(Async::Ready(None)),
-            Some(input_packet) => {
-                let output_packet: E::Output = self.element.process(input_packet);
-                Ok(Async::Ready(Some(output_packet)))
-            },
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let link = self.get_mut();
+        if let Err(message) = link.initialize() {
+            panic!("{} failed to initialize: {}", type_name::<E>(), message);
+        }
+        while let Poll::Ready(timer) = link.timers.poll_expired(cx) {
+            run_timer(link.element.run_timer(timer), &mut link.pending);
+        }
+        loop {
+            if let Some(output_packet) = link.pending.pop_front() {
+                link.metrics.record_out();
+                return Poll::Ready(Some(output_packet))
+            }
+
+            let input_packet_option: Option<E::Input> = ready!(Pin::new(&mut link.input_stream).poll_next(cx));
+            match input_packet_option {
+                None => return Poll::Ready(None),
+                Some(input_packet) => {
+                    link.metrics.record_in();
+                    let element = &mut link.element;
+                    match timed(&link.latency, || element.process(input_packet)) {
+                        Verdict::Pass(output_packet) => {
+                            link.metrics.record_out();
+                            return Poll::Ready(Some(output_packet))
+                        },
+                        Verdict::Drop => link.metrics.record_drop(),
+                        Verdict::Many(output_packets) => {
+                            if output_packets.is_empty() {
+                                link.metrics.record_drop();
+                            }
+                            link.pending.extend(output_packets);
+                        }
+                    }
+                },
+            }
         }
     }
 }
 
+/// Has the same lifecycle as an Element, with timers run on the consumer's
+/// task.
 pub trait AsyncElement {
     type Input: Sized;
     type Output: Sized;
 
-    fn process(&mut self, packet: Self::Input) -> Self::Output;
+    fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output>;
+
+    fn initialize(&mut self, _timers: &Timers) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn cleanup(&mut self) {}
+
+    fn run_timer(&mut self, _timer: usize) -> Verdict<Self::Output> {
+        Verdict::Drop
+    }
 }
 
 /// The AsyncElementLink is a wrapper to create and contain both sides of the
 /// link, the consumer, which intakes and processes packets, and the provider,
 /// which provides an interface where the next element retrieves the output
-/// packet.
-pub struct AsyncElementLink< E: AsyncElement> {
-    pub consumer: AsyncElementConsumer<E>,
+/// packet. The two sides share a single-producer, single-consumer ring, which
+/// also takes care of waking either side when the other makes progress. Like
+/// an ElementLink, the consumer takes any Unpin stream as input, the boxed
+/// ElementStream by default.
+pub struct AsyncElementLink<E: AsyncElement, S = ElementStream<<E as AsyncElement>::Input>> {
+    pub consumer: AsyncElementConsumer<E, S>,
     pub provider: AsyncElementProvider<E>
 }
 
-impl<E: AsyncElement> AsyncElementLink<E> {
-    pub fn new(input_stream: ElementStream<E::Input>, element: E, queue_capacity: usize) -> Self {
+impl<E: AsyncElement, S: Stream<Item = E::Input> + Unpin> AsyncElementLink<E, S> {
+    pub fn new(input_stream: S, element: E, queue_capacity: usize) -> Self {
+        AsyncElementLink::build(input_stream, element, queue_capacity, None)
+    }
+
+    /// Like `new`, but times every call to the element's `process`, and
+    /// timestamps packets as they are queued to find out how long they wait
+    /// for the provider.
+    pub fn instrumented(input_stream: S, element: E, queue_capacity: usize) -> Self {
+        AsyncElementLink::build(input_stream, element, queue_capacity, Some(LinkLatency::new()))
+    }
+
+    /// Has the consumer follow `policy` when the queue is full. Links start
+    /// out with `OverflowPolicy::Block`.
+    pub fn overflow(mut self, policy: OverflowPolicy) -> Self {
+        self.consumer.to_provider.set_policy(policy);
+        self
+    }
+
+    /// Bounds the queue by the bytes of the packets in it, as well as by
+    /// their number. A full queue is handled by the OverflowPolicy, whichever
+    /// bound it hit. HeadDrop only evicts by count, so it panics when
+    /// combined with a byte capacity.
+    pub fn byte_capacity(self, byte_capacity: usize) -> Self
+        where E::Output: PacketSize
+    {
+        let bytes = self.queue_bytes().with_capacity(byte_capacity);
+        self.count_bytes(bytes)
+    }
+
+    /// Counts the bytes in the queue against `budget`, dropping packets that
+    /// would go over it.
+    pub fn memory_budget(self, budget: &MemoryBudget) -> Self
+        where E::Output: PacketSize
+    {
+        let bytes = self.queue_bytes().with_budget(budget.clone());
+        self.count_bytes(bytes)
+    }
+
+    fn queue_bytes(&self) -> QueueBytes<E::Output>
+        where E::Output: PacketSize
+    {
+        self.consumer.to_provider.bytes().unwrap_or_else(QueueBytes::new)
+    }
+
+    fn count_bytes(mut self, bytes: QueueBytes<E::Output>) -> Self {
+        self.consumer.to_provider.set_bytes(bytes.clone());
+        self.provider.from_consumer.set_bytes(bytes);
+        self
+    }
+
+    /// Initializes the element, as `ElementLink::initialize` does.
+    pub fn initialize(&mut self) -> Result<(), String> {
+        self.consumer.initialize()
+    }
 
-        let (to_provider, from_consumer) = bounded::<Option<E::Output>>(queue_capacity);
-        let (await_provider, wake_provider) = bounded::<task::Task>(1);
-        let (await_consumer, wake_consumer) = bounded::<task::Task>(1);
+    fn build(input_stream: S, element: E, queue_capacity: usize, latency: Option<LinkLatency>) -> Self {
+        let (to_provider, from_consumer) = queue(queue_capacity, latency);
 
         AsyncElementLink {
-            consumer: AsyncElementConsumer::new(input_stream, to_provider, element, await_consumer, wake_provider),
-            provider: AsyncElementProvider::new(from_consumer, await_provider, wake_consumer)
+            consumer: AsyncElementConsumer {
+                input_stream,
+                input_finished: false,
+                to_provider,
+                element,
+                timers: Timers::new(),
+                initialized: false
+            },
+            provider: AsyncElementProvider { from_consumer }
         }
     }
 }
@@ -93,48 +395,109 @@ impl<E: AsyncElement> AsyncElementLink<E> {
 /// processing them using the `element`s process function, and pushing the
 /// output packet onto the to_provider queue. It does work in batches, so it
 /// will continue to pull packets as long as it can make forward progess,
-/// after which it will return NotReady to sleep. This is handed to, and is
+/// after which it will return Pending to sleep. This is handed to, and is
 /// polled by the runtime.
-pub struct AsyncElementConsumer<E: AsyncElement> {
-    input_stream: ElementStream<E::Input>,
-    to_provider: Sender<Option<E::Output>>,
+pub struct AsyncElementConsumer<E: AsyncElement, S = ElementStream<<E as AsyncElement>::Input>> {
+    input_stream: S,
+    input_finished: bool,
+    to_provider: QueueSender<E::Output>,
     element: E,
-    await_provider: Sender<task::Task>,
-    wake_provider: Receiver<task::Task>
+    timers: Timers,
+    initialized: bool
 }
 
-impl<E: AsyncElement> AsyncElementConsumer<E> {
-    fn new(
-        input_stream: ElementStream<E::Input>, 
-        to_provider: Sender<Option<E::Output>>, 
-        element: E,
-        await_provider: Sender<task::Task>,
-        wake_provider: Receiver<task::Task>) 
-    -> Self {
-        AsyncElementConsumer {
-            input_stream,
-            to_provider,
-            element,
-            await_provider,
-            wake_provider
+impl<E: AsyncElement, S: Stream<Item = E::Input> + Unpin> AsyncElementConsumer<E, S> {
+    /// Initializes the element, as `ElementLink::initialize` does.
+    pub fn initialize(&mut self) -> Result<(), String> {
+        if !self.initialized {
+            self.element.initialize(&self.timers)?;
+            self.initialized = true;
+        }
+        Ok(())
+    }
+
+    /// The latencies recorded by the link, if it is instrumented. Shared
+    /// with its provider.
+    pub fn latency(&self) -> Option<LinkLatency> {
+        self.to_provider.latency().clone()
+    }
+
+    /// Number of input packets the element has dropped so far.
+    pub fn drop_counter(&self) -> Counter {
+        self.to_provider.metrics().drop_counter()
+    }
+
+    /// The metrics of the link, shared with its provider.
+    pub fn metrics(&self) -> LinkMetrics {
+        self.to_provider.metrics().clone()
+    }
+
+    /// Number of packets dropped by the link's OverflowPolicy so far. These
+    /// are counted as drops too. Always zero under `OverflowPolicy::Block`.
+    pub fn overflow_counter(&self) -> Counter {
+        self.to_provider.overflow_counter()
+    }
+
+    fn poll_work(&mut self, cx: &mut Context) -> Poll<()> {
+        loop {
+            if let Some(poll) = self.to_provider.poll_push(cx, self.input_finished) {
+                return poll
+            }
+            // Under HeadDrop, we may still have packets stashed here, which
+            // we need to be woken for once there is room.
+            let stashed = !self.to_provider.pending.is_empty();
+
+            match Pin::new(&mut self.input_stream).poll_next(cx) {
+                Poll::Pending => {
+                    if stashed {
+                        self.to_provider.park(cx);
+                    }
+                    return Poll::Pending
+                },
+                Poll::Ready(None) => {
+                    self.input_finished = true;
+                    if !stashed {
+                        return Poll::Ready(())
+                    }
+                },
+                Poll::Ready(Some(input_packet)) => {
+                    let element = &mut self.element;
+                    let queue = &mut self.to_provider;
+                    match timed(queue.latency(), || element.process(input_packet)) {
+                        Verdict::Pass(output_packet) => queue.pending.push_back(output_packet),
+                        Verdict::Drop => queue.metrics().record_drop(),
+                        Verdict::Many(output_packets) => {
+                            if output_packets.is_empty() {
+                                queue.metrics().record_drop();
+                            }
+                            queue.pending.extend(output_packets);
+                        }
+                    }
+                    if stashed {
+                        queue.drop_oldest();
+                    }
+                }
+            }
         }
     }
 }
 
-impl<E: AsyncElement> Drop for AsyncElementConsumer<E> {
+impl<E: AsyncElement, S> Drop for AsyncElementConsumer<E, S> {
+    /// Closing our end of the ring publishes whatever we pushed last. The
+    /// provider drains whatever is left in the queue and then sees the ring
+    /// closed.
     fn drop(&mut self) {
-        if let Err(err) = self.to_provider.try_send(None) {
-            panic!("Consumer: Drop: try_send to_provider, fail?: {:?}", err);
+        self.to_provider.close();
+        if self.initialized {
+            self.element.cleanup();
         }
-        if let Ok(task) = self.wake_provider.try_recv() {
-            task.notify();
-        } 
     }
 }
 
-impl<E: AsyncElement> Future for AsyncElementConsumer<E> {
-    type Item = ();
-    type Error = ();
+impl<E: AsyncElement, S: Unpin> Unpin for AsyncElementConsumer<E, S> {}
+
+impl<E: AsyncElement, S: Stream<Item = E::Input> + Unpin> Future for AsyncElementConsumer<E, S> {
+    type Output = ();
 
     /// Implement Poll for Future for AsyncElementConsumer
     /// 
@@ -142,117 +505,80 @@ impl<E: AsyncElement> Future for AsyncElementConsumer<E> {
     /// packets off it's input queue until it reaches a point where it can not
     /// make forward progress. There are three cases:
     /// ###
-    /// #1 The to_provider queue is full, we notify the provider that we need
-    /// awaking when there is work to do, and go to sleep.
+    /// #1 The to_provider queue is full, we park on the ring, so that the
+    /// provider awakens us once it makes room, and go to sleep.
     /// 
-    /// #2 The input_stream returns a NotReady, we sleep, with the assumption
-    /// that whomever produced the NotReady will awaken the task in the Future.
+    /// #2 The input_stream returns a Pending, we sleep, with the assumption
+    /// that whomever produced the Pending will awaken the task in the Future.
     /// 
-    /// #3 We get a Ready(None), in which case we push a None onto the to_provider
-    /// queue and then return Ready(()), which means we enter tear-down, since there
-    /// is no futher work to complete.
+    /// #3 We get a Ready(None), in which case we return Ready(()), which means
+    /// we enter tear-down, since there is no futher work to complete. Dropping
+    /// the consumer closes the to_provider queue.
     /// ###
-    /// By Sleep, we mean we return a NotReady to the runtime which will sleep the task.
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        loop{
-            if self.to_provider.is_full() {
-                let task = task::current();
-                if let Err(_) = self.await_provider.try_send(task) {
-                    task::current().notify();
-                }
-                return Ok(Async::NotReady)
-            }
-            let input_packet_option: Option<E::Input> = try_ready!(self.input_stream.poll());
-
-            match input_packet_option {
-                None => {
-                    return Ok(Async::Ready(()))
-                }
-                Some(input_packet) => {
-                    let output_packet: E::Output = self.element.process(input_packet);
-                    if let Err(err) = self.to_provider.send(Some(output_packet)) {
-                        panic!("Error in to_provider sender, have nowhere to put packet: {:?}", err);
-                    }
-                    if let Ok(task) = self.wake_provider.try_recv() {
-                        task.notify();
-                    }
-                },
-            }
+    /// Packets the element dropped are counted and never reach the queue. When
+    /// the element emits several packets at once, they are kept aside and pushed
+    /// one at a time, before any more input is pulled, so case #1 still applies.
+    /// Under TailDrop and RandomEarlyDetection, a full queue costs a packet
+    /// rather than a sleep, so case #1 does not arise. Under HeadDrop, we keep
+    /// pulling input into the stash, dropping the oldest packets, and only
+    /// sleep on a full queue once the input has run dry.
+    /// Should the provider go away, we have nowhere to put packets, and also
+    /// enter tear-down. By Sleep, we mean we return a Pending to the runtime
+    /// which will sleep the task.
+    ///
+    /// The packets we push are published to the provider in batches, so
+    /// whichever way we return, we publish what is left first.
+    ///
+    /// Before anything else, we initialize the element if that has not been
+    /// done yet, and run any of its timers that are due, keeping whatever
+    /// packets they emit aside to be pushed like any others.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let consumer = self.get_mut();
+        if let Err(message) = consumer.initialize() {
+            panic!("{} failed to initialize: {}", type_name::<E>(), message);
+        }
+        while let Poll::Ready(timer) = consumer.timers.poll_expired(cx) {
+            run_timer(consumer.element.run_timer(timer), &mut consumer.to_provider.pending);
         }
+        consumer.to_provider.record_stall();
+        let poll = consumer.poll_work(cx);
+        consumer.to_provider.flush();
+        poll
     }
 }
 
 /// The Provider side of the AsyncElement is responsible to converting the
-/// output queue of processed packets, which is a crossbeam channel, to a 
-/// Stream that can be polled for packets. It ends up being owned by the 
-/// element which is polling for packets. 
+/// output queue of processed packets, which is a ring, to a Stream that can
+/// be polled for packets. It ends up being owned by the element which is
+/// polling for packets.
 pub struct AsyncElementProvider<E: AsyncElement> {
-    from_consumer: Receiver<Option<E::Output>>,
-    await_consumer: Sender<task::Task>,
-    wake_consumer: Receiver<task::Task>
+    from_consumer: QueueReceiver<E::Output>
 }
 
 impl<E: AsyncElement> AsyncElementProvider<E> {
-    fn new(from_consumer: Receiver<Option<E::Output>>, await_consumer: Sender<task::Task>, wake_consumer: Receiver<task::Task>) -> Self {
-        AsyncElementProvider {
-            from_consumer,
-            await_consumer,
-            wake_consumer
-        }
+    /// The latencies recorded by the link, if it is instrumented. Shared
+    /// with its consumer.
+    pub fn latency(&self) -> Option<LinkLatency> {
+        self.from_consumer.latency().clone()
     }
-}
 
-impl<E: AsyncElement> Drop for AsyncElementProvider<E> {
-    fn drop(&mut self) {
-        if let Ok(task) = self.wake_consumer.try_recv() {
-            task.notify();
-        }
+    /// The metrics of the link, shared with its consumer.
+    pub fn metrics(&self) -> LinkMetrics {
+        self.from_consumer.metrics().clone()
     }
 }
 
+impl<E: AsyncElement> Unpin for AsyncElementProvider<E> {}
+
 impl<E: AsyncElement> Stream for AsyncElementProvider<E> {
     type Item = E::Output;
-    type Error = ();
 
     ///Implement Poll for Stream for AsyncElementProvider
     /// 
-    /// This function, tries to retrieve a packet off the `from_consumer`
-    /// channel, there are four cases: 
-    /// ###
-    /// #1 Ok(Some(Packet)): Got a packet.if the consumer needs (likely due to 
-    /// an until now full channel) to be awoken, wake them. Return the Async::Ready(Option(Packet))
-    /// 
-    /// #2 Ok(None): this means that the consumer is in tear-down, and we
-    /// will no longer be receivig packets. Return Async::Ready(None) to forward propagate teardown
-    /// 
-    /// #3 Err(TryRecvError::Empty): Packet queue is empty, await the consumer to awaken us with more
-    /// work, and return Async::NotReady to signal to runtime to sleep this task.
-    /// 
-    /// #4 Err(TryRecvError::Disconnected): Consumer is in teardown and has dropped its side of the
-    /// from_consumer channel; we will no longer receive packets. Return Async::Ready(None) to forward
-    /// propagate teardown.
-    /// ###
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        match self.from_consumer.try_recv() {
-            Ok(Some(packet)) => {
-                if let Ok(task) = self.wake_consumer.try_recv() {
-                        task.notify();
-                }
-                Ok(Async::Ready(Some(packet)))
-            },
-            Ok(None) => {
-                Ok(Async::Ready(None))
-            },
-            Err(TryRecvError::Empty) => {
-                let task = task::current();
-                if let Err(_) = self.await_consumer.try_send(task) {
-                    task::current().notify();
-                }
-                Ok(Async::NotReady)
-            },
-            Err(TryRecvError::Disconnected) => {
-                Ok(Async::Ready(None))
-            }
-        }
+    /// Takes the next packet off the `from_consumer` queue, sleeping until
+    /// the consumer has more work for us if it is empty, and forwarding
+    /// teardown once the consumer has closed the queue and it is drained.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.get_mut().from_consumer.poll_next(cx)
     }
-}
\ No newline at end of file
+}
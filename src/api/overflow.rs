@@ -0,0 +1,125 @@
+/// What an AsyncElementLink's consumer does with a packet when the queue to
+/// its provider is full, or, under random early detection, filling up.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OverflowPolicy {
+    /// Sleep until the provider makes room. Back-pressure travels all the
+    /// way upstream, to the source if need be, and no packet is lost.
+    #[default]
+    Block,
+    /// Drop the packet that did not fit.
+    TailDrop,
+    /// Drop the oldest packet in the queue to make room for the new one, so
+    /// what the provider hands on is as fresh as can be. The queue must not
+    /// also have a byte capacity, since packets are evicted by count.
+    HeadDrop,
+    /// Drop packets at random once the average queue depth goes above
+    /// `min_threshold`, with a probability rising from zero to
+    /// `max_probability` as it nears `max_threshold`, and every packet
+    /// beyond that. Packets that do not fit are tail-dropped.
+    RandomEarlyDetection {
+        min_threshold: usize,
+        max_threshold: usize,
+        max_probability: f64
+    }
+}
+
+/// How quickly the average queue depth follows the actual one. RED as first
+/// described uses 0.002, for queues thousands of packets deep; ours are far
+/// shorter, so we let the average follow more quickly.
+const WEIGHT: f64 = 0.125;
+
+/// The state random early detection keeps between packets.
+pub(crate) struct EarlyDetection {
+    min_threshold: f64,
+    max_threshold: f64,
+    max_probability: f64,
+    average: f64,
+    /// A xorshift generator. It need not be any good, only cheap.
+    random: u64
+}
+
+impl EarlyDetection {
+    pub(crate) fn new(min_threshold: usize, max_threshold: usize, max_probability: f64) -> Self {
+        assert!(min_threshold < max_threshold, "RandomEarlyDetection needs min_threshold below max_threshold");
+        EarlyDetection {
+            min_threshold: min_threshold as f64,
+            max_threshold: max_threshold as f64,
+            max_probability,
+            average: 0.0,
+            random: 0x9e37_79b9_7f4a_7c15
+        }
+    }
+
+    /// Updates the average with the current `queue_depth`, and decides
+    /// whether the next packet should be dropped.
+    pub(crate) fn should_drop(&mut self, queue_depth: usize) -> bool {
+        self.average += WEIGHT * (queue_depth as f64 - self.average);
+        if self.average < self.min_threshold {
+            false
+        } else if self.average >= self.max_threshold {
+            true
+        } else {
+            let probability = self.max_probability * (self.average - self.min_threshold)
+                / (self.max_threshold - self.min_threshold);
+            self.next_random() < probability
+        }
+    }
+
+    /// A number in [0, 1).
+    fn next_random(&mut self) -> f64 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        (self.random >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{AsyncElement, AsyncElementLink, Verdict};
+    use crate::utils::test::packet_generators::immediate_stream;
+    use futures::StreamExt;
+
+    struct Identity;
+
+    impl AsyncElement for Identity {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            Verdict::Pass(packet)
+        }
+    }
+
+    /// Runs `num_packets` into a queue of `queue_capacity`, and returns what
+    /// made it through along with the number of packets the policy dropped.
+    /// The consumer gets to run ahead of the provider, having the input all
+    /// to hand, so the queue fills up before the provider takes any.
+    async fn overflow(policy: OverflowPolicy, num_packets: i32, queue_capacity: usize) -> (Vec<i32>, u64) {
+        let link = AsyncElementLink::new(immediate_stream(0..num_packets), Identity, queue_capacity).overflow(policy);
+        let overflows = link.consumer.overflow_counter();
+        let drops = link.consumer.drop_counter();
+        let consumer = tokio::spawn(link.consumer);
+        let packets = link.provider.collect().await;
+        consumer.await.unwrap();
+        assert_eq!(overflows.get(), drops.get());
+        (packets, overflows.get())
+    }
+
+    #[tokio::test]
+    async fn drops_tail_or_head_of_full_queue() {
+        assert_eq!(overflow(OverflowPolicy::TailDrop, 10, 4).await, (vec![0, 1, 2, 3], 6));
+        assert_eq!(overflow(OverflowPolicy::HeadDrop, 10, 4).await, (vec![6, 7, 8, 9], 6));
+    }
+
+    #[tokio::test]
+    async fn drops_early_as_queue_fills() {
+        let policy = OverflowPolicy::RandomEarlyDetection { min_threshold: 8, max_threshold: 32, max_probability: 0.5 };
+        let (packets, overflows) = overflow(policy, 200, 64).await;
+        // Dropping early leaves the queue with room to spare.
+        assert!(packets.len() > 8);
+        assert!(packets.len() < 64);
+        assert_eq!(packets.len() as u64 + overflows, 200);
+    }
+}
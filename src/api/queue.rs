@@ -0,0 +1,358 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use crate::api::{
+    Counter, LinkMetrics, LinkLatency, OverflowPolicy,
+    ring, RingProducer, RingConsumer, PushError
+};
+use crate::api::budget::QueueBytes;
+use crate::api::overflow::EarlyDetection;
+
+/// A packet on a link's queue, along with when it was queued if the link is
+/// instrumented.
+type Queued<Packet> = (Packet, Option<Instant>);
+
+/// Creates both ends of the queue between a link's consumer and its
+/// provider, as used by the AsyncElementLink and the FutureElementLink.
+pub(crate) fn queue<Packet>(queue_capacity: usize, latency: Option<LinkLatency>) -> (QueueSender<Packet>, QueueReceiver<Packet>) {
+    let (to_provider, from_consumer) = ring::<Queued<Packet>>(queue_capacity);
+    let metrics = LinkMetrics::queued(queue_capacity);
+    let overflows = Counter::new();
+    let discard_before = Arc::new(AtomicUsize::new(0));
+    (
+        QueueSender {
+            to_provider,
+            pending: VecDeque::new(),
+            metrics: metrics.clone(),
+            latency: latency.clone(),
+            stalled_since: None,
+            policy: OverflowPolicy::Block,
+            early_detection: None,
+            overflows: overflows.clone(),
+            discard_before: Arc::clone(&discard_before),
+            bytes: None
+        },
+        QueueReceiver {
+            from_consumer,
+            metrics,
+            latency,
+            stalled_since: None,
+            overflows,
+            discard_before,
+            bytes: None
+        }
+    )
+}
+
+/// The consumer's end of the queue. Packets the consumer has ready are
+/// stashed in `pending`, and pushed onto the ring as the OverflowPolicy and
+/// the room in the queue allow.
+pub(crate) struct QueueSender<Packet> {
+    to_provider: RingProducer<Queued<Packet>>,
+    /// Packets waiting to be pushed.
+    pub(crate) pending: VecDeque<Packet>,
+    metrics: LinkMetrics,
+    latency: Option<LinkLatency>,
+    /// When we went to sleep on a full queue, if we did.
+    stalled_since: Option<Instant>,
+    policy: OverflowPolicy,
+    early_detection: Option<EarlyDetection>,
+    overflows: Counter,
+    /// Under HeadDrop, the index in the queue below which the provider drops
+    /// packets rather than handing them on, shared with the provider.
+    discard_before: Arc<AtomicUsize>,
+    /// The bytes in the queue, if it is bounded by them or has a budget.
+    bytes: Option<QueueBytes<Packet>>
+}
+
+impl<Packet> QueueSender<Packet> {
+    pub(crate) fn metrics(&self) -> &LinkMetrics {
+        &self.metrics
+    }
+
+    pub(crate) fn latency(&self) -> &Option<LinkLatency> {
+        &self.latency
+    }
+
+    pub(crate) fn overflow_counter(&self) -> Counter {
+        self.overflows.clone()
+    }
+
+    pub(crate) fn bytes(&self) -> Option<QueueBytes<Packet>> {
+        self.bytes.clone()
+    }
+
+    pub(crate) fn set_policy(&mut self, policy: OverflowPolicy) {
+        assert!(
+            policy != OverflowPolicy::HeadDrop || !self.bytes.as_ref().is_some_and(QueueBytes::is_bounded),
+            "HeadDrop cannot be combined with a byte capacity"
+        );
+        self.policy = policy;
+        self.early_detection = match policy {
+            OverflowPolicy::RandomEarlyDetection { min_threshold, max_threshold, max_probability } =>
+                Some(EarlyDetection::new(min_threshold, max_threshold, max_probability)),
+            _ => None
+        };
+    }
+
+    /// HeadDrop evicts queued packets by count, so it cannot make room in a
+    /// queue that is full by bytes, and the two are not to be combined.
+    pub(crate) fn set_bytes(&mut self, bytes: QueueBytes<Packet>) {
+        assert!(
+            !self.drops_head() || !bytes.is_bounded(),
+            "HeadDrop cannot be combined with a byte capacity"
+        );
+        self.bytes = Some(bytes);
+    }
+
+    /// Under HeadDrop, we keep taking in packets while the queue is full,
+    /// dropping the oldest ones, rather than sleeping.
+    pub(crate) fn drops_head(&self) -> bool {
+        self.policy == OverflowPolicy::HeadDrop
+    }
+
+    /// Records how long we were asleep on a full queue, if we were.
+    pub(crate) fn record_stall(&mut self) {
+        if let Some(since) = self.stalled_since.take() {
+            self.metrics.record_consumer_stall(since);
+        }
+    }
+
+    fn record_overflow(&self) {
+        self.overflows.incr();
+        self.metrics.record_drop();
+    }
+
+    /// Whether `packet` would fit in the to_provider queue, by count and by
+    /// bytes.
+    fn has_room(&self, packet: &Packet) -> bool {
+        !self.to_provider.is_full()
+            && self.bytes.as_ref().is_none_or(|bytes| bytes.fits(bytes.size_of(packet)))
+    }
+
+    /// Tries to push `packet` onto the to_provider queue. If the queue is
+    /// full, what happens depends on the OverflowPolicy: under Block and
+    /// HeadDrop, the packet is handed back so it can be stashed until there
+    /// is room, and otherwise it is dropped. If the provider has gone away,
+    /// or the packet would go over the link's MemoryBudget, the packet is
+    /// dropped.
+    fn try_push(&mut self, packet: Packet) -> Result<(), PushError<Packet>> {
+        if self.to_provider.is_closed() {
+            self.metrics.record_drop();
+            return Err(PushError::Closed(packet))
+        }
+        if let Some(early_detection) = &mut self.early_detection {
+            if early_detection.should_drop(self.to_provider.len()) {
+                self.record_overflow();
+                return Ok(())
+            }
+        }
+        if self.to_provider.is_full() {
+            return self.overflow(packet)
+        }
+        let size = match &self.bytes {
+            Some(bytes) => {
+                let size = bytes.size_of(&packet);
+                if !bytes.fits(size) {
+                    return self.overflow(packet)
+                }
+                if !bytes.reserve(size) {
+                    self.metrics.record_drop();
+                    return Ok(())
+                }
+                size
+            },
+            None => 0
+        };
+        // The provider may take the packet off the queue as soon as it is
+        // pushed, so it is counted on beforehand. Only we push, so having
+        // found room above, the push can only fail if the provider has gone
+        // since.
+        self.metrics.record_enqueue();
+        let queued_at = self.latency.as_ref().map(|_| Instant::now());
+        match self.to_provider.try_push((packet, queued_at)) {
+            Ok(()) => Ok(()),
+            Err(PushError::Full((packet, _))) | Err(PushError::Closed((packet, _))) => {
+                self.metrics.record_enqueue_failed();
+                self.release(size);
+                self.metrics.record_drop();
+                Err(PushError::Closed(packet))
+            }
+        }
+    }
+
+    /// Gives back the bytes of a packet that left the queue, or never made
+    /// it on.
+    fn release(&self, size: usize) {
+        if let Some(bytes) = &self.bytes {
+            bytes.release(size);
+        }
+    }
+
+    /// Handles `packet` not fitting in the queue, as the link's
+    /// OverflowPolicy has it.
+    fn overflow(&mut self, packet: Packet) -> Result<(), PushError<Packet>> {
+        match self.policy {
+            OverflowPolicy::Block | OverflowPolicy::HeadDrop => Err(PushError::Full(packet)),
+            OverflowPolicy::TailDrop | OverflowPolicy::RandomEarlyDetection { .. } => {
+                self.record_overflow();
+                Ok(())
+            }
+        }
+    }
+
+    /// Under HeadDrop, keeps only the newest packets, as many as the queue
+    /// holds, between the queue and those stashed in `pending`. Stashed
+    /// packets beyond that are dropped here, and the provider is told to
+    /// drop the queued ones when it comes to them.
+    pub(crate) fn drop_oldest(&mut self) {
+        let queue_capacity = self.to_provider.capacity();
+        while self.pending.len() > queue_capacity {
+            self.pending.pop_front();
+            self.record_overflow();
+        }
+        let keep_queued = queue_capacity - self.pending.len();
+        let discard_before = self.to_provider.tail().saturating_sub(keep_queued);
+        self.discard_before.fetch_max(discard_before, Ordering::Release);
+    }
+
+    /// Pushes stashed packets until the queue is full, and then, unless
+    /// HeadDrop has us keep going while there is still input, sleeps until
+    /// the provider makes room. Returns None if there is no need to sleep,
+    /// having pushed everything or being under HeadDrop, and Ready if the
+    /// provider has gone away.
+    pub(crate) fn poll_push(&mut self, cx: &mut Context, input_finished: bool) -> Option<Poll<()>> {
+        while let Some(packet) = self.pending.pop_front() {
+            match self.try_push(packet) {
+                Ok(()) => continue,
+                Err(PushError::Closed(_)) => return Some(Poll::Ready(())),
+                Err(PushError::Full(packet)) => {
+                    self.pending.push_front(packet);
+                    if self.drops_head() && !input_finished {
+                        return None
+                    }
+                    self.to_provider.park(cx.waker());
+                    if self.has_room(&self.pending[0]) || self.to_provider.is_closed() {
+                        continue
+                    }
+                    self.metrics.record_consumer_sleep();
+                    self.stalled_since = Some(Instant::now());
+                    return Some(Poll::Pending)
+                }
+            }
+        }
+        None
+    }
+
+    /// Arranges to be woken once the provider makes room, for packets left
+    /// stashed under HeadDrop when going to sleep on the input.
+    pub(crate) fn park(&mut self, cx: &mut Context) {
+        self.to_provider.park(cx.waker());
+    }
+
+    /// Publishes the packets pushed so far to the provider.
+    pub(crate) fn flush(&mut self) {
+        self.to_provider.flush();
+    }
+
+    /// Publishes the packets pushed so far, and tells the provider there
+    /// will be no more.
+    pub(crate) fn close(&mut self) {
+        self.to_provider.close();
+    }
+}
+
+/// The provider's end of the queue.
+pub(crate) struct QueueReceiver<Packet> {
+    from_consumer: RingConsumer<Queued<Packet>>,
+    metrics: LinkMetrics,
+    latency: Option<LinkLatency>,
+    /// When we went to sleep on an empty queue, if we did.
+    stalled_since: Option<Instant>,
+    overflows: Counter,
+    discard_before: Arc<AtomicUsize>,
+    bytes: Option<QueueBytes<Packet>>
+}
+
+impl<Packet> QueueReceiver<Packet> {
+    pub(crate) fn metrics(&self) -> &LinkMetrics {
+        &self.metrics
+    }
+
+    pub(crate) fn latency(&self) -> &Option<LinkLatency> {
+        &self.latency
+    }
+
+    pub(crate) fn set_bytes(&mut self, bytes: QueueBytes<Packet>) {
+        self.bytes = Some(bytes);
+    }
+
+    /// Takes the next packet off the queue with `RingConsumer::poll_pop`.
+    /// There are three cases:
+    /// ###
+    /// #1 Ready(Some(Packet)): Got a packet. Return the
+    /// Poll::Ready(Option(Packet)). The ring gives the room back to the
+    /// consumer in batches, and awakens it if it is asleep on a full queue.
+    ///
+    /// #2 Pending: Packet queue is empty, and we are parked on the ring so
+    /// the consumer awakens us with more work. We record the sleep, and
+    /// return Poll::Pending to signal to runtime to sleep this task.
+    ///
+    /// #3 Ready(None): Consumer is in teardown and has closed its end of the
+    /// ring, and we have drained every packet it left in it; we will no
+    /// longer receive packets. Return Poll::Ready(None) to forward propagate
+    /// teardown.
+    /// ###
+    /// Under HeadDrop, packets the consumer has since told us to drop are
+    /// dropped as we come to them, rather than handed on.
+    pub(crate) fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<Packet>> {
+        if let Some(since) = self.stalled_since.take() {
+            self.metrics.record_provider_stall(since);
+        }
+        loop {
+            let index = self.from_consumer.head();
+            match self.from_consumer.poll_pop(cx) {
+                Poll::Ready(Some((packet, queued_at))) => {
+                    if let Some(bytes) = &self.bytes {
+                        bytes.release(bytes.size_of(&packet));
+                    }
+                    if index < self.discard_before.load(Ordering::Acquire) {
+                        self.metrics.record_evict();
+                        self.overflows.incr();
+                        self.metrics.record_drop();
+                        continue
+                    }
+                    self.metrics.record_dequeue();
+                    if let (Some(latency), Some(queued_at)) = (&self.latency, queued_at) {
+                        latency.queueing.record(queued_at.elapsed());
+                    }
+                    return Poll::Ready(Some(packet))
+                },
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => {
+                    self.metrics.record_provider_sleep();
+                    self.stalled_since = Some(Instant::now());
+                    return Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+impl<Packet> Drop for QueueReceiver<Packet> {
+    /// Packets left in the queue will never be handed on, so we give their
+    /// bytes back to the budget. We close the ring first, which wakes the
+    /// consumer, if it is asleep on a full queue, to find us gone, so that it
+    /// pushes nothing more once we have drained. A packet it was pushing just
+    /// as we closed has its bytes given back once both ends are gone.
+    fn drop(&mut self) {
+        self.from_consumer.close();
+        if let Some(bytes) = &self.bytes {
+            while let Ok((packet, _)) = self.from_consumer.try_pop() {
+                bytes.release(bytes.size_of(&packet));
+            }
+        }
+    }
+}
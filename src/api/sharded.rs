@@ -0,0 +1,155 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use crate::api::{
+    ElementStream, AsyncElement, AsyncElementLink, AsyncElementConsumer,
+    ClassifyElement, ClassifyElementLink, ClassifyElementConsumer, ClassifyElementProvider, JoinLink
+};
+
+/// Picks a shard for each packet by hashing its flow key, so that every
+/// packet of a flow goes to the same shard. Usable on its own, as the
+/// ClassifyElement of any ClassifyElementLink.
+pub struct FlowHash<Packet> {
+    num_shards: usize,
+    key: Box<dyn Fn(&Packet) -> u64 + Send>
+}
+
+impl<Packet> FlowHash<Packet> {
+    /// `key` picks out whatever identifies a packet's flow, such as its
+    /// 5-tuple.
+    pub fn new<K: Hash + 'static>(num_shards: usize, key: fn(&Packet) -> K) -> Self
+        where Packet: 'static
+    {
+        assert!(num_shards > 0, "FlowHash needs at least one shard");
+        FlowHash {
+            num_shards,
+            key: Box::new(move |packet| {
+                let mut hasher = DefaultHasher::new();
+                key(packet).hash(&mut hasher);
+                hasher.finish()
+            })
+        }
+    }
+}
+
+impl<Packet> ClassifyElement for FlowHash<Packet> {
+    type Packet = Packet;
+
+    fn classify(&mut self, packet: &Self::Packet) -> usize {
+        ((self.key)(packet) % self.num_shards as u64) as usize
+    }
+}
+
+/// The ShardedLink runs several instances of a stateful AsyncElement, such
+/// as NAT or connection tracking, side by side. Each packet goes to the
+/// shard its flow key hashes to, so the state of a flow lives on one shard
+/// only, and the packets of a flow leave in the order they came in. Packets
+/// of different flows can overtake each other.
+///
+/// It is built out of a ClassifyElementLink with a FlowHash to dispatch the
+/// packets, one AsyncElementLink per shard, and a round-robin JoinLink to
+/// merge what the shards hand on. The dispatcher and every shard are tasks
+/// of their own, and the provider is polled by whoever is downstream.
+pub struct ShardedLink<E: AsyncElement> {
+    pub dispatcher: ClassifyElementConsumer<FlowHash<E::Input>>,
+    pub shards: Vec<AsyncElementConsumer<E, ClassifyElementProvider<FlowHash<E::Input>>>>,
+    pub provider: JoinLink<E::Output>
+}
+
+impl<E> ShardedLink<E>
+    where E: AsyncElement + 'static,
+          E::Input: Send + 'static,
+          E::Output: Send + 'static
+{
+    /// Builds one shard per element in `elements`.
+    pub fn new<K: Hash + 'static>(
+        input_stream: ElementStream<E::Input>,
+        elements: Vec<E>,
+        flow_key: fn(&E::Input) -> K,
+        queue_capacity: usize)
+    -> Self {
+        assert!(!elements.is_empty(), "ShardedLink needs at least one shard");
+        let flow_hash = FlowHash::new(elements.len(), flow_key);
+        let dispatch = ClassifyElementLink::new(input_stream, flow_hash, queue_capacity, elements.len());
+
+        let mut shards = Vec::with_capacity(elements.len());
+        let mut outputs: Vec<ElementStream<E::Output>> = Vec::with_capacity(elements.len());
+        for (element, dispatched) in elements.into_iter().zip(dispatch.providers) {
+            let link = AsyncElementLink::new(dispatched, element, queue_capacity);
+            shards.push(link.consumer);
+            outputs.push(Box::pin(link.provider));
+        }
+
+        ShardedLink {
+            dispatcher: dispatch.consumer,
+            shards,
+            provider: JoinLink::round_robin(outputs)
+        }
+    }
+
+    /// Initializes the element of every shard, as `ElementLink::initialize`
+    /// does.
+    pub fn initialize(&mut self) -> Result<(), String> {
+        for shard in self.shards.iter_mut() {
+            shard.initialize()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::Verdict;
+    use crate::utils::test::packet_generators::immediate_stream;
+    use crate::utils::test::packet_collectors::ExhaustiveCollector;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    /// Numbers the packets of every flow, as a stand-in for per-flow state.
+    /// Packets are (flow, sequence) pairs, and come out as (flow, sequence,
+    /// count of packets this shard has seen of the flow so far).
+    #[derive(Default)]
+    struct FlowCounter {
+        seen: HashMap<u8, usize>
+    }
+
+    impl AsyncElement for FlowCounter {
+        type Input = (u8, usize);
+        type Output = (u8, usize, usize);
+
+        fn process(&mut self, (flow, sequence): Self::Input) -> Verdict<Self::Output> {
+            let seen = self.seen.entry(flow).or_insert(0);
+            *seen += 1;
+            Verdict::Pass((flow, sequence, *seen))
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_flows_on_one_shard_and_in_order() {
+        let packets: Vec<(u8, usize)> = (0..200).map(|sequence| ((sequence % 13) as u8, sequence)).collect();
+        let elements = (0..4).map(|_| FlowCounter::default()).collect();
+        let link = ShardedLink::new(immediate_stream(packets), elements, |packet: &(u8, usize)| packet.0, 3);
+
+        let collected = Arc::new(Mutex::new(Vec::new()));
+        let collector = ExhaustiveCollector::new(0, Box::pin(link.provider), Arc::clone(&collected));
+        let dispatcher = tokio::spawn(link.dispatcher);
+        let shards: Vec<_> = link.shards.into_iter().map(tokio::spawn).collect();
+        let collector = tokio::spawn(collector);
+        dispatcher.await.unwrap();
+        for shard in shards {
+            shard.await.unwrap();
+        }
+        collector.await.unwrap();
+
+        let collected = collected.lock().unwrap();
+        assert_eq!(collected.len(), 200);
+        for flow in 0..13 {
+            let packets: Vec<&(u8, usize, usize)> = collected.iter().filter(|packet| packet.0 == flow).collect();
+            // A flow split over shards would restart its count on each.
+            let counts: Vec<usize> = packets.iter().map(|packet| packet.2).collect();
+            assert_eq!(counts, (1..=packets.len()).collect::<Vec<usize>>());
+            let sequences: Vec<usize> = packets.iter().map(|packet| packet.1).collect();
+            assert!(sequences.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+}
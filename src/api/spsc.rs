@@ -0,0 +1,452 @@
+use crossbeam::utils::CachePadded;
+use futures::task::AtomicWaker;
+use std::cell::UnsafeCell;
+use std::collections::VecDeque;
+use std::mem::MaybeUninit;
+use std::sync::Arc;
+use std::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+use std::task::{Context, Poll, Waker};
+
+/// The most pushes or pops either end makes before letting the other end see
+/// them, however large the ring.
+const MAX_BATCH: usize = 32;
+
+/// One end of the ring, asleep until the other end makes progress.
+struct Parked {
+    parked: AtomicBool,
+    waker: AtomicWaker
+}
+
+impl Parked {
+    fn new() -> Self {
+        Parked { parked: AtomicBool::new(false), waker: AtomicWaker::new() }
+    }
+
+    /// Registers `waker`, and raises the flag for the other end to see. The
+    /// caller must check again whether it can make progress afterwards,
+    /// since the other end may have done so just before the flag went up.
+    fn park(&self, waker: &Waker) {
+        self.waker.register(waker);
+        self.parked.store(true, Ordering::SeqCst);
+        fence(Ordering::SeqCst);
+    }
+
+    /// Wakes this end if, and only if, it is parked. This is all a push or a
+    /// pop costs in notification when nobody is waiting on it.
+    fn notify(&self) {
+        fence(Ordering::SeqCst);
+        if self.parked.load(Ordering::SeqCst) && self.parked.swap(false, Ordering::SeqCst) {
+            self.waker.wake();
+        }
+    }
+}
+
+struct Shared<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Index of the next slot to pop, as published by the consumer. Indices
+    /// only ever grow; a slot is at its index modulo the capacity.
+    head: CachePadded<AtomicUsize>,
+    /// Index of the next slot to push, as published by the producer.
+    tail: CachePadded<AtomicUsize>,
+    closed: AtomicBool,
+    producer: Parked,
+    consumer: Parked
+}
+
+// The producer only writes slots between tail and head + capacity, and the
+// consumer only reads slots between head and tail, so no slot is ever
+// touched from both ends at once.
+unsafe impl<T: Send> Send for Shared<T> {}
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        self.slots[index % self.capacity()].get()
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.producer.waker.wake();
+        self.consumer.waker.wake();
+    }
+}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let tail = *self.tail.get_mut();
+        let mut index = *self.head.get_mut();
+        while index != tail {
+            unsafe { (*self.slot(index)).assume_init_drop() };
+            index = index.wrapping_add(1);
+        }
+    }
+}
+
+/// Why a push failed. Either way, the packet is handed back.
+#[derive(Debug, PartialEq)]
+pub enum PushError<T> {
+    Full(T),
+    /// The consumer has gone away.
+    Closed(T)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PopError {
+    Empty,
+    /// The producer has gone away, and every packet it pushed has been
+    /// popped.
+    Closed
+}
+
+/// Creates a single-producer, single-consumer ring of `capacity` slots, the
+/// queue of an AsyncElementLink.
+///
+/// Neither end takes a lock. Each end keeps its own index, and only
+/// publishes it to the other end every so many operations, or when it
+/// `flush`es, so that a run of pushes or pops costs one write to shared
+/// memory rather than one each. An end that can not make progress `park`s,
+/// raising a flag, and the other end only wakes it when it finds the flag
+/// raised, so a busy link does not pay for a wakeup on every packet; only the
+/// empty to non-empty and full to non-full transitions do.
+pub fn ring<T>(capacity: usize) -> (RingProducer<T>, RingConsumer<T>) {
+    assert!(capacity > 0, "ring needs at least one slot");
+    let slots = (0..capacity).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect();
+    let shared = Arc::new(Shared {
+        slots,
+        head: CachePadded::new(AtomicUsize::new(0)),
+        tail: CachePadded::new(AtomicUsize::new(0)),
+        closed: AtomicBool::new(false),
+        producer: Parked::new(),
+        consumer: Parked::new()
+    });
+    let batch = (capacity / 4).clamp(1, MAX_BATCH);
+    (
+        RingProducer { shared: Arc::clone(&shared), tail: 0, published: 0, head: 0, batch },
+        RingConsumer { shared, head: 0, published: 0, tail: 0, batch }
+    )
+}
+
+/// The pushing end of a ring. Closes the ring when dropped.
+pub struct RingProducer<T> {
+    shared: Arc<Shared<T>>,
+    tail: usize,
+    /// The tail as the consumer sees it.
+    published: usize,
+    /// The head as we last saw it, which is never ahead of the real one.
+    head: usize,
+    batch: usize
+}
+
+impl<T> RingProducer<T> {
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity()
+    }
+
+    /// Number of packets in the ring, including those not yet published.
+    pub fn len(&self) -> usize {
+        self.tail.wrapping_sub(self.shared.head.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() == self.capacity()
+    }
+
+    /// Whether the consumer has gone away.
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::SeqCst)
+    }
+
+    /// Index the next packet pushed will have.
+    pub fn tail(&self) -> usize {
+        self.tail
+    }
+
+    pub fn try_push(&mut self, packet: T) -> Result<(), PushError<T>> {
+        if self.is_closed() {
+            return Err(PushError::Closed(packet))
+        }
+        if self.tail.wrapping_sub(self.head) == self.capacity() {
+            self.head = self.shared.head.load(Ordering::Acquire);
+            if self.tail.wrapping_sub(self.head) == self.capacity() {
+                return Err(PushError::Full(packet))
+            }
+        }
+        unsafe { (*self.shared.slot(self.tail)).write(packet) };
+        self.tail = self.tail.wrapping_add(1);
+        if self.tail.wrapping_sub(self.published) >= self.batch {
+            self.flush();
+        }
+        Ok(())
+    }
+
+    /// Pushes as many of `packets` as fit, from the front, and publishes
+    /// them all at once. Returns how many were pushed.
+    pub fn push_batch(&mut self, packets: &mut VecDeque<T>) -> Result<usize, PushError<()>> {
+        let mut pushed = 0;
+        while let Some(packet) = packets.pop_front() {
+            match self.try_push(packet) {
+                Ok(()) => pushed += 1,
+                Err(PushError::Full(packet)) => {
+                    packets.push_front(packet);
+                    break
+                },
+                Err(PushError::Closed(packet)) => {
+                    packets.push_front(packet);
+                    return Err(PushError::Closed(()))
+                }
+            }
+        }
+        self.flush();
+        Ok(pushed)
+    }
+
+    /// Publishes every packet pushed so far, waking the consumer if it is
+    /// parked on an empty ring.
+    pub fn flush(&mut self) {
+        if self.published != self.tail {
+            self.shared.tail.store(self.tail, Ordering::Release);
+            self.published = self.tail;
+            self.shared.consumer.notify();
+        }
+    }
+
+    /// Publishes what we have pushed, and arranges to be woken once the
+    /// consumer makes room. Check again for room afterwards.
+    pub fn park(&mut self, waker: &Waker) {
+        self.flush();
+        self.shared.producer.park(waker);
+    }
+
+    /// Publishes what we have pushed, and tells the consumer there will be
+    /// no more.
+    pub fn close(&mut self) {
+        self.flush();
+        self.shared.close();
+    }
+}
+
+impl<T> Drop for RingProducer<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// The popping end of a ring. Closes the ring when dropped.
+pub struct RingConsumer<T> {
+    shared: Arc<Shared<T>>,
+    head: usize,
+    /// The head as the producer sees it.
+    published: usize,
+    /// The tail as we last saw it, which is never ahead of the real one.
+    tail: usize,
+    batch: usize
+}
+
+impl<T> RingConsumer<T> {
+    pub fn capacity(&self) -> usize {
+        self.shared.capacity()
+    }
+
+    /// Number of packets published to us that we have yet to pop.
+    pub fn len(&self) -> usize {
+        self.shared.tail.load(Ordering::Acquire).wrapping_sub(self.head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the producer has gone away. There may still be packets left
+    /// to pop.
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.load(Ordering::SeqCst)
+    }
+
+    /// Index of the next packet to pop.
+    pub fn head(&self) -> usize {
+        self.head
+    }
+
+    pub fn try_pop(&mut self) -> Result<T, PopError> {
+        if self.head == self.tail {
+            self.tail = self.shared.tail.load(Ordering::Acquire);
+            if self.head == self.tail {
+                // Give back the room we made before reporting that we are
+                // out of packets, so the producer is never left waiting on
+                // a ring we have already emptied.
+                self.flush();
+                if !self.is_closed() {
+                    return Err(PopError::Empty)
+                }
+                // The producer publishes before closing, so look once more.
+                self.tail = self.shared.tail.load(Ordering::Acquire);
+                if self.head == self.tail {
+                    return Err(PopError::Closed)
+                }
+            }
+        }
+        let packet = unsafe { (*self.shared.slot(self.head)).assume_init_read() };
+        self.head = self.head.wrapping_add(1);
+        if self.head.wrapping_sub(self.published) >= self.batch {
+            self.flush();
+        }
+        Ok(packet)
+    }
+
+    /// Pops up to `max` packets onto the back of `packets`, and gives back
+    /// their room all at once. Returns how many were popped.
+    pub fn pop_batch(&mut self, packets: &mut VecDeque<T>, max: usize) -> Result<usize, PopError> {
+        let mut popped = 0;
+        while popped < max {
+            match self.try_pop() {
+                Ok(packet) => {
+                    packets.push_back(packet);
+                    popped += 1;
+                },
+                Err(PopError::Empty) => break,
+                Err(PopError::Closed) if popped == 0 => return Err(PopError::Closed),
+                Err(PopError::Closed) => break
+            }
+        }
+        self.flush();
+        Ok(popped)
+    }
+
+    /// Gives back the room of every packet popped so far, waking the
+    /// producer if it is parked on a full ring.
+    pub fn flush(&mut self) {
+        if self.published != self.head {
+            self.shared.head.store(self.head, Ordering::Release);
+            self.published = self.head;
+            self.shared.producer.notify();
+        }
+    }
+
+    /// Gives back the room we have made, and arranges to be woken once the
+    /// producer publishes more packets. Check again for packets afterwards.
+    pub fn park(&mut self, waker: &Waker) {
+        self.flush();
+        self.shared.consumer.park(waker);
+    }
+
+    /// Pops the next packet for a Stream's poll_next. There are three cases:
+    /// ###
+    /// #1 Ok(Packet): Got a packet. Return Poll::Ready(Some(Packet)).
+    ///
+    /// #2 Err(PopError::Empty): The ring is empty, so we park, to be woken
+    /// once the producer publishes more. It may have done so just before we
+    /// parked, so we look once more, and only then return Poll::Pending.
+    ///
+    /// #3 Err(PopError::Closed): The producer has gone away, and we have
+    /// popped everything it left behind. Return Poll::Ready(None).
+    /// ###
+    /// This is the only place the popping end of the wakeup protocol lives;
+    /// every provider built on a ring goes through it.
+    pub fn poll_pop(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        loop {
+            match self.try_pop() {
+                Ok(packet) => return Poll::Ready(Some(packet)),
+                Err(PopError::Closed) => return Poll::Ready(None),
+                Err(PopError::Empty) => {
+                    self.park(cx.waker());
+                    if self.is_empty() && !self.is_closed() {
+                        return Poll::Pending
+                    }
+                }
+            }
+        }
+    }
+
+    /// Gives back the room we have made, and tells the producer we will pop
+    /// no more. Whatever it already pushed can still be popped.
+    pub fn close(&mut self) {
+        self.flush();
+        self.shared.close();
+    }
+}
+
+impl<T> Drop for RingConsumer<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn hands_packets_across_threads_in_order() {
+        let (mut producer, mut consumer) = ring::<usize>(8);
+        let pushing = thread::spawn(move || {
+            let mut packets: VecDeque<usize> = (0..10_000).collect();
+            while !packets.is_empty() {
+                producer.push_batch(&mut packets).unwrap();
+                thread::yield_now();
+            }
+        });
+
+        let mut popped = VecDeque::new();
+        loop {
+            match consumer.pop_batch(&mut popped, 5) {
+                Ok(_) => thread::yield_now(),
+                Err(PopError::Closed) => break,
+                Err(PopError::Empty) => unreachable!()
+            }
+        }
+        pushing.join().unwrap();
+        assert!(popped.into_iter().eq(0..10_000));
+    }
+
+    #[test]
+    fn reports_full_and_closed() {
+        let (mut producer, mut consumer) = ring::<Vec<u8>>(2);
+        assert_eq!(producer.try_push(vec![0]), Ok(()));
+        assert_eq!(producer.try_push(vec![1]), Ok(()));
+        assert_eq!(producer.try_push(vec![2]), Err(PushError::Full(vec![2])));
+        assert_eq!(consumer.try_pop(), Ok(vec![0]));
+        assert!(consumer.len() <= 1);
+
+        // Packets pushed before the producer goes away can still be popped,
+        // and any left unpopped are dropped along with the ring.
+        assert_eq!(producer.try_push(vec![3]), Ok(()));
+        drop(producer);
+        assert_eq!(consumer.try_pop(), Ok(vec![1]));
+        assert!(consumer.is_closed());
+        drop(consumer);
+    }
+
+    #[test]
+    fn poll_pop_sleeps_until_the_producer_publishes() {
+        let (mut producer, mut consumer) = ring::<usize>(4);
+        let pushing = thread::spawn(move || {
+            for packet in 0..1000 {
+                while let Err(PushError::Full(_)) = producer.try_push(packet) {
+                    thread::yield_now();
+                }
+                // Publish straight away, and now and then let the consumer
+                // run dry and go to sleep, so that it has to be woken.
+                producer.flush();
+                if packet % 100 == 0 {
+                    thread::sleep(std::time::Duration::from_millis(1));
+                }
+            }
+        });
+
+        // block_on really sleeps, so a lost wakeup would hang here.
+        let popped: Vec<usize> = futures::executor::block_on(
+            futures::StreamExt::collect(futures::stream::poll_fn(|cx| consumer.poll_pop(cx)))
+        );
+        pushing.join().unwrap();
+        assert!(popped.into_iter().eq(0..1000));
+    }
+}
@@ -0,0 +1,22 @@
+use std::any::Any;
+use std::sync::{Arc, Mutex};
+
+/// Elements that keep state worth carrying over when their part of the
+/// router is reconfigured, such as counters or learned addresses, implement
+/// TransferState. When a graph is swapped for a new one, each element that
+/// opts in is handed the state saved from the element of the same name in
+/// the old graph.
+///
+/// The state is saved while the old element is still running, so anything
+/// it does to its state after that is not carried over. `restore_state` is
+/// handed whatever the old element saved, which may come from a different
+/// version of the element, so it should check the type it gets back, and
+/// return an Err explaining the problem if it can not use it.
+pub trait TransferState {
+    fn save_state(&self) -> Box<dyn Any + Send>;
+
+    fn restore_state(&mut self, state: Box<dyn Any + Send>) -> Result<(), String>;
+}
+
+/// The transferable state of an element, as registered with the router.
+pub type SharedState = Arc<Mutex<dyn TransferState + Send>>;
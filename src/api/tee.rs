@@ -0,0 +1,360 @@
+use futures::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::sync::Arc;
+use std::time::Instant;
+use crate::api::{ElementStream, Counter, LinkMetrics, ring, RingProducer, RingConsumer, PushError};
+
+/// What the TeeLink does when one branch's queue is full while the others
+/// still have room.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TeePolicy {
+    /// Wait until every branch has room, so every branch sees every packet.
+    /// The slowest branch sets the pace for all of them.
+    BlockAll,
+    /// Deliver to the branches that have room and drop the packet for the
+    /// ones that don't. We still wait if no branch has room at all.
+    DropSlow
+}
+
+/// The TeeLink duplicates its input onto several branches, say a main path
+/// and a mirror port. Packets are wrapped in an `Arc` once, and every branch
+/// gets a clone of that `Arc`, so duplicating a packet never copies it. A
+/// branch that needs to modify its packet can use `Arc::make_mut`, which only
+/// copies when some other branch still holds a reference.
+pub struct TeeLink<Packet> {
+    pub consumer: TeeConsumer<Packet>,
+    pub providers: Vec<TeeProvider<Packet>>
+}
+
+impl<Packet> TeeLink<Packet> {
+    pub fn new(input_stream: ElementStream<Packet>, queue_capacity: usize, num_branches: usize, policy: TeePolicy) -> Self {
+        assert!(num_branches > 0, "TeeLink needs at least one branch");
+
+        let mut to_providers = Vec::with_capacity(num_branches);
+        let mut providers = Vec::with_capacity(num_branches);
+        let mut metrics = Vec::with_capacity(num_branches);
+
+        for _ in 0..num_branches {
+            let (to_provider, from_consumer) = ring::<Arc<Packet>>(queue_capacity);
+            let branch_metrics = LinkMetrics::queued(queue_capacity);
+
+            to_providers.push(Some(to_provider));
+            providers.push(TeeProvider::new(from_consumer, branch_metrics.clone()));
+            metrics.push(branch_metrics);
+        }
+
+        TeeLink {
+            consumer: TeeConsumer::new(input_stream, to_providers, policy, metrics),
+            providers
+        }
+    }
+}
+
+/// The TeeConsumer pulls packets off its input stream and offers each one to
+/// every branch. A packet that some branch could not take yet is held back,
+/// along with the branches still waiting for it, until there is room.
+pub struct TeeConsumer<Packet> {
+    input_stream: ElementStream<Packet>,
+    to_providers: Vec<Option<RingProducer<Arc<Packet>>>>,
+    policy: TeePolicy,
+    pending: Option<(Arc<Packet>, Vec<usize>)>,
+    metrics: Vec<LinkMetrics>,
+    /// When we went to sleep on the full queues of the pending branches.
+    stalled_since: Option<Instant>
+}
+
+impl<Packet> TeeConsumer<Packet> {
+    fn new(
+        input_stream: ElementStream<Packet>,
+        to_providers: Vec<Option<RingProducer<Arc<Packet>>>>,
+        policy: TeePolicy,
+        metrics: Vec<LinkMetrics>)
+    -> Self {
+        TeeConsumer {
+            input_stream,
+            to_providers,
+            policy,
+            pending: None,
+            metrics,
+            stalled_since: None
+        }
+    }
+
+    /// Number of packets the given branch missed because its queue was full.
+    /// Always zero under `TeePolicy::BlockAll`.
+    pub fn drop_counter(&self, branch: usize) -> Counter {
+        self.metrics[branch].drop_counter()
+    }
+
+    /// The metrics of the queue for `branch`, shared with its provider.
+    pub fn metrics(&self, branch: usize) -> LinkMetrics {
+        self.metrics[branch].clone()
+    }
+
+    fn open_branches(&self) -> Vec<usize> {
+        (0..self.to_providers.len()).filter(|branch| self.to_providers[*branch].is_some()).collect()
+    }
+
+    /// Offers `packet` to each of `branches`, and returns the ones whose
+    /// queue was full. A branch whose provider has gone away is closed and
+    /// not offered anything again.
+    fn offer(&mut self, packet: &Arc<Packet>, branches: Vec<usize>) -> Vec<usize> {
+        let mut full = vec![];
+        for branch in branches {
+            let to_provider = match &mut self.to_providers[branch] {
+                Some(to_provider) if !to_provider.is_closed() => to_provider,
+                Some(_) => {
+                    self.to_providers[branch] = None;
+                    continue
+                },
+                None => continue
+            };
+            if to_provider.is_full() {
+                full.push(branch);
+                continue
+            }
+            // The provider may take the packet off the queue as soon as it
+            // is pushed, so it is counted on beforehand.
+            self.metrics[branch].record_enqueue();
+            match to_provider.try_push(Arc::clone(packet)) {
+                Ok(()) => {},
+                Err(PushError::Full(_)) | Err(PushError::Closed(_)) => {
+                    self.metrics[branch].record_enqueue_failed();
+                    self.to_providers[branch] = None;
+                }
+            }
+        }
+        full
+    }
+
+    /// Parks on the ring of every branch in `branches`, so that whichever
+    /// makes room first awakens us. Returns whether any of them has room, or
+    /// has gone away, by now, in which case there is no need to sleep.
+    fn await_branches(&mut self, branches: &[usize], cx: &mut Context) -> bool {
+        let mut progress = false;
+        for branch in branches {
+            match &mut self.to_providers[*branch] {
+                Some(to_provider) => {
+                    to_provider.park(cx.waker());
+                    progress |= !to_provider.is_full() || to_provider.is_closed();
+                },
+                None => progress = true
+            }
+        }
+        progress
+    }
+
+    fn poll_work(&mut self, cx: &mut Context) -> Poll<()> {
+        loop {
+            let (packet, branches) = match self.pending.take() {
+                Some(pending) => pending,
+                None => {
+                    if self.open_branches().is_empty() {
+                        return Poll::Ready(())
+                    }
+                    match self.input_stream.as_mut().poll_next(cx) {
+                        Poll::Pending => return Poll::Pending,
+                        Poll::Ready(None) => return Poll::Ready(()),
+                        Poll::Ready(Some(packet)) => (Arc::new(packet), self.open_branches())
+                    }
+                }
+            };
+
+            let num_offered = branches.len();
+            let full = self.offer(&packet, branches);
+            if full.is_empty() {
+                continue;
+            }
+
+            if self.policy == TeePolicy::DropSlow && full.len() < num_offered {
+                for branch in full {
+                    self.metrics[branch].record_drop();
+                }
+                continue;
+            }
+
+            if self.await_branches(&full, cx) {
+                self.pending = Some((packet, full));
+                continue;
+            }
+            for branch in &full {
+                self.metrics[*branch].record_consumer_sleep();
+            }
+            self.stalled_since = Some(Instant::now());
+            self.pending = Some((packet, full));
+            return Poll::Pending
+        }
+    }
+}
+
+impl<Packet> Drop for TeeConsumer<Packet> {
+    /// Dropping our end of every branch's ring closes it. Each provider
+    /// drains what is left in its queue and then sees the ring closed.
+    fn drop(&mut self) {
+        self.to_providers.clear();
+    }
+}
+
+impl<Packet> Unpin for TeeConsumer<Packet> {}
+
+impl<Packet> Future for TeeConsumer<Packet> {
+    type Output = ();
+
+    /// Implement Poll for Future for TeeConsumer
+    ///
+    /// We first retry the packet we held back on an earlier poll, if any, and
+    /// only pull a new packet once every branch that is owed it has it. What
+    /// happens when a branch's queue is full depends on the policy:
+    /// ###
+    /// #1 BlockAll: the packet is held back for the full branches, and we
+    /// park on their rings and sleep until one of them makes room, unless
+    /// one already has by the time we are parked.
+    ///
+    /// #2 DropSlow: the packet is dropped for the full branches. Only when no
+    /// branch at all could take the packet do we hold it back and sleep.
+    /// ###
+    /// Once every branch's provider has gone away, or the input stream returns
+    /// Ready(None), we return Ready(()) and enter tear-down. Whichever way we
+    /// return, we first publish what we pushed on every branch.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let consumer = self.get_mut();
+        if let (Some(since), Some((_, branches))) = (consumer.stalled_since.take(), &consumer.pending) {
+            for branch in branches {
+                consumer.metrics[*branch].record_consumer_stall(since);
+            }
+        }
+        let poll = consumer.poll_work(cx);
+        for to_provider in consumer.to_providers.iter_mut().flatten() {
+            to_provider.flush();
+        }
+        poll
+    }
+}
+
+/// The TeeProvider is the stream for a single branch of a TeeLink. It hands
+/// out shared references to the packets the consumer put on its queue.
+pub struct TeeProvider<Packet> {
+    from_consumer: RingConsumer<Arc<Packet>>,
+    metrics: LinkMetrics,
+    stalled_since: Option<Instant>
+}
+
+impl<Packet> TeeProvider<Packet> {
+    fn new(from_consumer: RingConsumer<Arc<Packet>>, metrics: LinkMetrics) -> Self {
+        TeeProvider {
+            from_consumer,
+            metrics,
+            stalled_since: None
+        }
+    }
+
+    /// The metrics of this branch's queue, shared with the consumer.
+    pub fn metrics(&self) -> LinkMetrics {
+        self.metrics.clone()
+    }
+}
+
+impl<Packet> Unpin for TeeProvider<Packet> {}
+
+impl<Packet> Stream for TeeProvider<Packet> {
+    type Item = Arc<Packet>;
+
+    /// Implement Poll for Stream for TeeProvider
+    ///
+    /// Same as the AsyncElementProvider: hand out a packet, forward tear-down
+    /// once the consumer has closed its end of the ring and we have drained
+    /// it, or park on the ring and sleep until the consumer has more work for
+    /// us, all by way of `RingConsumer::poll_pop`.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let provider = self.get_mut();
+        if let Some(since) = provider.stalled_since.take() {
+            provider.metrics.record_provider_stall(since);
+        }
+        match provider.from_consumer.poll_pop(cx) {
+            Poll::Ready(Some(packet)) => {
+                provider.metrics.record_dequeue();
+                Poll::Ready(Some(packet))
+            },
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => {
+                provider.metrics.record_provider_sleep();
+                provider.stalled_since = Some(Instant::now());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test::packet_generators::{immediate_stream, LinearIntervalGenerator};
+    use crate::utils::test::packet_collectors::ExhaustiveCollector;
+    use core::time;
+    use std::sync::Mutex;
+
+    use futures::StreamExt;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn block_all_delivers_shared_packets_to_every_branch() {
+        let packet_generator = LinearIntervalGenerator::new(time::Duration::from_millis(10), 10);
+
+        let mut tee_link = TeeLink::new(Box::pin(packet_generator), 2, 2, TeePolicy::BlockAll);
+
+        let mirror_provider = tee_link.providers.pop().unwrap();
+        let main_provider = tee_link.providers.pop().unwrap();
+        let tee_drain = tee_link.consumer;
+
+        let main_packets = Arc::new(Mutex::new(Vec::new()));
+        let mirror_packets = Arc::new(Mutex::new(Vec::new()));
+        let main_collector = ExhaustiveCollector::new(0, Box::pin(main_provider), Arc::clone(&main_packets));
+        let mirror_collector = ExhaustiveCollector::new(1, Box::pin(mirror_provider), Arc::clone(&mirror_packets));
+
+        let tee_drain = tokio::spawn(tee_drain);
+        let main_collector = tokio::spawn(main_collector);
+        let mirror_collector = tokio::spawn(mirror_collector);
+        tee_drain.await.unwrap();
+        main_collector.await.unwrap();
+        mirror_collector.await.unwrap();
+
+        let main_packets = main_packets.lock().unwrap();
+        let mirror_packets = mirror_packets.lock().unwrap();
+        assert_eq!(main_packets.iter().map(|p| **p).collect::<Vec<i32>>(), (0..=10).collect::<Vec<i32>>());
+        assert_eq!(main_packets.len(), mirror_packets.len());
+        for (main_packet, mirror_packet) in main_packets.iter().zip(mirror_packets.iter()) {
+            assert!(Arc::ptr_eq(main_packet, mirror_packet));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drop_slow_only_drops_for_slow_branch() {
+        let packet_generator = immediate_stream(0..20);
+
+        let mut tee_link = TeeLink::new(Box::pin(packet_generator), 4, 2, TeePolicy::DropSlow);
+
+        let mirror_provider = tee_link.providers.pop().unwrap();
+        let main_provider = tee_link.providers.pop().unwrap();
+        let mirror_drops = tee_link.consumer.drop_counter(1);
+        let main_drops = tee_link.consumer.drop_counter(0);
+        let tee_drain = tee_link.consumer;
+
+        let main_packets = Arc::new(Mutex::new(Vec::new()));
+        let main_collector = ExhaustiveCollector::new(0, Box::pin(main_provider), Arc::clone(&main_packets));
+
+        // The mirror is never polled while the tee runs, so its queue fills up
+        // and stays full.
+        let tee_drain = tokio::spawn(tee_drain);
+        let main_collector = tokio::spawn(main_collector);
+        tee_drain.await.unwrap();
+        main_collector.await.unwrap();
+
+        let mirror_packets = mirror_provider.collect::<Vec<Arc<i32>>>().await;
+
+        assert_eq!(main_packets.lock().unwrap().iter().map(|p| **p).collect::<Vec<i32>>(), (0..20).collect::<Vec<i32>>());
+        assert_eq!(main_drops.get(), 0);
+        assert_eq!(mirror_packets.iter().map(|p| **p).collect::<Vec<i32>>(), vec![0, 1, 2, 3]);
+        assert_eq!(mirror_drops.get(), 16);
+    }
+}
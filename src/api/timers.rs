@@ -0,0 +1,219 @@
+use futures::task::AtomicWaker;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Sleep, sleep_until};
+
+struct Timer {
+    id: usize,
+    deadline: Instant,
+    /// How often the timer repeats, if it does.
+    period: Option<Duration>
+}
+
+#[derive(Default)]
+struct Schedule {
+    timers: Vec<Timer>,
+    /// Sleeps until the earliest deadline, once the link has polled us.
+    sleep: Option<Pin<Box<Sleep>>>
+}
+
+#[derive(Default)]
+struct Shared {
+    schedule: Mutex<Schedule>,
+    /// Whether any timer is scheduled, so that links whose element has none
+    /// can skip the lock on every poll.
+    armed: AtomicBool,
+    /// The task of the link, to wake when a timer is scheduled from
+    /// elsewhere, say from a handler.
+    waker: AtomicWaker
+}
+
+/// Timers let an element get work done without a packet arriving, such as
+/// expiring an ARP cache or rolling over statistics. The link hands its
+/// element a Timers in `initialize`, which the element keeps, and uses to
+/// schedule timers under ids of its choosing. When a timer fires, the link
+/// calls the element's `run_timer` with its id, on the link's own task.
+///
+/// Cloning a Timers is cheap, and every clone schedules onto the same link.
+/// Timers only fire while the link is being polled, so an ElementLink's
+/// timers run on the task of whoever pulls from it.
+#[derive(Clone, Default)]
+pub struct Timers(Arc<Shared>);
+
+impl Timers {
+    pub fn new() -> Self {
+        Timers::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Schedule> {
+        self.0.schedule.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Fires timer `id` once, after `delay`. Rescheduling a timer replaces
+    /// whatever it was scheduled for before.
+    pub fn schedule_after(&self, id: usize, delay: Duration) {
+        self.schedule(Timer { id, deadline: Instant::now() + delay, period: None });
+    }
+
+    /// Fires timer `id` every `period`, starting one `period` from now.
+    pub fn schedule_every(&self, id: usize, period: Duration) {
+        self.schedule(Timer { id, deadline: Instant::now() + period, period: Some(period) });
+    }
+
+    fn schedule(&self, timer: Timer) {
+        let mut schedule = self.lock();
+        schedule.timers.retain(|scheduled| scheduled.id != timer.id);
+        schedule.timers.push(timer);
+        self.0.armed.store(true, Ordering::SeqCst);
+        drop(schedule);
+        self.0.waker.wake();
+    }
+
+    pub fn cancel(&self, id: usize) {
+        let mut schedule = self.lock();
+        schedule.timers.retain(|timer| timer.id != id);
+        self.0.armed.store(!schedule.timers.is_empty(), Ordering::SeqCst);
+    }
+
+    pub fn is_scheduled(&self, id: usize) -> bool {
+        self.lock().timers.iter().any(|timer| timer.id == id)
+    }
+
+    /// Returns the id of a timer that is due, if any, rescheduling it if it
+    /// repeats. Otherwise, arranges for the task to be woken once the next
+    /// timer is due, or one is scheduled. A repeating timer that fell more
+    /// than a period behind fires once, rather than once per missed period.
+    ///
+    /// This is called on every poll of the link, so when no timer is
+    /// scheduled, all it costs is registering the waker, which is only
+    /// cloned if it changed, and reading a flag.
+    pub(crate) fn poll_expired(&self, cx: &mut Context) -> Poll<usize> {
+        self.0.waker.register(cx.waker());
+        if !self.0.armed.load(Ordering::SeqCst) {
+            return Poll::Pending
+        }
+        let mut schedule = self.lock();
+        let schedule = &mut *schedule;
+        loop {
+            let now = Instant::now();
+            let next = schedule.timers.iter().enumerate()
+                .min_by_key(|(_, timer)| timer.deadline)
+                .map(|(index, timer)| (index, timer.deadline));
+
+            match next {
+                None => return Poll::Pending,
+                Some((index, deadline)) if deadline <= now => {
+                    let timer = &mut schedule.timers[index];
+                    let id = timer.id;
+                    match timer.period {
+                        Some(period) => {
+                            let next = deadline + period;
+                            timer.deadline = if next > now { next } else { now + period };
+                        },
+                        None => {
+                            schedule.timers.swap_remove(index);
+                            self.0.armed.store(!schedule.timers.is_empty(), Ordering::SeqCst);
+                        }
+                    }
+                    return Poll::Ready(id)
+                },
+                Some((_, deadline)) => {
+                    let sleep = schedule.sleep.get_or_insert_with(|| Box::pin(sleep_until(deadline)));
+                    if sleep.deadline() != deadline {
+                        sleep.as_mut().reset(deadline);
+                    }
+                    if sleep.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{AsyncElement, AsyncElementLink, Verdict};
+    use crate::utils::test::packet_generators::LinearIntervalGenerator;
+    use crate::utils::test::packet_collectors::ExhaustiveCollector;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+    const TICK: usize = 0;
+    const ONCE: usize = 1;
+
+    /// Passes packets through, counts ticks of a repeating timer, and emits
+    /// a -1 when a one-shot timer fires.
+    struct Ticker {
+        ticks: Arc<AtomicU64>,
+        cleaned_up: Arc<AtomicBool>
+    }
+
+    impl AsyncElement for Ticker {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            Verdict::Pass(packet)
+        }
+
+        fn initialize(&mut self, timers: &Timers) -> Result<(), String> {
+            timers.schedule_every(TICK, Duration::from_millis(5));
+            timers.schedule_after(ONCE, Duration::from_millis(15));
+            Ok(())
+        }
+
+        fn cleanup(&mut self) {
+            self.cleaned_up.store(true, Ordering::SeqCst);
+        }
+
+        fn run_timer(&mut self, timer: usize) -> Verdict<Self::Output> {
+            match timer {
+                TICK => {
+                    self.ticks.fetch_add(1, Ordering::SeqCst);
+                    Verdict::Drop
+                },
+                _ => Verdict::Pass(-1)
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_timers_between_packets() {
+        let ticks = Arc::new(AtomicU64::new(0));
+        let cleaned_up = Arc::new(AtomicBool::new(false));
+        let ticker = Ticker { ticks: Arc::clone(&ticks), cleaned_up: Arc::clone(&cleaned_up) };
+        let packet_generator = LinearIntervalGenerator::new(Duration::from_millis(20), 2);
+        let link = AsyncElementLink::new(Box::pin(packet_generator), ticker, 10);
+
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        let collector = ExhaustiveCollector::new(0, Box::pin(link.provider), Arc::clone(&packets));
+        let consumer = tokio::spawn(link.consumer);
+        let collector = tokio::spawn(collector);
+        consumer.await.unwrap();
+        collector.await.unwrap();
+
+        // The generator takes 60ms to run dry, so the 5ms timer fires about a
+        // dozen times, give or take a slow machine.
+        assert!(ticks.load(Ordering::SeqCst) >= 5);
+        let packets = packets.lock().unwrap();
+        assert_eq!(packets.iter().filter(|packet| **packet == -1).count(), 1);
+        assert_eq!(packets.iter().filter(|packet| **packet >= 0).copied().collect::<Vec<i32>>(), vec![0, 1, 2]);
+        assert!(cleaned_up.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn reschedules_and_cancels() {
+        let timers = Timers::new();
+        timers.schedule_after(ONCE, Duration::from_secs(60));
+        timers.schedule_every(ONCE, Duration::from_secs(1));
+        assert_eq!(timers.lock().timers.len(), 1);
+        assert!(timers.is_scheduled(ONCE));
+        timers.cancel(ONCE);
+        assert!(!timers.is_scheduled(ONCE));
+    }
+}
@@ -0,0 +1,348 @@
+use futures::Stream;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use crate::api::{
+    ElementStream, AsyncElement, AsyncElementLink, AsyncElementConsumer, AsyncElementProvider,
+    ClassifyElement, ClassifyElementLink, ClassifyElementConsumer, ClassifyElementProvider, Counter, Timers, Verdict
+};
+
+/// The WorkerPoolLink spreads a CPU-heavy AsyncElement, such as a cipher or
+/// deep packet inspection, over several worker tasks, so that one stage can
+/// make use of several cores. Every worker has its own instance of the
+/// element. Elements that need to share state, say a flow table, can keep it
+/// behind an `Arc` and be cloned with `replicate`.
+///
+/// The dispatcher deals packets out to the workers in turn, through a bounded
+/// queue per worker, and every worker pushes what it made of each packet onto
+/// a bounded queue of its own. By default, the provider restores the original
+/// order by taking from the workers in the same turn the dispatcher dealt to
+/// them, so the worker queues act as the reorder buffer: a worker that is
+/// done early waits there for a slower one. `unordered` hands packets on from
+/// whichever worker has one instead.
+///
+/// Packets emitted by timers belong to no turn, and are handed on once the
+/// provider gets to them.
+pub struct WorkerPoolLink<E: AsyncElement> {
+    pub dispatcher: ClassifyElementConsumer<Deal<E::Input>>,
+    pub workers: Vec<WorkerConsumer<E>>,
+    pub provider: WorkerPoolProvider<E>,
+    drops: Counter
+}
+
+/// A worker's task, taking the packets dealt to it straight from the
+/// dispatcher.
+pub type WorkerConsumer<E> = AsyncElementConsumer<Worker<E>, ClassifyElementProvider<Deal<<E as AsyncElement>::Input>>>;
+
+impl<E: AsyncElement> WorkerPoolLink<E> {
+    /// Builds one worker per element in `elements`.
+    pub fn new(input_stream: ElementStream<E::Input>, elements: Vec<E>, queue_capacity: usize) -> Self
+        where E::Input: Send + 'static
+    {
+        assert!(!elements.is_empty(), "WorkerPoolLink needs at least one worker");
+        let num_workers = elements.len();
+        let deal = Deal { num_workers, next: 0, packet: PhantomData };
+        let dispatch = ClassifyElementLink::new(input_stream, deal, queue_capacity, num_workers);
+        let drops = Counter::new();
+
+        let mut workers = Vec::with_capacity(num_workers);
+        let mut providers = Vec::with_capacity(num_workers);
+        for (element, dealt) in elements.into_iter().zip(dispatch.providers) {
+            let worker = Worker { element, drops: drops.clone() };
+            let link = AsyncElementLink::new(dealt, worker, queue_capacity);
+            workers.push(link.consumer);
+            providers.push(Some(link.provider));
+        }
+
+        WorkerPoolLink {
+            dispatcher: dispatch.consumer,
+            workers,
+            provider: WorkerPoolProvider {
+                providers,
+                ordered: true,
+                next: 0,
+                pending: VecDeque::new()
+            },
+            drops
+        }
+    }
+
+    /// Builds `num_workers` workers, each with a clone of `element`.
+    pub fn replicate(input_stream: ElementStream<E::Input>, element: E, num_workers: usize, queue_capacity: usize) -> Self
+        where E: Clone,
+              E::Input: Send + 'static
+    {
+        WorkerPoolLink::new(input_stream, vec![element; num_workers], queue_capacity)
+    }
+
+    /// Hands on each packet as soon as any worker is done with it.
+    pub fn unordered(mut self) -> Self {
+        self.provider.ordered = false;
+        self
+    }
+
+    /// Initializes the element of every worker, as `ElementLink::initialize`
+    /// does.
+    pub fn initialize(&mut self) -> Result<(), String> {
+        for worker in self.workers.iter_mut() {
+            worker.initialize()?;
+        }
+        Ok(())
+    }
+
+    /// Number of input packets the workers' elements have dropped so far.
+    pub fn drop_counter(&self) -> Counter {
+        self.drops.clone()
+    }
+}
+
+/// Deals packets out to the workers of a WorkerPoolLink in turn.
+pub struct Deal<Packet> {
+    num_workers: usize,
+    next: usize,
+    packet: PhantomData<fn(Packet)>
+}
+
+impl<Packet> ClassifyElement for Deal<Packet> {
+    type Packet = Packet;
+
+    fn classify(&mut self, _packet: &Self::Packet) -> usize {
+        let worker = self.next;
+        self.next = (self.next + 1) % self.num_workers;
+        worker
+    }
+}
+
+/// What a worker made of one packet, or emitted from a timer, carried over
+/// as the element's own Verdict so that passing a single packet along does
+/// not cost an allocation.
+pub enum Batch<Packet> {
+    Processed(Verdict<Packet>),
+    Fired(Verdict<Packet>)
+}
+
+/// Runs a worker's element, handing on exactly one Batch per packet, even
+/// when the element drops it, so that the provider can keep count of turns.
+pub struct Worker<E: AsyncElement> {
+    element: E,
+    drops: Counter
+}
+
+impl<E: AsyncElement> AsyncElement for Worker<E> {
+    type Input = E::Input;
+    type Output = Batch<E::Output>;
+
+    fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+        let verdict = self.element.process(packet);
+        match &verdict {
+            Verdict::Drop => self.drops.incr(),
+            Verdict::Many(packets) if packets.is_empty() => self.drops.incr(),
+            _ => {}
+        }
+        Verdict::Pass(Batch::Processed(verdict))
+    }
+
+    fn initialize(&mut self, timers: &Timers) -> Result<(), String> {
+        self.element.initialize(timers)
+    }
+
+    fn cleanup(&mut self) {
+        self.element.cleanup()
+    }
+
+    fn run_timer(&mut self, timer: usize) -> Verdict<Self::Output> {
+        match self.element.run_timer(timer) {
+            Verdict::Drop => Verdict::Drop,
+            verdict => Verdict::Pass(Batch::Fired(verdict))
+        }
+    }
+}
+
+/// The WorkerPoolProvider merges what the workers made of their packets
+/// back into one stream.
+pub struct WorkerPoolProvider<E: AsyncElement> {
+    /// The provider of every worker, or None once it has run dry.
+    providers: Vec<Option<AsyncElementProvider<Worker<E>>>>,
+    ordered: bool,
+    /// The worker whose turn it is.
+    next: usize,
+    /// Packets of a batch that have yet to be handed on.
+    pending: VecDeque<E::Output>
+}
+
+impl<E: AsyncElement> WorkerPoolProvider<E> {
+    /// Keeps the packets of a batch aside, to be handed on in turn.
+    fn stash(&mut self, verdict: Verdict<E::Output>) {
+        match verdict {
+            Verdict::Pass(packet) => self.pending.push_back(packet),
+            Verdict::Drop => {},
+            Verdict::Many(packets) => self.pending.extend(packets)
+        }
+    }
+
+    /// Polls the worker at `index`, keeping whatever packets it hands over
+    /// aside. Returns whether that batch took up the worker's turn, or None
+    /// if the worker had nothing for us.
+    fn poll_worker(&mut self, index: usize, cx: &mut Context) -> Option<bool> {
+        let provider = self.providers[index].as_mut()?;
+        match Pin::new(provider).poll_next(cx) {
+            Poll::Ready(Some(Batch::Processed(verdict))) => {
+                self.stash(verdict);
+                Some(true)
+            },
+            Poll::Ready(Some(Batch::Fired(verdict))) => {
+                self.stash(verdict);
+                Some(false)
+            },
+            Poll::Ready(None) => {
+                self.providers[index] = None;
+                None
+            },
+            Poll::Pending => None
+        }
+    }
+}
+
+impl<E: AsyncElement> Unpin for WorkerPoolProvider<E> {}
+
+impl<E: AsyncElement> Stream for WorkerPoolProvider<E> {
+    type Item = E::Output;
+
+    /// Implement Poll for Stream for WorkerPoolProvider
+    ///
+    /// Packets left over from the last batch go first. Otherwise, when order
+    /// is preserved, we only poll the worker whose turn it is. There are
+    /// three cases:
+    /// ###
+    /// #1 The worker hands over a batch, which takes up its turn unless a
+    /// timer emitted it. We hand on its first packet, or go around again if
+    /// the element dropped the packet.
+    ///
+    /// #2 The worker returns a Pending, we sleep until it wakes us, however
+    /// far ahead the other workers are.
+    ///
+    /// #3 The worker has run dry. The dispatcher deals in turn, so no later
+    /// packet can exist, and we return Ready(None).
+    /// ###
+    /// When order is relaxed, we poll the workers in turn, like a JoinLink,
+    /// until one hands over a packet, and only return Ready(None) once they
+    /// have all run dry.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let provider = self.get_mut();
+        let num_workers = provider.providers.len();
+        loop {
+            if let Some(packet) = provider.pending.pop_front() {
+                return Poll::Ready(Some(packet))
+            }
+
+            if provider.ordered {
+                let index = provider.next;
+                match provider.poll_worker(index, cx) {
+                    Some(took_turn) => {
+                        if took_turn {
+                            provider.next = (index + 1) % num_workers;
+                        }
+                    },
+                    None if provider.providers[index].is_none() => return Poll::Ready(None),
+                    None => return Poll::Pending
+                }
+            } else {
+                let mut progress = false;
+                for offset in 0..num_workers {
+                    let index = (provider.next + offset) % num_workers;
+                    if provider.poll_worker(index, cx).is_some() {
+                        provider.next = (index + 1) % num_workers;
+                        progress = true;
+                        break
+                    }
+                }
+                if !progress {
+                    if provider.providers.iter().all(Option::is_none) {
+                        return Poll::Ready(None)
+                    }
+                    return Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::test::packet_generators::immediate_stream;
+    use crate::utils::test::packet_collectors::ExhaustiveCollector;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Busies its thread for a while on every packet, dropping multiples of
+    /// seven, and keeps track of how many packets are being worked on at once.
+    #[derive(Clone)]
+    struct Digest {
+        busy: Arc<AtomicUsize>,
+        most_busy: Arc<AtomicUsize>
+    }
+
+    impl AsyncElement for Digest {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            let busy = self.busy.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_busy.fetch_max(busy, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(1 + (packet % 3) as u64));
+            self.busy.fetch_sub(1, Ordering::SeqCst);
+            if packet % 7 == 0 {
+                Verdict::Drop
+            } else {
+                Verdict::Pass(packet)
+            }
+        }
+    }
+
+    async fn run(link: WorkerPoolLink<Digest>) -> Vec<i32> {
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        let collector = ExhaustiveCollector::new(0, Box::pin(link.provider), Arc::clone(&packets));
+        let dispatcher = tokio::spawn(link.dispatcher);
+        let workers: Vec<_> = link.workers.into_iter().map(tokio::spawn).collect();
+        let collector = tokio::spawn(collector);
+        dispatcher.await.unwrap();
+        for worker in workers {
+            worker.await.unwrap();
+        }
+        collector.await.unwrap();
+        let packets = packets.lock().unwrap().clone();
+        packets
+    }
+
+    fn digest() -> (Digest, Arc<AtomicUsize>) {
+        let most_busy = Arc::new(AtomicUsize::new(0));
+        (Digest { busy: Arc::new(AtomicUsize::new(0)), most_busy: Arc::clone(&most_busy) }, most_busy)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn restores_order_across_workers() {
+        let (element, most_busy) = digest();
+        let link = WorkerPoolLink::replicate(immediate_stream(0..100), element, 4, 5);
+        let drops = link.drop_counter();
+
+        let expected: Vec<i32> = (0..100).filter(|packet| packet % 7 != 0).collect();
+        assert_eq!(run(link).await, expected);
+        assert_eq!(drops.get(), 15);
+        assert!(most_busy.load(Ordering::SeqCst) > 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn hands_on_every_packet_when_unordered() {
+        // Which order the packets come out in is up to the scheduler, so we
+        // only check that none went missing.
+        let (element, _) = digest();
+        let link = WorkerPoolLink::replicate(immediate_stream(0..100), element, 4, 5).unordered();
+
+        let mut packets = run(link).await;
+        packets.sort();
+        assert_eq!(packets, (0..100).filter(|packet| packet % 7 != 0).collect::<Vec<i32>>());
+    }
+}
@@ -0,0 +1,40 @@
+//! Sends a single command to a running router's control socket, and prints
+//! the answer:
+//!
+//!   routectl /run/router.sock READ counter.count
+//!   routectl /run/router.sock DOT | dot -Tsvg > router.svg
+
+extern crate route_rs;
+
+use std::env;
+use std::process;
+use route_rs::router::ControlClient;
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    if args.len() < 2 {
+        eprintln!("usage: routectl SOCKET COMMAND [ARGS...]");
+        eprintln!("commands: LIST, HANDLERS, READ element.handler, WRITE element.handler [value], METRICS, DOT, SHUTDOWN");
+        process::exit(2);
+    }
+
+    let mut client = match ControlClient::connect(&args[0]) {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("routectl: could not connect to {}: {}", args[0], err);
+            process::exit(1);
+        }
+    };
+
+    match client.request(&args[1..].join(" ")) {
+        Ok(Ok(output)) => print!("{}", output),
+        Ok(Err(message)) => {
+            eprintln!("routectl: {}", message);
+            process::exit(1);
+        },
+        Err(err) => {
+            eprintln!("routectl: {}", err);
+            process::exit(1);
+        }
+    }
+}
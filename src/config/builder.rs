@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use crate::config::{Config, ConfigError, ErrorKind, Position};
+use crate::router::{AnyStream, DynElement, NodeId, Registry, RouterGraph};
+
+/// An element of the configuration along with everything we have worked
+/// out about it on the way to building it.
+struct Pending {
+    element: Option<Box<dyn DynElement>>,
+    /// Index into `Config::connections` of the connection feeding each input.
+    inputs: Vec<usize>,
+    num_outputs: usize,
+    node: Option<NodeId>
+}
+
+/// Finds the connection feeding each port, checking that every port
+/// exists and is used at most once. `output` selects which side of the
+/// connections to look at.
+fn connected_ports(config: &Config, element: &str, ports: Option<usize>, output: bool)
+    -> Result<HashMap<usize, usize>, ConfigError>
+{
+    let mut connected = HashMap::new();
+    for (index, connection) in config.connections.iter().enumerate() {
+        let endpoint = if output { &connection.from } else { &connection.to };
+        if endpoint.element != element {
+            continue;
+        }
+        if ports.is_some_and(|ports| endpoint.port >= ports) {
+            return Err(ConfigError {
+                position: endpoint.position,
+                kind: ErrorKind::NoSuchPort { element: element.to_string(), port: endpoint.port, output }
+            })
+        }
+        if connected.insert(endpoint.port, index).is_some() {
+            return Err(ConfigError {
+                position: endpoint.position,
+                kind: ErrorKind::ConnectedTwice { element: element.to_string(), port: endpoint.port, output }
+            })
+        }
+    }
+    Ok(connected)
+}
+
+/// Works out how many ports an element ends up with, and checks that every
+/// one of them is connected. Elements that take as many ports as are
+/// connected get at least one.
+fn port_count(config: &Config, element: &str, ports: Option<usize>, connected: &HashMap<usize, usize>, output: bool)
+    -> Result<usize, ConfigError>
+{
+    let count = ports.unwrap_or_else(|| connected.keys().max().map_or(1, |max| max + 1));
+    match (0..count).find(|port| !connected.contains_key(port)) {
+        Some(port) => Err(ConfigError {
+            position: config.declaration(element).unwrap().position,
+            kind: ErrorKind::Unconnected { element: element.to_string(), port, output }
+        }),
+        None => Ok(count)
+    }
+}
+
+/// Instantiates every element in `config` from `registry`, checks the
+/// connections between them, including that each carries the type of packet
+/// both ends expect, and wires them up into a RouterGraph. Elements are
+/// built in dependency order, so that each one is handed the output streams
+/// of the elements feeding it.
+///
+/// `boundary` holds elements made up front, by class name, such as the
+/// Input and Output of a subgraph. Each is used exactly once, in place of
+/// the registry, and it is an error for the configuration not to use one.
+pub fn build(config: &Config, registry: &Registry, mut boundary: Vec<(&str, Box<dyn DynElement>)>)
+    -> Result<RouterGraph, ConfigError>
+{
+    let mut pending = Vec::with_capacity(config.declarations.len());
+    let mut indices = HashMap::new();
+
+    for declaration in &config.declarations {
+        let constructed = match boundary.iter().position(|(class, _)| *class == declaration.class) {
+            Some(index) => Some(Ok(boundary.remove(index).1)),
+            None => registry.construct(&declaration.class, &declaration.args)
+        };
+        let element = match constructed {
+            None => return Err(ConfigError {
+                position: declaration.position,
+                kind: ErrorKind::UnknownClass(declaration.class.clone())
+            }),
+            Some(Err(message)) => return Err(ConfigError {
+                position: declaration.args_position.unwrap_or(declaration.position),
+                kind: ErrorKind::BadArguments { class: declaration.class.clone(), message }
+            }),
+            Some(Ok(element)) => element
+        };
+
+        let inputs = connected_ports(config, &declaration.name, element.inputs(), false)?;
+        let outputs = connected_ports(config, &declaration.name, element.outputs(), true)?;
+        let num_inputs = port_count(config, &declaration.name, element.inputs(), &inputs, false)?;
+        let num_outputs = port_count(config, &declaration.name, element.outputs(), &outputs, true)?;
+
+        indices.insert(declaration.name.as_str(), pending.len());
+        pending.push(Pending {
+            element: Some(element),
+            inputs: (0..num_inputs).map(|port| inputs[&port]).collect(),
+            num_outputs,
+            node: None
+        });
+    }
+
+    for connection in &config.connections {
+        let from = pending[indices[connection.from.element.as_str()]].element.as_ref().unwrap();
+        let to = pending[indices[connection.to.element.as_str()]].element.as_ref().unwrap();
+        if let (Some(output_type), Some(input_type)) = (from.output_type(), to.input_type()) {
+            if output_type != input_type {
+                return Err(ConfigError {
+                    position: connection.to.position,
+                    kind: ErrorKind::TypeMismatch {
+                        from: connection.from.element.clone(),
+                        to: connection.to.element.clone(),
+                        output_type: output_type.name().to_string(),
+                        input_type: input_type.name().to_string()
+                    }
+                })
+            }
+        }
+    }
+
+    if let Some((class, _)) = boundary.first() {
+        return Err(ConfigError {
+            position: Position { line: 1, column: 1 },
+            kind: ErrorKind::Missing(class.to_string())
+        })
+    }
+
+    let mut graph = RouterGraph::new();
+    let mut streams: HashMap<usize, AnyStream> = HashMap::new();
+    let mut remaining = pending.len();
+
+    while remaining > 0 {
+        let ready = (0..pending.len()).find(|index| {
+            pending[*index].element.is_some() && pending[*index].inputs.iter().all(|connection| streams.contains_key(connection))
+        });
+
+        let index = match ready {
+            Some(index) => index,
+            None => {
+                let stuck = (0..pending.len()).find(|index| pending[*index].element.is_some()).unwrap();
+                let declaration = &config.declarations[stuck];
+                return Err(ConfigError {
+                    position: declaration.position,
+                    kind: ErrorKind::Cycle(declaration.name.clone())
+                })
+            }
+        };
+
+        let declaration = &config.declarations[index];
+        let mut element = pending[index].element.take().unwrap();
+        let inputs = pending[index].inputs.iter().map(|connection| streams.remove(connection).unwrap()).collect();
+        let num_outputs = pending[index].num_outputs;
+        let kind = element.kind();
+        let (input_type, output_type) = (element.input_type(), element.output_type());
+
+        element.stop_on(&graph.shutdown_request());
+        let built = element.build(inputs, num_outputs).map_err(|message| ConfigError {
+            position: declaration.position,
+            kind: ErrorKind::Build { element: declaration.name.clone(), message }
+        })?;
+        assert_eq!(built.outputs.len(), num_outputs, "{} built the wrong number of outputs", declaration.name);
+
+        let node = graph.add_node(&declaration.name, kind, pending[index].inputs.len(), num_outputs);
+        graph.set_packet_types(node, input_type, output_type);
+        for task in built.tasks {
+            graph.add_task(node, task);
+        }
+        for (port, metrics) in built.metrics.into_iter().enumerate() {
+            graph.add_metrics(node.port(port), metrics);
+        }
+        if let Some(latency) = built.latency {
+            graph.add_latency(node, latency);
+        }
+        if let Some(handlers) = built.handlers {
+            graph.add_handlers(node, handlers);
+        }
+        if let Some(state) = built.state {
+            graph.add_state(node, state);
+        }
+        for (port, output) in built.outputs.into_iter().enumerate() {
+            let connection = config.connections.iter()
+                .position(|connection| connection.from.element == declaration.name && connection.from.port == port)
+                .unwrap();
+            streams.insert(connection, output);
+        }
+        pending[index].node = Some(node);
+        remaining -= 1;
+    }
+
+    for connection in &config.connections {
+        let from = pending[indices[connection.from.element.as_str()]].node.unwrap();
+        let to = pending[indices[connection.to.element.as_str()]].node.unwrap();
+        graph.connect(from.port(connection.from.port), to, connection.to.port);
+    }
+
+    Ok(graph)
+}
@@ -0,0 +1,273 @@
+//! A configuration language for describing routers, in the style of Click:
+//!
+//!   src :: FromDevice(eth0);
+//!   cls :: Classifier(12/0806, 12/0800, -);
+//!   src -> cls;
+//!   cls [0] -> ArpResponder -> out :: ToDevice(eth0);
+//!   cls [1] -> Strip(14) -> [0] out;
+//!   cls [2] -> Discard;
+//!
+//! Elements are declared with `name :: Class(args)`, or used anonymously by
+//! class name. `->` connects an output port of one element to an input port
+//! of the next, where ports default to 0 and are picked with `[n]`. Element
+//! classes are looked up in a Registry.
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use crate::api::ElementStream;
+use crate::router::{DynElement, Registry, RouterGraph, SinkNode, SourceNode};
+
+mod parser;
+mod builder;
+
+pub use self::parser::{Config, Declaration, Connection, Endpoint, Parser};
+
+/// A line and column in the configuration, both counting from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ErrorKind {
+    Syntax(String),
+    Redeclared(String),
+    UnknownClass(String),
+    BadArguments { class: String, message: String },
+    NoSuchPort { element: String, port: usize, output: bool },
+    ConnectedTwice { element: String, port: usize, output: bool },
+    Unconnected { element: String, port: usize, output: bool },
+    TypeMismatch { from: String, to: String, output_type: String, input_type: String },
+    Cycle(String),
+    Build { element: String, message: String },
+    /// A subgraph's configuration does not use its Input or Output.
+    Missing(String)
+}
+
+fn direction(output: bool) -> &'static str {
+    if output { "output" } else { "input" }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::Syntax(message) => write!(f, "syntax error: {}", message),
+            ErrorKind::Redeclared(name) => write!(f, "element {} is declared twice", name),
+            ErrorKind::UnknownClass(class) => write!(f, "unknown element class {}", class),
+            ErrorKind::BadArguments { class, message } => write!(f, "bad arguments to {}: {}", class, message),
+            ErrorKind::NoSuchPort { element, port, output } =>
+                write!(f, "{} has no {} port {}", element, direction(*output), port),
+            ErrorKind::ConnectedTwice { element, port, output } =>
+                write!(f, "{} port {} of {} is connected more than once", direction(*output), port, element),
+            ErrorKind::Unconnected { element, port, output } =>
+                write!(f, "{} port {} of {} is not connected", direction(*output), port, element),
+            ErrorKind::TypeMismatch { from, to, output_type, input_type } =>
+                write!(f, "cannot connect {}, which provides {}, to {}, which takes {}", from, output_type, to, input_type),
+            ErrorKind::Cycle(element) => write!(f, "{} is part of a cycle", element),
+            ErrorKind::Build { element, message } => write!(f, "could not build {}: {}", element, message),
+            ErrorKind::Missing(class) => write!(f, "the configuration has no {} element", class)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigError {
+    pub position: Position,
+    pub kind: ErrorKind
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.position, self.kind)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub fn parse(source: &str) -> Result<Config, ConfigError> {
+    Parser::new(source).parse()
+}
+
+/// Parses `source` and builds the router it describes, with element classes
+/// looked up in `registry`.
+pub fn build_router(source: &str, registry: &Registry) -> Result<RouterGraph, ConfigError> {
+    builder::build(&parse(source)?, registry, vec![])
+}
+
+/// Like `build_router`, but builds a subgraph to be swapped into a running
+/// router by a HotSwap. Packets come into the subgraph from its `Input`
+/// element, fed by `input`, and leave it through its `Output` element, whose
+/// input is returned as the subgraph's output stream:
+///
+///   Input -> cls :: EvenOdd;
+///   cls [0] -> Queue -> [0] join :: Join;
+///   cls [1] -> [1] join;
+///   join -> Output;
+pub fn build_subgraph<I, O>(source: &str, registry: &Registry, input: ElementStream<I>)
+    -> Result<(RouterGraph, ElementStream<O>), ConfigError>
+    where I: Send + 'static,
+          O: Send + 'static
+{
+    let output = Arc::new(Mutex::new(None));
+    let slot = Arc::clone(&output);
+    let boundary: Vec<(&str, Box<dyn DynElement>)> = vec![
+        ("Input", Box::new(SourceNode::new(input))),
+        ("Output", Box::new(SinkNode::new(move |stream: ElementStream<O>| {
+            *slot.lock().unwrap() = Some(stream);
+            async {}
+        })))
+    ];
+    let graph = builder::build(&parse(source)?, registry, boundary)?;
+    let output = output.lock().unwrap().take().expect("Output is built along with the rest of the graph");
+    Ok((graph, output))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{Element, AsyncElement, ClassifyElement, Verdict, ArgType, Schema};
+    use crate::router::{ClassifyNode, JoinNode};
+    use crate::utils::test::packet_generators::immediate_stream;
+    use crate::utils::test::packet_collectors::ExhaustiveCollector;
+    use tokio::runtime::Handle;
+
+    struct Identity;
+
+    impl Element for Identity {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            Verdict::Pass(packet)
+        }
+    }
+
+    impl AsyncElement for Identity {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            Verdict::Pass(packet)
+        }
+    }
+
+    struct EvenOdd;
+
+    impl ClassifyElement for EvenOdd {
+        type Packet = i32;
+
+        fn classify(&mut self, packet: &Self::Packet) -> usize {
+            (packet % 2) as usize
+        }
+    }
+
+    struct Length;
+
+    impl Element for Length {
+        type Input = String;
+        type Output = usize;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            Verdict::Pass(packet.len())
+        }
+    }
+
+    fn registry(packets: Arc<Mutex<Vec<i32>>>) -> Registry {
+        let mut registry = Registry::new();
+        let range = Schema::new().required("START", ArgType::Int).required("END", ArgType::Int);
+        registry.register("Range", range, |args| {
+            let (start, end) = (args.int("START") as i32, args.int("END") as i32);
+            Ok(Box::new(SourceNode::new(immediate_stream(start..end))))
+        });
+        registry.register_element("Identity", Schema::new(), |_| Ok(Identity));
+        registry.register_element("Length", Schema::new(), |_| Ok(Length));
+        registry.register_async_element("Queue", Schema::new(), 10, |_| Ok(Identity));
+        registry.register("EvenOdd", Schema::new(), |_| Ok(Box::new(ClassifyNode::new(EvenOdd, 10, 2))));
+        registry.register("Join", Schema::new(), |_| Ok(Box::new(JoinNode::<i32>::round_robin())));
+        registry.register("Collect", Schema::new(), move |_| {
+            let packets = Arc::clone(&packets);
+            Ok(Box::new(SinkNode::new(move |stream| ExhaustiveCollector::new(0, stream, packets))))
+        });
+        registry
+    }
+
+    #[test]
+    fn parses_declarations_and_chains() {
+        let config = parse("
+            // a comment
+            src :: Range(0, 10);
+            src -> cls :: EvenOdd /* another comment */;
+            cls [0] -> Queue -> [0] join :: Join;
+            cls [1] -> Queue -> [1] join;
+            join -> Collect();
+        ").unwrap();
+
+        let names = config.declarations.iter().map(|declaration| declaration.name.as_str()).collect::<Vec<&str>>();
+        assert_eq!(names, vec!["src", "cls", "Queue@1", "join", "Queue@2", "Collect@3"]);
+        assert_eq!(config.declaration("src").unwrap().args, vec!["0", "10"]);
+        assert_eq!(config.connections.len(), 6);
+
+        let connection = &config.connections[4];
+        assert_eq!((connection.from.element.as_str(), connection.from.port), ("Queue@2", 0));
+        assert_eq!((connection.to.element.as_str(), connection.to.port), ("join", 1));
+        assert_eq!(connection.to.position, Position { line: 6, column: 37 });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn builds_and_runs_router() {
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        let router = build_router("
+            src :: Range(0, 20);
+            src -> Identity -> cls :: EvenOdd;
+            cls [0] -> Queue -> [0] join :: Join;
+            cls [1] -> Queue -> [1] join;
+            join -> Collect;
+        ", &registry(Arc::clone(&packets))).unwrap();
+
+        assert_eq!(router.nodes().len(), 7);
+        assert_eq!(router.edges().len(), 7);
+        assert_eq!(router.validate(), Ok(()));
+
+        router.spawn(&Handle::current()).await.unwrap();
+
+        let mut packets = packets.lock().unwrap().clone();
+        packets.sort();
+        assert_eq!(packets, (0..20).collect::<Vec<i32>>());
+    }
+
+    fn build_error(source: &str) -> String {
+        let registry = registry(Arc::new(Mutex::new(Vec::new())));
+        build_router(source, &registry).err().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn reports_errors_with_positions() {
+        assert_eq!(build_error("src :: Range(0, 10)\nsrc -> Collect"),
+            "2:1: syntax error: expected ';' or '->', found 's'");
+        assert_eq!(build_error("src :: Range(0, 10);\nsrc -> Frobnicate -> Collect;"),
+            "2:8: unknown element class Frobnicate");
+        assert_eq!(build_error("src :: Range(0, ten);\nsrc -> Collect;"),
+            "1:13: bad arguments to Range: END: expected integer, got 'ten'");
+        assert_eq!(build_error("src :: Range(0);\nsrc -> Collect;"),
+            "1:13: bad arguments to Range: missing argument END");
+        assert_eq!(build_error("src :: Range(0, 10);\nsrc -> Length -> Collect;"),
+            "2:8: cannot connect src, which provides i32, to Length@1, which takes alloc::string::String");
+        assert_eq!(build_error("src :: Range(0, 10);\ncls :: EvenOdd;\nsrc -> cls;\ncls [0] -> Collect;"),
+            "2:1: output port 1 of cls is not connected");
+        assert_eq!(build_error("src :: Range(0, 10);\nsrc -> Identity;"),
+            "2:8: output port 0 of Identity@1 is not connected");
+        assert_eq!(build_error("src :: Range(0, 10);\nsrc [1] -> Collect;"),
+            "2:1: src has no output port 1");
+        assert_eq!(build_error("a :: Range(0, 10); b :: Range(0, 10);\nc :: Collect;\na -> c;\nb -> c;"),
+            "4:6: input port 0 of c is connected more than once");
+        assert_eq!(build_error("a :: Identity; b :: Identity;\na -> b -> a;"),
+            "1:1: a is part of a cycle");
+    }
+}
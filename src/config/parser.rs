@@ -0,0 +1,320 @@
+use crate::config::{ConfigError, ErrorKind, Position};
+
+/// An element as it appears in the configuration, either declared with
+/// `name :: Class(args)`, or used anonymously as `Class(args)`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Declaration {
+    pub name: String,
+    pub class: String,
+    pub args: Vec<String>,
+    /// Where the element's name, or its class if anonymous, appears.
+    pub position: Position,
+    /// Where the argument list starts, if it has one.
+    pub args_position: Option<Position>
+}
+
+/// One end of a connection: an element and one of its ports.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Endpoint {
+    pub element: String,
+    pub port: usize,
+    pub position: Position
+}
+
+/// A single `from [port] -> [port] to` connection.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Connection {
+    pub from: Endpoint,
+    pub to: Endpoint
+}
+
+/// A parsed configuration. Chains like `a -> b -> c` have been split into
+/// their individual connections, and anonymous elements have been given
+/// names of the form `Class@N`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    pub declarations: Vec<Declaration>,
+    pub connections: Vec<Connection>
+}
+
+impl Config {
+    pub fn declaration(&self, name: &str) -> Option<&Declaration> {
+        self.declarations.iter().find(|declaration| declaration.name == name)
+    }
+}
+
+/// An element reference within a chain, along with the ports written before
+/// and after it.
+struct ChainElement {
+    name: String,
+    input_port: usize,
+    output_port: usize,
+    position: Position
+}
+
+/// A hand written recursive descent parser for the configuration language:
+///
+///   config      := { statement }
+///   statement   := chain ( ';' | EOF ) | ';'
+///   chain       := endpoint { '->' endpoint }
+///   endpoint    := [ '[' port ']' ] element [ '[' port ']' ]
+///   element     := IDENT '::' IDENT [ args ]
+///                | IDENT [ args ]
+///
+/// A bare IDENT refers to an element declared earlier if there is one, and
+/// otherwise creates an anonymous element of that class.
+pub struct Parser<'a> {
+    source: &'a str,
+    offset: usize,
+    line: usize,
+    column: usize,
+    config: Config,
+    anonymous: usize
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(source: &'a str) -> Self {
+        Parser {
+            source,
+            offset: 0,
+            line: 1,
+            column: 1,
+            config: Config::default(),
+            anonymous: 0
+        }
+    }
+
+    pub fn parse(mut self) -> Result<Config, ConfigError> {
+        loop {
+            self.skip_whitespace()?;
+            match self.peek() {
+                None => return Ok(self.config),
+                Some(';') => {
+                    self.bump();
+                },
+                Some(_) => {
+                    self.chain()?;
+                    self.skip_whitespace()?;
+                    match self.peek() {
+                        None => return Ok(self.config),
+                        Some(';') => {
+                            self.bump();
+                        },
+                        Some(c) => return Err(self.error(ErrorKind::Syntax(format!("expected ';' or '->', found '{}'", c))))
+                    }
+                }
+            }
+        }
+    }
+
+    fn position(&self) -> Position {
+        Position { line: self.line, column: self.column }
+    }
+
+    fn error(&self, kind: ErrorKind) -> ConfigError {
+        ConfigError { position: self.position(), kind }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.source[self.offset..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.offset += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn starts_with(&self, token: &str) -> bool {
+        self.source[self.offset..].starts_with(token)
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), ConfigError> {
+        if self.starts_with(token) {
+            for _ in token.chars() {
+                self.bump();
+            }
+            Ok(())
+        } else {
+            let found = self.peek().map_or("end of input".to_string(), |c| format!("'{}'", c));
+            Err(self.error(ErrorKind::Syntax(format!("expected '{}', found {}", token, found))))
+        }
+    }
+
+    /// Skips whitespace, `//` line comments and `/* */` block comments.
+    fn skip_whitespace(&mut self) -> Result<(), ConfigError> {
+        loop {
+            if self.starts_with("//") {
+                while !matches!(self.peek(), None | Some('\n')) {
+                    self.bump();
+                }
+            } else if self.starts_with("/*") {
+                let start = self.position();
+                self.expect("/*")?;
+                while !self.starts_with("*/") {
+                    if self.bump().is_none() {
+                        return Err(ConfigError { position: start, kind: ErrorKind::Syntax("unterminated comment".to_string()) })
+                    }
+                }
+                self.expect("*/")?;
+            } else if self.peek().is_some_and(char::is_whitespace) {
+                self.bump();
+            } else {
+                return Ok(())
+            }
+        }
+    }
+
+    fn identifier(&mut self) -> Result<(String, Position), ConfigError> {
+        self.skip_whitespace()?;
+        let position = self.position();
+        let start = self.offset;
+        if self.peek().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') {
+            while self.peek().is_some_and(|c| c.is_ascii_alphanumeric() || c == '_') {
+                self.bump();
+            }
+            Ok((self.source[start..self.offset].to_string(), position))
+        } else {
+            let found = self.peek().map_or("end of input".to_string(), |c| format!("'{}'", c));
+            Err(self.error(ErrorKind::Syntax(format!("expected an element name, found {}", found))))
+        }
+    }
+
+    /// Parses an optional `[port]`, defaulting to port 0.
+    fn port(&mut self) -> Result<usize, ConfigError> {
+        self.skip_whitespace()?;
+        if self.peek() != Some('[') {
+            return Ok(0)
+        }
+        self.bump();
+        self.skip_whitespace()?;
+        let position = self.position();
+        let start = self.offset;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.bump();
+        }
+        let port = self.source[start..self.offset].parse::<usize>()
+            .map_err(|_| ConfigError { position, kind: ErrorKind::Syntax("expected a port number".to_string()) })?;
+        self.skip_whitespace()?;
+        self.expect("]")?;
+        Ok(port)
+    }
+
+    /// Parses a parenthesized argument list, splitting on commas that are not
+    /// nested in parentheses or quotes. Arguments are trimmed of whitespace.
+    fn args(&mut self) -> Result<Vec<String>, ConfigError> {
+        let start = self.position();
+        self.expect("(")?;
+        let mut args = vec![];
+        let mut current = String::new();
+        let mut depth = 0;
+        let mut quoted = false;
+        loop {
+            let c = match self.bump() {
+                Some(c) => c,
+                None => return Err(ConfigError { position: start, kind: ErrorKind::Syntax("unterminated argument list".to_string()) })
+            };
+            match c {
+                '"' => {
+                    quoted = !quoted;
+                    current.push(c);
+                },
+                '(' if !quoted => {
+                    depth += 1;
+                    current.push(c);
+                },
+                ')' if !quoted && depth > 0 => {
+                    depth -= 1;
+                    current.push(c);
+                },
+                ')' if !quoted => break,
+                ',' if !quoted && depth == 0 => {
+                    args.push(current.trim().to_string());
+                    current.clear();
+                },
+                _ => current.push(c)
+            }
+        }
+        let last = current.trim();
+        if !last.is_empty() || !args.is_empty() {
+            args.push(last.to_string());
+        }
+        Ok(args)
+    }
+
+    fn declare(&mut self, declaration: Declaration) -> Result<(), ConfigError> {
+        if self.config.declaration(&declaration.name).is_some() {
+            return Err(ConfigError {
+                position: declaration.position,
+                kind: ErrorKind::Redeclared(declaration.name)
+            })
+        }
+        self.config.declarations.push(declaration);
+        Ok(())
+    }
+
+    fn element(&mut self) -> Result<(String, Position), ConfigError> {
+        let (identifier, position) = self.identifier()?;
+        self.skip_whitespace()?;
+
+        if self.starts_with("::") {
+            self.expect("::")?;
+            let (class, _) = self.identifier()?;
+            self.skip_whitespace()?;
+            let (args, args_position) = if self.peek() == Some('(') {
+                let args_position = self.position();
+                (self.args()?, Some(args_position))
+            } else {
+                (vec![], None)
+            };
+            self.declare(Declaration { name: identifier.clone(), class, args, position, args_position })?;
+            return Ok((identifier, position))
+        }
+
+        let has_args = self.peek() == Some('(');
+        if !has_args && self.config.declaration(&identifier).is_some() {
+            return Ok((identifier, position))
+        }
+
+        let (args, args_position) = if has_args {
+            let args_position = self.position();
+            (self.args()?, Some(args_position))
+        } else {
+            (vec![], None)
+        };
+        self.anonymous += 1;
+        let name = format!("{}@{}", identifier, self.anonymous);
+        self.declare(Declaration { name: name.clone(), class: identifier, args, position, args_position })?;
+        Ok((name, position))
+    }
+
+    fn endpoint(&mut self) -> Result<ChainElement, ConfigError> {
+        let input_port = self.port()?;
+        let (name, position) = self.element()?;
+        let output_port = self.port()?;
+        Ok(ChainElement { name, input_port, output_port, position })
+    }
+
+    fn chain(&mut self) -> Result<(), ConfigError> {
+        let mut from = self.endpoint()?;
+        loop {
+            self.skip_whitespace()?;
+            if !self.starts_with("->") {
+                return Ok(())
+            }
+            self.expect("->")?;
+            let to = self.endpoint()?;
+            self.config.connections.push(Connection {
+                from: Endpoint { element: from.name.clone(), port: from.output_port, position: from.position },
+                to: Endpoint { element: to.name.clone(), port: to.input_port, position: to.position }
+            });
+            from = to;
+        }
+    }
+}
@@ -1,20 +1,23 @@
-#[macro_use]
 extern crate futures;
 extern crate tokio;
 extern crate crossbeam;
+extern crate hdrhistogram;
 
 pub mod api;
+pub mod router;
+pub mod config;
 mod utils;
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::api::{ElementLink, Element, AsyncElementLink, AsyncElement};
+    use crate::api::{ElementLink, Element, AsyncElementLink, AsyncElement, Verdict};
     use crate::utils::test::packet_generators::{ immediate_stream, LinearIntervalGenerator };
-    use crate::utils::test::packet_collectors::ExhaustiveDrain;
+    use crate::utils::test::packet_collectors::{ExhaustiveDrain, ExhaustiveCollector};
     use core::time;
+    use std::sync::{Arc, Mutex};
 
-    use futures::future::lazy;
+    use futures::StreamExt;
 
     struct IdentityElement {
         id: i32
@@ -24,9 +27,9 @@ mod tests {
         type Input = i32;
         type Output = i32;
 
-        fn process(&mut self, packet: Self::Input) -> Self::Output {
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
             println!("Got packet {} in element {}", packet, self.id);
-            packet
+            Verdict::Pass(packet)
         }
     }
 
@@ -35,8 +38,8 @@ mod tests {
     /// This test creates one Sync element, and uses the LinearIntervalGenerator to test whether
     /// the element responds correctly to an upstream source providing a series of valid packets,
     /// interleaved with Async::NotReady values, finalized by a Async::Ready(None)
-    #[test]
-    fn one_sync_element_interval_yield() {
+    #[tokio::test]
+    async fn one_sync_element_interval_yield() {
         let packet_generator = LinearIntervalGenerator::new(time::Duration::from_millis(100), 10);
 
         let elem1 = IdentityElement { id: 0 };
@@ -44,12 +47,12 @@ mod tests {
 
         // core_elem1 to! core_elem2
 
-        let elem1_link = ElementLink::new(Box::new(packet_generator), elem1);
-        let elem2_link = ElementLink::new(Box::new(elem1_link), elem2);
+        let elem1_link = ElementLink::new(Box::pin(packet_generator), elem1);
+        let elem2_link = ElementLink::new(Box::pin(elem1_link), elem2);
 
-        let consumer = ExhaustiveDrain::new(1, Box::new(elem2_link));
+        let consumer = ExhaustiveDrain::new(1, Box::pin(elem2_link));
 
-        tokio::run(consumer);
+        consumer.await;
     }
 
 
@@ -61,59 +64,58 @@ mod tests {
         type Input = i32;
         type Output = i32;
 
-        fn process(&mut self, packet: Self::Input) -> Self::Output {
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
             println!("AsyncElement #{} got packet {}", self.id, packet);
-            packet
+            Verdict::Pass(packet)
         }
     }
 
 
-    #[test]
-    fn one_async_element_immediate_yield() {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn one_async_element_immediate_yield() {
         let default_channel_size = 10;
         let packet_generator = immediate_stream(0..=20);
 
 
         let elem0 = AsyncIdentityElement { id: 0 };
 
-        let elem0_link = AsyncElementLink::new(Box::new(packet_generator), elem0, default_channel_size);
+        let elem0_link = AsyncElementLink::new(Box::pin(packet_generator), elem0, default_channel_size);
 
         let elem0_drain = elem0_link.consumer;
-        let elem0_consumer = ExhaustiveDrain::new(1, Box::new(elem0_link.provider));
+        let elem0_consumer = ExhaustiveDrain::new(1, Box::pin(elem0_link.provider));
 
-        tokio::run(lazy (|| {
-            tokio::spawn(elem0_drain);
-            tokio::spawn(elem0_consumer);
-            Ok(())
-        }));
+        let elem0_drain = tokio::spawn(elem0_drain);
+        let elem0_consumer = tokio::spawn(elem0_consumer);
+        elem0_drain.await.unwrap();
+        elem0_consumer.await.unwrap();
     }
 
-    #[test]
-    fn two_async_elements_immediate_yield() {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn two_async_elements_immediate_yield() {
         let default_channel_size = 10;
         let packet_generator = immediate_stream(0..=20);
 
         let elem0 = AsyncIdentityElement { id: 0 };
         let elem1 = AsyncIdentityElement { id: 1 };
 
-        let elem0_link = AsyncElementLink::new(Box::new(packet_generator), elem0, default_channel_size);
-        let elem1_link = AsyncElementLink::new(Box::new(elem0_link.provider), elem1, default_channel_size);
+        let elem0_link = AsyncElementLink::new(Box::pin(packet_generator), elem0, default_channel_size);
+        let elem1_link = AsyncElementLink::new(Box::pin(elem0_link.provider), elem1, default_channel_size);
 
         let elem0_drain = elem0_link.consumer;
         let elem1_drain = elem1_link.consumer;
 
-        let elem1_consumer = ExhaustiveDrain::new(1, Box::new(elem1_link.provider));
+        let elem1_consumer = ExhaustiveDrain::new(1, Box::pin(elem1_link.provider));
 
-        tokio::run(lazy (|| {
-            tokio::spawn(elem0_drain);
-            tokio::spawn(elem1_drain);
-            tokio::spawn(elem1_consumer);
-            Ok(())
-        }));
+        let elem0_drain = tokio::spawn(elem0_drain);
+        let elem1_drain = tokio::spawn(elem1_drain);
+        let elem1_consumer = tokio::spawn(elem1_consumer);
+        elem0_drain.await.unwrap();
+        elem1_drain.await.unwrap();
+        elem1_consumer.await.unwrap();
     }
 
-    #[test]
-    fn series_sync_and_async_immediate_yield() {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn series_sync_and_async_immediate_yield() {
         let default_channel_size = 10;
         let packet_generator = immediate_stream(0..=20);
 
@@ -122,69 +124,68 @@ mod tests {
         let elem2 = IdentityElement { id: 2 };
         let elem3 = AsyncIdentityElement { id: 3 };
 
-        let elem0_link = ElementLink::new(Box::new(packet_generator), elem0);
-        let elem1_link = AsyncElementLink::new(Box::new(elem0_link), elem1, default_channel_size);
-        let elem2_link = ElementLink::new(Box::new(elem1_link.provider), elem2);
-        let elem3_link = AsyncElementLink::new(Box::new(elem2_link), elem3, default_channel_size);
+        let elem0_link = ElementLink::new(Box::pin(packet_generator), elem0);
+        let elem1_link = AsyncElementLink::new(Box::pin(elem0_link), elem1, default_channel_size);
+        let elem2_link = ElementLink::new(Box::pin(elem1_link.provider), elem2);
+        let elem3_link = AsyncElementLink::new(Box::pin(elem2_link), elem3, default_channel_size);
 
         let elem1_drain = elem1_link.consumer;
         let elem3_drain = elem3_link.consumer;
 
-        let elem3_consumer = ExhaustiveDrain::new(0, Box::new(elem3_link.provider));
+        let elem3_consumer = ExhaustiveDrain::new(0, Box::pin(elem3_link.provider));
 
-        tokio::run(lazy (|| {
-            tokio::spawn(elem1_drain);
-            tokio::spawn(elem3_drain); 
-            tokio::spawn(elem3_consumer);
-            Ok(())
-        }));
+        let elem1_drain = tokio::spawn(elem1_drain);
+        let elem3_drain = tokio::spawn(elem3_drain);
+        let elem3_consumer = tokio::spawn(elem3_consumer);
+        elem1_drain.await.unwrap();
+        elem3_drain.await.unwrap();
+        elem3_consumer.await.unwrap();
     }
 
-        #[test]
-    fn one_async_element_interval_yield() {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn one_async_element_interval_yield() {
         let default_channel_size = 10;
         let packet_generator = LinearIntervalGenerator::new(time::Duration::from_millis(100), 20);
 
         let elem0 = AsyncIdentityElement { id: 0 };
 
-        let elem0_link = AsyncElementLink::new(Box::new(packet_generator), elem0, default_channel_size);
+        let elem0_link = AsyncElementLink::new(Box::pin(packet_generator), elem0, default_channel_size);
 
         let elem0_drain = elem0_link.consumer;
-        let elem0_consumer = ExhaustiveDrain::new(0, Box::new(elem0_link.provider));
+        let elem0_consumer = ExhaustiveDrain::new(0, Box::pin(elem0_link.provider));
 
-        tokio::run(lazy (|| {
-            tokio::spawn(elem0_drain);
-            tokio::spawn(elem0_consumer);
-            Ok(())
-        }));
+        let elem0_drain = tokio::spawn(elem0_drain);
+        let elem0_consumer = tokio::spawn(elem0_consumer);
+        elem0_drain.await.unwrap();
+        elem0_consumer.await.unwrap();
     }
 
-    #[test]
-    fn two_async_elements_interval_yield() {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn two_async_elements_interval_yield() {
         let default_channel_size = 10;
         let packet_generator = LinearIntervalGenerator::new(time::Duration::from_millis(100), 20);
 
         let elem0 = AsyncIdentityElement { id: 0 };
         let elem1 = AsyncIdentityElement { id: 1 };
 
-        let elem0_link = AsyncElementLink::new(Box::new(packet_generator), elem0, default_channel_size);
-        let elem1_link = AsyncElementLink::new(Box::new(elem0_link.provider), elem1, default_channel_size);
+        let elem0_link = AsyncElementLink::new(Box::pin(packet_generator), elem0, default_channel_size);
+        let elem1_link = AsyncElementLink::new(Box::pin(elem0_link.provider), elem1, default_channel_size);
 
         let elem0_drain = elem0_link.consumer;
         let elem1_drain = elem1_link.consumer;
 
-        let elem1_consumer = ExhaustiveDrain::new(0, Box::new(elem1_link.provider));
+        let elem1_consumer = ExhaustiveDrain::new(0, Box::pin(elem1_link.provider));
 
-        tokio::run(lazy (|| {
-            tokio::spawn(elem0_drain);
-            tokio::spawn(elem1_drain);
-            tokio::spawn(elem1_consumer);
-            Ok(())
-        }));
+        let elem0_drain = tokio::spawn(elem0_drain);
+        let elem1_drain = tokio::spawn(elem1_drain);
+        let elem1_consumer = tokio::spawn(elem1_consumer);
+        elem0_drain.await.unwrap();
+        elem1_drain.await.unwrap();
+        elem1_consumer.await.unwrap();
     }
 
-    #[test]
-    fn series_sync_and_async_interval_yield() {
+    #[tokio::test(flavor = "multi_thread")]
+    async fn series_sync_and_async_interval_yield() {
         let default_channel_size = 10;
         let packet_generator = LinearIntervalGenerator::new(time::Duration::from_millis(100), 20);
 
@@ -193,21 +194,196 @@ mod tests {
         let elem2 = IdentityElement { id: 2 };
         let elem3 = AsyncIdentityElement { id: 3 };
 
-        let elem0_link = ElementLink::new(Box::new(packet_generator), elem0);
-        let elem1_link = AsyncElementLink::new(Box::new(elem0_link), elem1, default_channel_size);
-        let elem2_link = ElementLink::new(Box::new(elem1_link.provider), elem2);
-        let elem3_link = AsyncElementLink::new(Box::new(elem2_link), elem3, default_channel_size);
+        let elem0_link = ElementLink::new(Box::pin(packet_generator), elem0);
+        let elem1_link = AsyncElementLink::new(Box::pin(elem0_link), elem1, default_channel_size);
+        let elem2_link = ElementLink::new(Box::pin(elem1_link.provider), elem2);
+        let elem3_link = AsyncElementLink::new(Box::pin(elem2_link), elem3, default_channel_size);
 
         let elem1_drain = elem1_link.consumer;
         let elem3_drain = elem3_link.consumer;
 
-        let elem3_consumer = ExhaustiveDrain::new(2, Box::new(elem3_link.provider));
+        let elem3_consumer = ExhaustiveDrain::new(2, Box::pin(elem3_link.provider));
 
-        tokio::run(lazy (|| {
-            tokio::spawn(elem1_drain);
-            tokio::spawn(elem3_drain); 
-            tokio::spawn(elem3_consumer);
-            Ok(())
-        }));
+        let elem1_drain = tokio::spawn(elem1_drain);
+        let elem3_drain = tokio::spawn(elem3_drain);
+        let elem3_consumer = tokio::spawn(elem3_consumer);
+        elem1_drain.await.unwrap();
+        elem3_drain.await.unwrap();
+        elem3_consumer.await.unwrap();
+    }
+
+    struct DropOddElement;
+
+    impl Element for DropOddElement {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            if packet % 2 == 0 { Verdict::Pass(packet) } else { Verdict::Drop }
+        }
+    }
+
+    struct AsyncDuplicateElement;
+
+    impl AsyncElement for AsyncDuplicateElement {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            match packet % 3 {
+                0 => Verdict::Many(vec![]),
+                1 => Verdict::Pass(packet),
+                _ => Verdict::Many(vec![packet, packet])
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn sync_element_drops_packets() {
+        let packet_generator = LinearIntervalGenerator::new(time::Duration::from_millis(10), 20);
+
+        let elem0_link = ElementLink::new(Box::pin(packet_generator), DropOddElement);
+        let elem0_drops = elem0_link.drop_counter();
+
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        let consumer = ExhaustiveCollector::new(0, Box::pin(elem0_link), Arc::clone(&packets));
+
+        consumer.await;
+
+        assert_eq!(*packets.lock().unwrap(), (0..=20).filter(|p| p % 2 == 0).collect::<Vec<i32>>());
+        assert_eq!(elem0_drops.get(), 10);
+    }
+
+    /// Links chained without boxing, the input of each being the link before
+    /// it, as its concrete type.
+    #[tokio::test]
+    async fn unboxed_chain_of_links() {
+        let elem0_link = ElementLink::new(futures::stream::iter(0..=20), DropOddElement);
+        let elem1_link = ElementLink::new(elem0_link, IdentityElement { id: 1 });
+        let elem2_link = AsyncElementLink::new(elem1_link, AsyncDuplicateElement, 4);
+
+        let elem2_consumer = tokio::spawn(elem2_link.consumer);
+        let packets: Vec<i32> = elem2_link.provider.collect().await;
+        elem2_consumer.await.unwrap();
+
+        assert_eq!(packets, vec![2, 2, 4, 8, 8, 10, 14, 14, 16, 20, 20]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn async_element_emits_zero_or_more_packets() {
+        let default_channel_size = 2;
+        let packet_generator = immediate_stream(0..9);
+
+        let elem0_link = ElementLink::new(Box::pin(packet_generator), DropOddElement);
+        let elem1_link = AsyncElementLink::new(Box::pin(elem0_link), AsyncDuplicateElement, default_channel_size);
+        let elem1_drops = elem1_link.consumer.drop_counter();
+
+        let elem1_drain = elem1_link.consumer;
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        let elem1_consumer = ExhaustiveCollector::new(0, Box::pin(elem1_link.provider), Arc::clone(&packets));
+
+        let elem1_drain = tokio::spawn(elem1_drain);
+        let elem1_consumer = tokio::spawn(elem1_consumer);
+        elem1_drain.await.unwrap();
+        elem1_consumer.await.unwrap();
+
+        assert_eq!(*packets.lock().unwrap(), vec![2, 2, 4, 8, 8]);
+        assert_eq!(elem1_drops.get(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn async_element_provider_with_async_await() {
+        let default_channel_size = 4;
+        let packet_generator = immediate_stream(0..=20);
+
+        let elem0_link = AsyncElementLink::new(Box::pin(packet_generator), AsyncIdentityElement { id: 0 }, default_channel_size);
+
+        let elem0_drain = tokio::spawn(elem0_link.consumer);
+        let mut elem0_provider = elem0_link.provider;
+
+        let mut packets = vec![];
+        while let Some(packet) = elem0_provider.next().await {
+            packets.push(packet);
+        }
+        elem0_drain.await.unwrap();
+
+        assert_eq!(packets, (0..=20).collect::<Vec<i32>>());
+    }
+
+    #[tokio::test]
+    async fn links_record_metrics() {
+        let default_channel_size = 2;
+        let packet_generator = immediate_stream(0..9);
+
+        let elem0_link = ElementLink::new(Box::pin(packet_generator), DropOddElement);
+        let elem0_metrics = elem0_link.metrics();
+        let elem1_link = AsyncElementLink::new(Box::pin(elem0_link), AsyncDuplicateElement, default_channel_size);
+        let elem1_metrics = elem1_link.provider.metrics();
+
+        let elem1_drain = tokio::spawn(elem1_link.consumer);
+        let elem1_consumer = tokio::spawn(ExhaustiveDrain::new(0, Box::pin(elem1_link.provider)));
+        elem1_drain.await.unwrap();
+        elem1_consumer.await.unwrap();
+
+        let elem0 = elem0_metrics.snapshot();
+        assert_eq!((elem0.packets_in, elem0.packets_out, elem0.drops), (9, 5, 4));
+        assert_eq!(elem0.queue_capacity, None);
+
+        let elem1 = elem1_metrics.snapshot();
+        assert_eq!((elem1.packets_in, elem1.packets_out, elem1.drops), (5, 5, 2));
+        assert_eq!((elem1.queue_depth, elem1.queue_high_water, elem1.queue_capacity), (0, 2, Some(2)));
+        assert!(elem1.consumer_sleeps > 0);
+        assert!(elem1.provider_sleeps > 0);
+    }
+
+    struct SlowElement;
+
+    impl Element for SlowElement {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            std::thread::sleep(time::Duration::from_millis(2));
+            Verdict::Pass(packet)
+        }
+    }
+
+    #[tokio::test]
+    async fn instrumented_links_record_latency() {
+        let default_channel_size = 5;
+        let packet_generator = immediate_stream(0..10);
+
+        let elem0_link = ElementLink::instrumented(Box::pin(packet_generator), SlowElement);
+        let elem0_latency = elem0_link.latency().unwrap();
+        let elem1_link = AsyncElementLink::instrumented(Box::pin(elem0_link), AsyncIdentityElement { id: 1 }, default_channel_size);
+        let elem1_latency = elem1_link.provider.latency().unwrap();
+
+        let elem1_drain = tokio::spawn(elem1_link.consumer);
+        let elem1_consumer = tokio::spawn(ExhaustiveDrain::new(0, Box::pin(elem1_link.provider)));
+        elem1_drain.await.unwrap();
+        elem1_consumer.await.unwrap();
+
+        let processing = elem0_latency.processing.snapshot();
+        assert_eq!(processing.count, 10);
+        assert!(processing.min >= time::Duration::from_millis(2));
+        assert_eq!(elem0_latency.queueing.count(), 0);
+
+        assert_eq!(elem1_latency.processing.count(), 10);
+        assert_eq!(elem1_latency.queueing.count(), 10);
+        assert!(elem1_latency.queueing.percentile(100.0) >= elem1_latency.queueing.percentile(50.0));
+    }
+
+    #[tokio::test]
+    async fn dropping_consumer_on_full_queue_disconnects_provider() {
+        let elem0_link = AsyncElementLink::new(immediate_stream(0..=20), AsyncIdentityElement { id: 0 }, 4);
+        let mut elem0_drain = elem0_link.consumer;
+        let elem0_provider = elem0_link.provider;
+
+        // The consumer fills the queue, and goes to sleep on it.
+        assert_eq!(futures::poll!(&mut elem0_drain), std::task::Poll::Pending);
+        drop(elem0_drain);
+
+        let packets = elem0_provider.collect::<Vec<i32>>().await;
+        assert_eq!(packets, (0..4).collect::<Vec<i32>>());
     }
 }
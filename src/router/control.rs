@@ -0,0 +1,221 @@
+//! A control socket for a running router. Operators connect to a Unix socket
+//! and send one command per line:
+//!
+//!   LIST                  every element and its kind
+//!   HANDLERS              every handler, and whether it can be read or written
+//!   READ element.handler  the value of a handler
+//!   WRITE element.handler [value]
+//!   METRICS               every metric, in Prometheus' text format
+//!   DOT                   the graph in DOT, annotated with live statistics
+//!   SHUTDOWN              asks the router to shut down
+//!   QUIT                  closes the connection
+//!
+//! Every command is answered with `OK <length>` on a line of its own,
+//! followed by exactly `<length>` bytes of output, or with `ERR <message>`.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
+use tokio::net::{UnixListener, UnixStream};
+use crate::api::HandlerTable;
+use crate::router::{dot, Edge, Node, RouterMetrics, ShutdownRequest};
+
+/// Everything the control socket needs to know about a router: its shape,
+/// and handles on its metrics, handlers and shutdown. Cloning it is cheap.
+#[derive(Clone)]
+pub struct RouterControl {
+    pub(crate) nodes: Vec<Node>,
+    pub(crate) edges: Vec<Edge>,
+    pub(crate) metrics: RouterMetrics,
+    pub(crate) handlers: HandlerTable,
+    pub(crate) shutdown: ShutdownRequest
+}
+
+impl RouterControl {
+    /// Runs a single command, as sent over the control socket, returning its
+    /// output or an error message.
+    pub fn execute(&self, line: &str) -> Result<String, String> {
+        let line = line.trim();
+        let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match command.to_ascii_uppercase().as_str() {
+            "LIST" => Ok(self.nodes.iter()
+                .map(|node| format!("{} {:?}\n", node.name, node.kind))
+                .collect()),
+            "HANDLERS" => Ok(self.handlers.list().iter()
+                .map(|info| {
+                    let mode = match (info.readable, info.writable) {
+                        (true, true) => "rw",
+                        (true, false) => "r",
+                        _ => "w"
+                    };
+                    format!("{} {}\n", info.name, mode)
+                })
+                .collect()),
+            "READ" if !rest.is_empty() => self.handlers.read(rest.trim()).map_err(|err| err.to_string()),
+            "WRITE" if !rest.is_empty() => {
+                let rest = rest.trim_start();
+                let (path, value) = rest.split_once(' ').unwrap_or((rest, ""));
+                self.handlers.write(path, value).map(|()| String::new()).map_err(|err| err.to_string())
+            },
+            "READ" | "WRITE" => Err(format!("usage: {} element.handler", command)),
+            "METRICS" => Ok(self.metrics.to_prometheus()),
+            "DOT" => Ok(dot::render(&self.nodes, &self.edges, &self.metrics.edge_stats())),
+            "SHUTDOWN" => {
+                self.shutdown.request();
+                Ok(String::new())
+            },
+            "" => Err("empty command".to_string()),
+            _ => Err(format!("unknown command {}", command))
+        }
+    }
+
+    pub fn shutdown(&self) -> ShutdownRequest {
+        self.shutdown.clone()
+    }
+}
+
+async fn serve_connection(stream: UnixStream, control: &RouterControl) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = AsyncBufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().eq_ignore_ascii_case("QUIT") {
+            break;
+        }
+        let response = match control.execute(&line) {
+            Ok(output) => format!("OK {}\n{}", output.len(), output),
+            Err(message) => format!("ERR {}\n", message.replace('\n', " "))
+        };
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Answers commands from every connection on `listener`, until accepting a
+/// connection fails. Each connection is served on its own task.
+pub async fn serve_control(listener: UnixListener, control: RouterControl) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let control = control.clone();
+        tokio::spawn(async move {
+            // An operator hanging up mid-command only loses their own answer.
+            let _ = serve_connection(stream, &control).await;
+        });
+    }
+}
+
+/// A blocking client for the control socket, as used by `routectl`.
+pub struct ControlClient {
+    stream: BufReader<StdUnixStream>
+}
+
+impl ControlClient {
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(ControlClient { stream: BufReader::new(StdUnixStream::connect(path)?) })
+    }
+
+    /// Sends `command`, and returns its output, or the error message the
+    /// router answered with.
+    pub fn request(&mut self, command: &str) -> io::Result<Result<String, String>> {
+        writeln!(self.stream.get_mut(), "{}", command)?;
+
+        let mut status = String::new();
+        if self.stream.read_line(&mut status)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "control socket closed"))
+        }
+        let status = status.trim_end();
+        if let Some(message) = status.strip_prefix("ERR ") {
+            return Ok(Err(message.to_string()))
+        }
+        let length = status.strip_prefix("OK ")
+            .and_then(|length| length.parse::<usize>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("bad response: {}", status)))?;
+
+        let mut output = vec![0; length];
+        self.stream.read_exact(&mut output)?;
+        String::from_utf8(output)
+            .map(Ok)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{AsyncElement, AsyncElementLink, Handlers, HandlerInfo, Shared, Verdict};
+    use crate::router::RouterGraph;
+    use crate::utils::test::packet_generators::immediate_stream;
+    use crate::utils::test::packet_collectors::ExhaustiveDrain;
+    use tokio::runtime::Handle;
+
+    struct Counter {
+        count: u64
+    }
+
+    impl AsyncElement for Counter {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            self.count += 1;
+            Verdict::Pass(packet)
+        }
+    }
+
+    impl Handlers for Counter {
+        fn handlers(&self) -> Vec<HandlerInfo> {
+            vec![HandlerInfo::read_write("count")]
+        }
+
+        fn read_handler(&self, _name: &str) -> Result<String, String> {
+            Ok(self.count.to_string())
+        }
+
+        fn write_handler(&mut self, _name: &str, value: &str) -> Result<(), String> {
+            self.count = value.parse().map_err(|_| format!("not a number: {}", value))?;
+            Ok(())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn answers_commands_over_unix_socket() {
+        let counter = Shared::new(Counter { count: 0 });
+        let mut router = RouterGraph::new();
+        let src = router.add_source("src");
+        let link = AsyncElementLink::new(immediate_stream(0..5), counter.clone(), 10);
+        let (node, provider) = router.add_async_link("counter", src, link);
+        router.add_handlers(node, counter.handlers());
+        router.add_sink("drain", node, ExhaustiveDrain::new(0, Box::pin(provider)));
+
+        let control = router.control();
+        let shutdown = control.shutdown();
+        router.spawn(&Handle::current()).await.unwrap();
+
+        let path = std::env::temp_dir().join(format!("route-rs-control-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(serve_control(listener, control));
+
+        let client_path = path.clone();
+        let responses = tokio::task::spawn_blocking(move || {
+            let mut client = ControlClient::connect(&client_path).unwrap();
+            let commands = ["LIST", "HANDLERS", "READ counter.count", "WRITE counter.count 42", "read counter.count",
+                "WRITE counter.count many", "READ", "FROB", "METRICS", "DOT", "SHUTDOWN"];
+            commands.iter().map(|command| client.request(command).unwrap()).collect::<Vec<_>>()
+        }).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(responses[0], Ok("src Source\ncounter Async\ndrain Sink\n".to_string()));
+        assert_eq!(responses[1], Ok("counter.count rw\n".to_string()));
+        assert_eq!(responses[2], Ok("5".to_string()));
+        assert_eq!(responses[3], Ok("".to_string()));
+        assert_eq!(responses[4], Ok("42".to_string()));
+        assert_eq!(responses[5], Err("counter.count: not a number: many".to_string()));
+        assert_eq!(responses[6], Err("usage: READ element.handler".to_string()));
+        assert_eq!(responses[7], Err("unknown command FROB".to_string()));
+        assert!(responses[8].as_ref().unwrap().contains("route_link_packets_out_total{element=\"counter\",port=\"0\"} 5\n"));
+        assert!(responses[9].as_ref().unwrap().contains("n1 -> n2 [style=solid, label=\"5 pkts\\n0/10 queued\"];"));
+        assert_eq!(responses[10], Ok("".to_string()));
+        shutdown.requested().await;
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use crate::router::{Edge, Node, NodeKind, OutputPort};
+
+/// Live statistics to annotate an edge with, as of when the graph is
+/// rendered. Anything left as None is not shown.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EdgeStats {
+    /// Packets that have gone over the edge so far.
+    pub packets: Option<u64>,
+    /// Packets sitting in the queue behind the edge, and how many it holds.
+    pub queue: Option<(usize, usize)>
+}
+
+fn shape(kind: NodeKind) -> &'static str {
+    match kind {
+        NodeKind::Source => "invhouse",
+        NodeKind::Sync => "box",
+        NodeKind::Async => "box3d",
+        NodeKind::Classify => "diamond",
+        NodeKind::Join => "invtrapezium",
+        NodeKind::Tee => "trapezium",
+        NodeKind::Sink => "house"
+    }
+}
+
+/// Whether packets leaving a node of this kind sit in a queue until pulled,
+/// rather than being handed straight to whoever polls the node.
+fn queued(kind: NodeKind) -> bool {
+    match kind {
+        NodeKind::Async | NodeKind::Classify | NodeKind::Tee => true,
+        NodeKind::Source | NodeKind::Sync | NodeKind::Join | NodeKind::Sink => false
+    }
+}
+
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn label(stats: &EdgeStats) -> Option<String> {
+    let mut lines = vec![];
+    if let Some(packets) = stats.packets {
+        lines.push(format!("{} pkts", packets));
+    }
+    if let Some((depth, capacity)) = stats.queue {
+        lines.push(format!("{}/{} queued", depth, capacity));
+    }
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join("\\n"))
+    }
+}
+
+/// Renders a graph in Graphviz's DOT language. Nodes are shaped by their
+/// kind, nodes with a task of their own are filled in, and edges coming out
+/// of a queue are drawn solid while direct pulls are dashed.
+pub fn render(nodes: &[Node], edges: &[Edge], stats: &HashMap<OutputPort, EdgeStats>) -> String {
+    let mut dot = String::new();
+    writeln!(dot, "digraph router {{").unwrap();
+    writeln!(dot, "    rankdir=LR;").unwrap();
+
+    for (index, node) in nodes.iter().enumerate() {
+        let style = if node.kind.has_task() { ", style=filled" } else { "" };
+        writeln!(dot, "    n{} [label={}, shape={}{}];", index, quote(&node.name), shape(node.kind), style).unwrap();
+    }
+
+    for edge in edges {
+        let from = &nodes[edge.from.node.0];
+        let to = &nodes[edge.to.0];
+        let mut attributes = vec![format!("style={}", if queued(from.kind) { "solid" } else { "dashed" })];
+        if from.outputs > 1 {
+            attributes.push(format!("taillabel=\"{}\"", edge.from.port));
+        }
+        if to.inputs > 1 {
+            attributes.push(format!("headlabel=\"{}\"", edge.to_port));
+        }
+        if let Some(label) = stats.get(&edge.from).and_then(label) {
+            attributes.push(format!("label=\"{}\"", label));
+        }
+        writeln!(dot, "    n{} -> n{} [{}];", edge.from.node.0, edge.to.0, attributes.join(", ")).unwrap();
+    }
+
+    writeln!(dot, "}}").unwrap();
+    dot
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::RouterGraph;
+
+    #[test]
+    fn renders_nodes_edges_and_stats() {
+        let mut graph = RouterGraph::new();
+        let src = graph.add_source("src");
+        let classifier = graph.add_node("classifier", NodeKind::Classify, 1, 2);
+        graph.connect(src, classifier, 0);
+        let join = graph.add_join_link("join", vec![classifier.port(0), classifier.port(1)]);
+        let elem = graph.add_node("\"quoted\"", NodeKind::Sync, 1, 1);
+        graph.connect(join, elem, 0);
+        let drain = graph.add_node("drain", NodeKind::Sink, 1, 0);
+        graph.connect(elem, drain, 0);
+
+        let mut stats = HashMap::new();
+        stats.insert(classifier.port(1), EdgeStats { packets: Some(42), queue: Some((3, 10)) });
+
+        assert_eq!(graph.to_dot_with_stats(&stats), "\
+digraph router {
+    rankdir=LR;
+    n0 [label=\"src\", shape=invhouse];
+    n1 [label=\"classifier\", shape=diamond, style=filled];
+    n2 [label=\"join\", shape=invtrapezium];
+    n3 [label=\"\\\"quoted\\\"\", shape=box];
+    n4 [label=\"drain\", shape=house, style=filled];
+    n0 -> n1 [style=dashed];
+    n1 -> n2 [style=solid, taillabel=\"0\", headlabel=\"0\"];
+    n1 -> n2 [style=solid, taillabel=\"1\", headlabel=\"1\", label=\"42 pkts\\n3/10 queued\"];
+    n2 -> n3 [style=dashed];
+    n3 -> n4 [style=dashed];
+}
+");
+    }
+}
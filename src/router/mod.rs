@@ -0,0 +1,850 @@
+use futures::Stream;
+use futures::future::{Future, FutureExt};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::fmt;
+use tokio::runtime::Handle;
+use tokio::task::JoinHandle;
+use crate::api::{
+    Element, ElementLink, AsyncElement, AsyncElementLink, AsyncElementProvider,
+    FutureElement, FutureElementLink, FutureElementProvider, WorkerPoolLink, WorkerPoolProvider,
+    ShardedLink, JoinLink,
+    ClassifyElement, ClassifyElementLink, ClassifyElementProvider,
+    TeeLink, TeeProvider, LinkMetrics, LinkSnapshot, LinkLatency, LatencySnapshot,
+    HandlerTable, SharedHandlers, SharedState
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+mod registry;
+pub use self::registry::{
+    PacketType, AnyStream, Built, DynElement, Constructor, Registry,
+    SourceNode, SyncNode, AsyncNode, FutureNode, ClassifyNode, JoinNode, TeeNode, SinkNode, HandlersNode, StateNode
+};
+
+mod dot;
+pub use self::dot::EdgeStats;
+
+mod control;
+pub use self::control::{RouterControl, ControlClient, serve_control};
+
+mod shutdown;
+pub use self::shutdown::{ShutdownRequest, Valve};
+
+mod prometheus;
+pub use self::prometheus::{RouterMetrics, serve_prometheus};
+
+mod reconfigure;
+pub use self::reconfigure::{HotSwap, SwapInput, SwapOutput, Replacement, ReconfigureError};
+
+mod validate;
+pub use self::validate::{Problem, ValidationReport};
+
+/// A Task is a future the router has to spawn to keep the graph running,
+/// such as the consumer half of an AsyncElementLink, or a drain at the end
+/// of the graph.
+pub type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Identifies a node within the RouterGraph it was added to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+impl NodeId {
+    /// Refers to one of this node's output ports, for nodes with more than
+    /// one, such as classifiers and tees.
+    pub fn port(self, port: usize) -> OutputPort {
+        OutputPort { node: self, port }
+    }
+}
+
+/// An output port of a node, which is what the input of another node gets
+/// connected to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OutputPort {
+    pub node: NodeId,
+    pub port: usize
+}
+
+impl From<NodeId> for OutputPort {
+    fn from(node: NodeId) -> Self {
+        node.port(0)
+    }
+}
+
+/// What sort of link a node is built from. This decides whether the node
+/// has a task of its own, and whether its outputs are queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeKind {
+    /// Produces packets, like a packet generator or a network interface.
+    Source,
+    /// An ElementLink, run by whoever polls it.
+    Sync,
+    /// An AsyncElementLink, run by its consumer task.
+    Async,
+    /// A ClassifyElementLink, run by its consumer task.
+    Classify,
+    /// A JoinLink, run by whoever polls it.
+    Join,
+    /// A TeeLink, run by its consumer task.
+    Tee,
+    /// Consumes packets at the end of the graph, run by its own task.
+    Sink
+}
+
+impl NodeKind {
+    /// Whether nodes of this kind are driven by a task of their own, rather
+    /// than by whoever is downstream of them.
+    pub fn has_task(self) -> bool {
+        match self {
+            NodeKind::Async | NodeKind::Classify | NodeKind::Tee | NodeKind::Sink => true,
+            NodeKind::Source | NodeKind::Sync | NodeKind::Join => false
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Node {
+    pub name: String,
+    pub kind: NodeKind,
+    pub inputs: usize,
+    pub outputs: usize,
+    /// Type of packet taken on every input, if known.
+    pub input_type: Option<PacketType>,
+    /// Type of packet provided on every output, if known.
+    pub output_type: Option<PacketType>
+}
+
+/// The metrics of one output port of a node, at one point in time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PortSnapshot {
+    pub element: String,
+    pub port: usize,
+    pub link: LinkSnapshot
+}
+
+/// The latencies recorded by an instrumented element, at one point in time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LatencyReport {
+    pub element: String,
+    pub processing: LatencySnapshot,
+    /// Empty for links without a queue.
+    pub queueing: LatencySnapshot
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edge {
+    pub from: OutputPort,
+    pub to: NodeId,
+    pub to_port: usize
+}
+
+/// The RouterGraph keeps track of every element in a router, how they are
+/// connected, and every task that has to run to keep packets flowing. Links
+/// are still built and chained by hand, but handing them to the graph makes
+/// sure no consumer or drain is forgotten when it comes time to spawn.
+#[derive(Default)]
+pub struct RouterGraph {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+    tasks: Vec<(NodeId, Task)>,
+    metrics: Vec<(OutputPort, LinkMetrics)>,
+    latencies: Vec<(NodeId, LinkLatency)>,
+    handlers: HandlerTable,
+    states: Vec<(NodeId, SharedState)>,
+    /// The first element that failed to initialize, and why.
+    failed: Option<(NodeId, String)>,
+    shutdown: ShutdownRequest
+}
+
+impl RouterGraph {
+    pub fn new() -> Self {
+        RouterGraph::default()
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+
+    pub fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id.0]
+    }
+
+    /// Looks a node up by the name it was added with.
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        self.nodes.iter().position(|node| node.name == name).map(NodeId)
+    }
+
+    /// Adds a node with the given number of input and output ports. The
+    /// typed `add_*` methods below are usually more convenient.
+    pub fn add_node(&mut self, name: &str, kind: NodeKind, inputs: usize, outputs: usize) -> NodeId {
+        assert!(self.find(name).is_none(), "RouterGraph already has a node named {}", name);
+        self.nodes.push(Node {
+            name: name.to_string(),
+            kind,
+            inputs,
+            outputs,
+            input_type: None,
+            output_type: None
+        });
+        NodeId(self.nodes.len() - 1)
+    }
+
+    /// Hands the graph the metrics kept by the link behind the output port
+    /// `port`. The typed `add_*` methods do this for the links they are
+    /// given.
+    pub fn add_metrics(&mut self, port: impl Into<OutputPort>, metrics: LinkMetrics) {
+        self.metrics.push((port.into(), metrics));
+    }
+
+    /// Takes a snapshot of the metrics of every output port that has them,
+    /// in the order they were added.
+    pub fn snapshot(&self) -> Vec<PortSnapshot> {
+        self.metrics.iter().map(|(port, metrics)| PortSnapshot {
+            element: self.node(port.node).name.clone(),
+            port: port.port,
+            link: metrics.snapshot()
+        }).collect()
+    }
+
+    /// Hands the graph the latencies recorded by the instrumented link behind
+    /// `node`. `add_element_link` and `add_async_link` do this for
+    /// instrumented links.
+    pub fn add_latency(&mut self, node: NodeId, latency: LinkLatency) {
+        self.latencies.push((node, latency));
+    }
+
+    /// The latencies recorded by the element named `name`, if it is
+    /// instrumented, for querying arbitrary percentiles.
+    pub fn latency(&self, name: &str) -> Option<&LinkLatency> {
+        let node = self.find(name)?;
+        self.latencies.iter().find(|(id, _)| *id == node).map(|(_, latency)| latency)
+    }
+
+    /// Summarizes the latencies of every instrumented element.
+    pub fn latency_report(&self) -> Vec<LatencyReport> {
+        self.latencies.iter().map(|(node, latency)| LatencyReport {
+            element: self.node(*node).name.clone(),
+            processing: latency.processing.snapshot(),
+            queueing: latency.queueing.snapshot()
+        }).collect()
+    }
+
+    /// Handles on the metrics and latencies of every link in the graph, to
+    /// export them from, say, `serve_prometheus`.
+    pub fn metrics(&self) -> RouterMetrics {
+        RouterMetrics {
+            links: self.metrics.iter()
+                .map(|(port, metrics)| (*port, self.node(port.node).name.clone(), metrics.clone()))
+                .collect(),
+            latencies: self.latencies.iter()
+                .map(|(node, latency)| (self.node(*node).name.clone(), latency.clone()))
+                .collect()
+        }
+    }
+
+    /// Makes the handlers of the element behind `node` reachable through
+    /// `handlers`, under the node's name.
+    pub fn add_handlers(&mut self, node: NodeId, handlers: SharedHandlers) {
+        let name = self.node(node).name.clone();
+        self.handlers.add(&name, handlers);
+    }
+
+    /// Carries the state of the element behind `node` over to the element of
+    /// the same name when the graph is replaced by a HotSwap.
+    pub fn add_state(&mut self, node: NodeId, state: SharedState) {
+        self.states.push((node, state));
+    }
+
+    /// The handlers of every element that has them, to list, read and write
+    /// by `element.handler` path, before or after the graph is spawned.
+    pub fn handlers(&self) -> HandlerTable {
+        self.handlers.clone()
+    }
+
+    /// Everything needed to answer a control socket for this graph, which
+    /// stays usable once the graph is spawned.
+    pub fn control(&self) -> RouterControl {
+        RouterControl {
+            nodes: self.nodes.clone(),
+            edges: self.edges.clone(),
+            metrics: self.metrics(),
+            handlers: self.handlers(),
+            shutdown: self.shutdown.clone()
+        }
+    }
+
+    /// Shared with every RouterControl made from this graph, and with the
+    /// RouterHandle once it is spawned. Sources added by hand should be
+    /// wrapped in its `valve`, so that they stop when it is requested.
+    pub fn shutdown_request(&self) -> ShutdownRequest {
+        self.shutdown.clone()
+    }
+
+    /// Current packet counts and queue occupancy of every output port that
+    /// has metrics, for `to_dot_with_stats`.
+    pub fn edge_stats(&self) -> HashMap<OutputPort, EdgeStats> {
+        self.metrics().edge_stats()
+    }
+
+    /// Records the types of packet `node` takes and provides, so that
+    /// `validate` can check them against its neighbours.
+    pub fn set_packet_types(&mut self, node: NodeId, input_type: Option<PacketType>, output_type: Option<PacketType>) {
+        let node = &mut self.nodes[node.0];
+        node.input_type = input_type;
+        node.output_type = output_type;
+    }
+
+    /// Records that `to`'s input `to_port` pulls from the output port `from`.
+    pub fn connect(&mut self, from: impl Into<OutputPort>, to: NodeId, to_port: usize) {
+        let from = from.into();
+        assert!(from.port < self.node(from.node).outputs,
+            "{} has no output port {}", self.node(from.node).name, from.port);
+        assert!(to_port < self.node(to).inputs,
+            "{} has no input port {}", self.node(to).name, to_port);
+        self.edges.push(Edge { from, to, to_port });
+    }
+
+    /// Hands the graph a task that belongs to `node`, to be spawned along with
+    /// every other task in the graph.
+    pub fn add_task<F>(&mut self, node: NodeId, task: F)
+        where F: Future<Output = ()> + Send + 'static
+    {
+        self.tasks.push((node, Box::pin(task)));
+    }
+
+    pub fn add_source(&mut self, name: &str) -> NodeId {
+        self.add_node(name, NodeKind::Source, 0, 1)
+    }
+
+    /// Registers an ElementLink pulling from `input`. ElementLinks have no
+    /// task of their own, so the link is handed back, to be chained onwards.
+    /// As with `add_async_link`, the element is initialized straight away,
+    /// and if it fails to, the graph will refuse to spawn.
+    pub fn add_element_link<E, S>(&mut self, name: &str, input: impl Into<OutputPort>, mut link: ElementLink<E, S>)
+        -> (NodeId, ElementLink<E, S>)
+        where E: Element,
+              E::Input: 'static,
+              E::Output: 'static,
+              S: Stream<Item = E::Input> + Unpin
+    {
+        let node = self.add_node(name, NodeKind::Sync, 1, 1);
+        self.set_packet_types(node, Some(PacketType::of::<E::Input>()), Some(PacketType::of::<E::Output>()));
+        self.connect(input, node, 0);
+        self.add_metrics(node, link.metrics());
+        if let Some(latency) = link.latency() {
+            self.add_latency(node, latency);
+        }
+        if let Err(message) = link.initialize() {
+            self.failed.get_or_insert((node, message));
+        }
+        (node, link)
+    }
+
+    /// Registers an AsyncElementLink pulling from `input`. The graph takes
+    /// the consumer, and gives back the provider to be chained onwards. The
+    /// element is initialized straight away, and if it fails to, the graph
+    /// will refuse to spawn.
+    pub fn add_async_link<E, S>(&mut self, name: &str, input: impl Into<OutputPort>, mut link: AsyncElementLink<E, S>)
+        -> (NodeId, AsyncElementProvider<E>)
+        where E: AsyncElement + Send + 'static,
+              S: Stream<Item = E::Input> + Unpin + Send + 'static,
+              E::Input: Send + 'static,
+              E::Output: Send + 'static
+    {
+        let node = self.add_node(name, NodeKind::Async, 1, 1);
+        self.set_packet_types(node, Some(PacketType::of::<E::Input>()), Some(PacketType::of::<E::Output>()));
+        self.connect(input, node, 0);
+        self.add_metrics(node, link.consumer.metrics());
+        if let Some(latency) = link.consumer.latency() {
+            self.add_latency(node, latency);
+        }
+        if let Err(message) = link.initialize() {
+            self.failed.get_or_insert((node, message));
+        }
+        self.add_task(node, link.consumer);
+        (node, link.provider)
+    }
+
+    /// Registers a FutureElementLink pulling from `input`, as
+    /// `add_async_link` does.
+    pub fn add_future_link<E>(&mut self, name: &str, input: impl Into<OutputPort>, mut link: FutureElementLink<E>)
+        -> (NodeId, FutureElementProvider<E>)
+        where E: FutureElement + Send + 'static,
+              E::Input: Send + 'static,
+              E::Output: Send + 'static
+    {
+        let node = self.add_node(name, NodeKind::Async, 1, 1);
+        self.set_packet_types(node, Some(PacketType::of::<E::Input>()), Some(PacketType::of::<E::Output>()));
+        self.connect(input, node, 0);
+        self.add_metrics(node, link.consumer.metrics());
+        if let Err(message) = link.initialize() {
+            self.failed.get_or_insert((node, message));
+        }
+        self.add_task(node, link.consumer);
+        (node, link.provider)
+    }
+
+    /// Registers a WorkerPoolLink pulling from `input`. The graph takes the
+    /// dispatcher and every worker, and gives back the provider to be
+    /// chained onwards. Like `add_async_link`, it initializes the elements
+    /// straight away. The workers' queues would all share the one output
+    /// port, so their metrics are not recorded.
+    pub fn add_worker_pool<E>(&mut self, name: &str, input: impl Into<OutputPort>, mut link: WorkerPoolLink<E>)
+        -> (NodeId, WorkerPoolProvider<E>)
+        where E: AsyncElement + Send + 'static,
+              E::Input: Send + 'static,
+              E::Output: Send + 'static
+    {
+        let node = self.add_node(name, NodeKind::Async, 1, 1);
+        self.set_packet_types(node, Some(PacketType::of::<E::Input>()), Some(PacketType::of::<E::Output>()));
+        self.connect(input, node, 0);
+        if let Err(message) = link.initialize() {
+            self.failed.get_or_insert((node, message));
+        }
+        self.add_task(node, link.dispatcher);
+        for worker in link.workers {
+            self.add_task(node, worker);
+        }
+        (node, link.provider)
+    }
+
+    /// Registers a ShardedLink pulling from `input`. The graph takes the
+    /// dispatcher and every shard, and gives back the merged provider to be
+    /// chained onwards, as `add_worker_pool` does.
+    pub fn add_sharded_link<E>(&mut self, name: &str, input: impl Into<OutputPort>, mut link: ShardedLink<E>)
+        -> (NodeId, JoinLink<E::Output>)
+        where E: AsyncElement + Send + 'static,
+              E::Input: Send + 'static,
+              E::Output: Send + 'static
+    {
+        let node = self.add_node(name, NodeKind::Async, 1, 1);
+        self.set_packet_types(node, Some(PacketType::of::<E::Input>()), Some(PacketType::of::<E::Output>()));
+        self.connect(input, node, 0);
+        if let Err(message) = link.initialize() {
+            self.failed.get_or_insert((node, message));
+        }
+        self.add_task(node, link.dispatcher);
+        for shard in link.shards {
+            self.add_task(node, shard);
+        }
+        (node, link.provider)
+    }
+
+    /// Registers a ClassifyElementLink pulling from `input`. The graph takes
+    /// the consumer, and gives back one provider per output port.
+    pub fn add_classify_link<E>(&mut self, name: &str, input: impl Into<OutputPort>, link: ClassifyElementLink<E>)
+        -> (NodeId, Vec<ClassifyElementProvider<E>>)
+        where E: ClassifyElement + Send + 'static,
+              E::Packet: Send + 'static
+    {
+        let node = self.add_node(name, NodeKind::Classify, 1, link.providers.len());
+        self.set_packet_types(node, Some(PacketType::of::<E::Packet>()), Some(PacketType::of::<E::Packet>()));
+        self.connect(input, node, 0);
+        for port in 0..link.providers.len() {
+            self.add_metrics(node.port(port), link.consumer.metrics(port));
+        }
+        self.add_task(node, link.consumer);
+        (node, link.providers)
+    }
+
+    /// Registers a JoinLink pulling from each of `inputs`, in order.
+    pub fn add_join_link(&mut self, name: &str, inputs: Vec<OutputPort>) -> NodeId {
+        let node = self.add_node(name, NodeKind::Join, inputs.len(), 1);
+        for (to_port, input) in inputs.into_iter().enumerate() {
+            self.connect(input, node, to_port);
+        }
+        node
+    }
+
+    /// Registers a TeeLink pulling from `input`. The graph takes the
+    /// consumer, and gives back one provider per branch.
+    pub fn add_tee_link<P>(&mut self, name: &str, input: impl Into<OutputPort>, link: TeeLink<P>)
+        -> (NodeId, Vec<TeeProvider<P>>)
+        where P: Send + Sync + 'static
+    {
+        let node = self.add_node(name, NodeKind::Tee, 1, link.providers.len());
+        self.set_packet_types(node, Some(PacketType::of::<P>()), Some(PacketType::of::<Arc<P>>()));
+        self.connect(input, node, 0);
+        for branch in 0..link.providers.len() {
+            self.add_metrics(node.port(branch), link.consumer.metrics(branch));
+        }
+        self.add_task(node, link.consumer);
+        (node, link.providers)
+    }
+
+    /// Registers a sink pulling from `input`, such as a drain or a network
+    /// interface's transmit side, along with the task that runs it.
+    pub fn add_sink<F>(&mut self, name: &str, input: impl Into<OutputPort>, sink: F) -> NodeId
+        where F: Future<Output = ()> + Send + 'static
+    {
+        let node = self.add_node(name, NodeKind::Sink, 1, 0);
+        self.connect(input, node, 0);
+        self.add_task(node, sink);
+        node
+    }
+
+    /// Checks the graph for anything that would keep it from running
+    /// properly once spawned, such as dangling ports or nodes that nothing
+    /// drives. Every problem found is listed in the report.
+    pub fn validate(&self) -> Result<(), ValidationReport> {
+        validate::validate(self)
+    }
+
+    /// Renders the graph in Graphviz's DOT language, for `dot -Tsvg` and
+    /// friends.
+    pub fn to_dot(&self) -> String {
+        dot::render(&self.nodes, &self.edges, &HashMap::new())
+    }
+
+    /// Like `to_dot`, but labels the edges coming out of each output port in
+    /// `stats` with its packet count and queue occupancy.
+    pub fn to_dot_with_stats(&self, stats: &HashMap<OutputPort, EdgeStats>) -> String {
+        dot::render(&self.nodes, &self.edges, stats)
+    }
+
+    /// Spawns every task in the graph onto the runtime behind `handle`. The
+    /// returned RouterHandle resolves once every task has finished, that is,
+    /// once the whole graph has torn down. The graph is validated first, and
+    /// if it has any problems, or an element failed to initialize, nothing is
+    /// spawned, and the RouterHandle resolves to the error.
+    pub fn spawn(mut self, handle: &Handle) -> RouterHandle {
+        if let Err(report) = self.validate() {
+            self.tasks.clear();
+            return RouterHandle { graph: self, tasks: vec![], failed: Some(RouterError::Invalid(report)) }
+        }
+        if let Some((node, message)) = self.failed.take() {
+            let error = RouterError::InitializeFailed { element: self.node(node).name.clone(), message };
+            // Dropping the tasks tears down the elements that did initialize.
+            self.tasks.clear();
+            return RouterHandle { graph: self, tasks: vec![], failed: Some(error) }
+        }
+        let tasks = std::mem::take(&mut self.tasks).into_iter()
+            .map(|(node, task)| (self.node(node).name.clone(), handle.spawn(task)))
+            .collect();
+        RouterHandle { graph: self, tasks, failed: None }
+    }
+}
+
+#[derive(Debug)]
+pub enum RouterError {
+    /// The task belonging to the named node panicked or was cancelled.
+    TaskFailed(String),
+    /// The named nodes' tasks were still running when the drain timed out,
+    /// and were aborted.
+    DrainTimedOut(Vec<String>),
+    /// The named element refused to start, so the graph never did.
+    InitializeFailed { element: String, message: String },
+    /// The graph failed validation, so it was never started.
+    Invalid(ValidationReport)
+}
+
+impl fmt::Display for RouterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RouterError::TaskFailed(name) => write!(f, "task for {} failed", name),
+            RouterError::DrainTimedOut(names) => write!(f, "timed out draining {}", names.join(", ")),
+            RouterError::InitializeFailed { element, message } => write!(f, "{} failed to initialize: {}", element, message),
+            RouterError::Invalid(report) => report.fmt(f)
+        }
+    }
+}
+
+impl std::error::Error for RouterError {}
+
+/// The RouterHandle is a future over every task of a running RouterGraph.
+/// It resolves once they have all finished, or as soon as one of them fails.
+/// It keeps the rest of the graph around, so its metrics can be looked at
+/// while it runs.
+pub struct RouterHandle {
+    graph: RouterGraph,
+    tasks: Vec<(String, JoinHandle<()>)>,
+    failed: Option<RouterError>
+}
+
+impl RouterHandle {
+    /// The graph being run. Its tasks have been handed to the runtime.
+    pub fn graph(&self) -> &RouterGraph {
+        &self.graph
+    }
+
+    /// Stops the graph's sources, and waits up to `timeout` for the packets
+    /// already in it to drain out of every queue. Whatever is still running
+    /// by then is aborted.
+    pub async fn shutdown(mut self, timeout: Duration) -> Result<(), RouterError> {
+        self.graph.shutdown.request();
+        match tokio::time::timeout(timeout, &mut self).await {
+            Ok(result) => result,
+            Err(_) => {
+                let names = self.tasks.iter().map(|(name, _)| name.clone()).collect();
+                for (_, task) in self.tasks.drain(..) {
+                    task.abort();
+                    // An aborted task reports that it was cancelled, which is
+                    // what we asked for.
+                    let _ = task.await;
+                }
+                Err(RouterError::DrainTimedOut(names))
+            }
+        }
+    }
+
+    /// Runs the graph until it finishes on its own, or until shutdown is
+    /// requested through its ShutdownRequest, say over the control socket,
+    /// in which case it is shut down as by `shutdown`.
+    pub async fn run(mut self, drain_timeout: Duration) -> Result<(), RouterError> {
+        let shutdown = self.graph.shutdown.clone();
+        tokio::select! {
+            result = &mut self => result,
+            _ = shutdown.requested() => self.shutdown(drain_timeout).await
+        }
+    }
+}
+
+impl Future for RouterHandle {
+    type Output = Result<(), RouterError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Some(error) = self.failed.take() {
+            return Poll::Ready(Err(error))
+        }
+        let mut failed = None;
+        self.tasks.retain_mut(|(name, task)| {
+            match task.poll_unpin(cx) {
+                Poll::Pending => true,
+                Poll::Ready(Ok(())) => false,
+                Poll::Ready(Err(_)) => {
+                    failed.get_or_insert_with(|| name.clone());
+                    false
+                }
+            }
+        });
+
+        if let Some(name) = failed {
+            return Poll::Ready(Err(RouterError::TaskFailed(name)))
+        }
+        if self.tasks.is_empty() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{ElementLink, Element, Verdict, JoinLink};
+    use crate::utils::test::packet_generators::{immediate_stream, LinearIntervalGenerator};
+    use crate::utils::test::packet_collectors::{ExhaustiveDrain, ExhaustiveCollector};
+    use core::time;
+    use std::sync::{Arc, Mutex};
+
+    struct IdentityElement;
+
+    impl Element for IdentityElement {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            Verdict::Pass(packet)
+        }
+    }
+
+    impl AsyncElement for IdentityElement {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            Verdict::Pass(packet)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn series_sync_and_async_interval_yield() {
+        let default_channel_size = 10;
+        let packet_generator = LinearIntervalGenerator::new(time::Duration::from_millis(10), 20);
+
+        let mut router = RouterGraph::new();
+        let src = router.add_source("src");
+
+        let elem0_link = ElementLink::new(Box::pin(packet_generator), IdentityElement);
+        let (elem0, elem0_link) = router.add_element_link("elem0", src, elem0_link);
+
+        let elem1_link = AsyncElementLink::instrumented(Box::pin(elem0_link), IdentityElement, default_channel_size);
+        let (elem1, elem1_provider) = router.add_async_link("elem1", elem0, elem1_link);
+
+        let elem2_link = ElementLink::new(Box::pin(elem1_provider), IdentityElement);
+        let (elem2, elem2_link) = router.add_element_link("elem2", elem1, elem2_link);
+
+        let elem3_link = AsyncElementLink::new(Box::pin(elem2_link), IdentityElement, default_channel_size);
+        let (elem3, elem3_provider) = router.add_async_link("elem3", elem2, elem3_link);
+
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        router.add_sink("drain", elem3, ExhaustiveCollector::new(0, Box::pin(elem3_provider), Arc::clone(&packets)));
+
+        assert_eq!(router.nodes().len(), 6);
+        assert_eq!(router.edges().len(), 5);
+
+        let mut running = router.spawn(&Handle::current());
+        (&mut running).await.unwrap();
+
+        assert_eq!(*packets.lock().unwrap(), (0..=20).collect::<Vec<i32>>());
+
+        let report = running.graph().latency_report();
+        assert_eq!(report.len(), 1);
+        assert_eq!((report[0].element.as_str(), report[0].processing.count, report[0].queueing.count), ("elem1", 21, 21));
+        assert!(running.graph().latency("elem1").is_some());
+        assert!(running.graph().latency("elem3").is_none());
+    }
+
+    struct EvenOddClassifier;
+
+    impl ClassifyElement for EvenOddClassifier {
+        type Packet = i32;
+
+        fn classify(&mut self, packet: &Self::Packet) -> usize {
+            (packet % 2) as usize
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn classify_and_join_back_together() {
+        let default_channel_size = 10;
+
+        let mut router = RouterGraph::new();
+        let src = router.add_source("src");
+
+        let classify_link = ClassifyElementLink::new(immediate_stream(0..20), EvenOddClassifier, default_channel_size, 2);
+        let (classifier, mut providers) = router.add_classify_link("classifier", src, classify_link);
+        let odd_provider = providers.pop().unwrap();
+        let even_provider = providers.pop().unwrap();
+
+        let even_link = AsyncElementLink::new(Box::pin(even_provider), IdentityElement, default_channel_size);
+        let (even, even_provider) = router.add_async_link("even", classifier.port(0), even_link);
+        let odd_link = AsyncElementLink::new(Box::pin(odd_provider), IdentityElement, default_channel_size);
+        let (odd, odd_provider) = router.add_async_link("odd", classifier.port(1), odd_link);
+
+        let join = JoinLink::round_robin(vec![Box::pin(even_provider), Box::pin(odd_provider)]);
+        let join_node = router.add_join_link("join", vec![even.into(), odd.into()]);
+
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        router.add_sink("drain", join_node, ExhaustiveCollector::new(0, Box::pin(join), Arc::clone(&packets)));
+
+        let mut running = router.spawn(&Handle::current());
+        (&mut running).await.unwrap();
+
+        let mut packets = packets.lock().unwrap().clone();
+        packets.sort();
+        assert_eq!(packets, (0..20).collect::<Vec<i32>>());
+
+        let snapshot = running.graph().snapshot();
+        let counts = snapshot.iter()
+            .map(|port| (port.element.as_str(), port.port, port.link.packets_in, port.link.packets_out))
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![("classifier", 0, 10, 10), ("classifier", 1, 10, 10), ("even", 0, 10, 10), ("odd", 0, 10, 10)]);
+        assert_eq!(running.graph().edge_stats()[&even.into()], EdgeStats { packets: Some(10), queue: Some((0, 10)) });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failed_task_is_reported_by_name() {
+        let mut router = RouterGraph::new();
+        let src = router.add_source("src");
+        let broken_src = router.add_source("broken_src");
+        router.add_sink("drain", src, ExhaustiveDrain::new(0, immediate_stream(0..5)));
+        router.add_sink("broken", broken_src, async { panic!("broken sink") });
+
+        match router.spawn(&Handle::current()).await {
+            Err(RouterError::TaskFailed(name)) => assert_eq!(name, "broken"),
+            other => panic!("expected broken to fail, got {:?}", other)
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_graph_is_not_spawned() {
+        let mut router = RouterGraph::new();
+        let src = router.add_source("src");
+        let ran = Arc::new(Mutex::new(false));
+        let sink_ran = Arc::clone(&ran);
+        router.add_sink("drain", src, async move { *sink_ran.lock().unwrap() = true; });
+        router.add_source("dangling");
+
+        match router.spawn(&Handle::current()).await {
+            Err(RouterError::Invalid(report)) => {
+                assert_eq!(report.problems, vec![
+                    Problem::Unconnected { element: "dangling".to_string(), port: 0, output: true },
+                    Problem::Undriven("dangling".to_string())
+                ]);
+            },
+            other => panic!("expected the graph to be invalid, got {:?}", other)
+        }
+        assert!(!*ran.lock().unwrap());
+    }
+
+    struct Unplugged;
+
+    impl AsyncElement for Unplugged {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            Verdict::Pass(packet)
+        }
+
+        fn initialize(&mut self, _timers: &crate::api::Timers) -> Result<(), String> {
+            Err("no such device eth7".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn element_failing_to_initialize_stops_spawn() {
+        let mut router = RouterGraph::new();
+        let src = router.add_source("src");
+        let link = AsyncElementLink::new(immediate_stream(0..5), Unplugged, 10);
+        let (node, provider) = router.add_async_link("eth7", src, link);
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        router.add_sink("drain", node, ExhaustiveCollector::new(0, Box::pin(provider), Arc::clone(&packets)));
+
+        match router.spawn(&Handle::current()).await {
+            Err(RouterError::InitializeFailed { element, message }) => {
+                assert_eq!(element, "eth7");
+                assert_eq!(message, "no such device eth7");
+            },
+            other => panic!("expected eth7 to fail to initialize, got {:?}", other)
+        }
+        assert!(packets.lock().unwrap().is_empty());
+    }
+
+    impl Element for Unplugged {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            Verdict::Pass(packet)
+        }
+
+        fn initialize(&mut self, _timers: &crate::api::Timers) -> Result<(), String> {
+            Err("no such device eth8".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn sync_element_failing_to_initialize_stops_spawn() {
+        let mut router = RouterGraph::new();
+        let src = router.add_source("src");
+        let link = ElementLink::new(immediate_stream(0..5), Unplugged);
+        let (node, link) = router.add_element_link("eth8", src, link);
+        router.add_sink("drain", node, ExhaustiveDrain::new(0, Box::pin(link)));
+
+        match router.spawn(&Handle::current()).await {
+            Err(RouterError::InitializeFailed { element, message }) => {
+                assert_eq!(element, "eth8");
+                assert_eq!(message, "no such device eth8");
+            },
+            other => panic!("expected eth8 to fail to initialize, got {:?}", other)
+        }
+    }
+}
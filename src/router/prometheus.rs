@@ -0,0 +1,259 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use crate::api::{LinkMetrics, LinkLatency, LinkSnapshot, LatencySnapshot};
+use crate::router::{EdgeStats, OutputPort};
+
+/// Handles on the metrics and latencies of every link in a RouterGraph,
+/// along with the names of the elements they belong to. Cloning it is cheap,
+/// and it stays live after the graph is spawned, so it can be handed to
+/// whatever exports the metrics.
+#[derive(Clone, Debug, Default)]
+pub struct RouterMetrics {
+    pub(crate) links: Vec<(OutputPort, String, LinkMetrics)>,
+    pub(crate) latencies: Vec<(String, LinkLatency)>
+}
+
+/// Escapes a label value as the exposition format asks.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs_f64()
+}
+
+struct LinkMetric {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    /// None for links where the metric does not apply.
+    value: fn(&LinkSnapshot) -> Option<f64>
+}
+
+const LINK_METRICS: &[LinkMetric] = &[
+    LinkMetric {
+        name: "route_link_packets_in_total", kind: "counter",
+        help: "Packets taken in by the link.",
+        value: |link| Some(link.packets_in as f64)
+    },
+    LinkMetric {
+        name: "route_link_packets_out_total", kind: "counter",
+        help: "Packets handed on by the link.",
+        value: |link| Some(link.packets_out as f64)
+    },
+    LinkMetric {
+        name: "route_link_drops_total", kind: "counter",
+        help: "Packets dropped by the link.",
+        value: |link| Some(link.drops as f64)
+    },
+    LinkMetric {
+        name: "route_link_queue_depth", kind: "gauge",
+        help: "Packets currently in the link's queue.",
+        value: |link| link.queue_capacity.map(|_| link.queue_depth as f64)
+    },
+    LinkMetric {
+        name: "route_link_queue_high_water", kind: "gauge",
+        help: "Most packets ever in the link's queue at once.",
+        value: |link| link.queue_capacity.map(|_| link.queue_high_water as f64)
+    },
+    LinkMetric {
+        name: "route_link_queue_capacity", kind: "gauge",
+        help: "Packets the link's queue can hold.",
+        value: |link| link.queue_capacity.map(|capacity| capacity as f64)
+    },
+    LinkMetric {
+        name: "route_link_consumer_sleeps_total", kind: "counter",
+        help: "Times the consumer slept on a full queue.",
+        value: |link| link.queue_capacity.map(|_| link.consumer_sleeps as f64)
+    },
+    LinkMetric {
+        name: "route_link_provider_sleeps_total", kind: "counter",
+        help: "Times the provider slept on an empty queue.",
+        value: |link| link.queue_capacity.map(|_| link.provider_sleeps as f64)
+    },
+    LinkMetric {
+        name: "route_link_consumer_stalled_seconds_total", kind: "counter",
+        help: "Time the consumer spent asleep on a full queue.",
+        value: |link| link.queue_capacity.map(|_| seconds(link.consumer_stalled))
+    },
+    LinkMetric {
+        name: "route_link_provider_stalled_seconds_total", kind: "counter",
+        help: "Time the provider spent asleep on an empty queue.",
+        value: |link| link.queue_capacity.map(|_| seconds(link.provider_stalled))
+    }
+];
+
+fn write_summary(out: &mut String, name: &str, element: &str, latency: &LatencySnapshot) {
+    let element = escape(element);
+    for (quantile, value) in [("0.5", latency.p50), ("0.9", latency.p90), ("0.99", latency.p99), ("0.999", latency.p999)] {
+        writeln!(out, "{}{{element=\"{}\",quantile=\"{}\"}} {}", name, element, quantile, seconds(value)).unwrap();
+    }
+    writeln!(out, "{}_sum{{element=\"{}\"}} {}", name, element, seconds(latency.mean) * latency.count as f64).unwrap();
+    writeln!(out, "{}_count{{element=\"{}\"}} {}", name, element, latency.count).unwrap();
+}
+
+impl RouterMetrics {
+    /// Current packet counts and queue occupancy of every output port, for
+    /// `to_dot_with_stats`.
+    pub fn edge_stats(&self) -> HashMap<OutputPort, EdgeStats> {
+        self.links.iter().map(|(port, _, metrics)| {
+            let snapshot = metrics.snapshot();
+            let stats = EdgeStats {
+                packets: Some(snapshot.packets_out),
+                queue: snapshot.queue_capacity.map(|capacity| (snapshot.queue_depth, capacity))
+            };
+            (*port, stats)
+        }).collect()
+    }
+
+    /// Renders every metric in Prometheus' text exposition format. Link
+    /// metrics are labeled with the element and output port, latencies with
+    /// the element, and are exported as summaries.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let snapshots = self.links.iter()
+            .map(|(port, element, metrics)| (escape(element), port.port, metrics.snapshot()))
+            .collect::<Vec<_>>();
+
+        for metric in LINK_METRICS {
+            writeln!(out, "# HELP {} {}", metric.name, metric.help).unwrap();
+            writeln!(out, "# TYPE {} {}", metric.name, metric.kind).unwrap();
+            for (element, port, snapshot) in &snapshots {
+                if let Some(value) = (metric.value)(snapshot) {
+                    writeln!(out, "{}{{element=\"{}\",port=\"{}\"}} {}", metric.name, element, port, value).unwrap();
+                }
+            }
+        }
+
+        let latencies = self.latencies.iter()
+            .map(|(element, latency)| (element, latency.processing.snapshot(), latency.queueing.snapshot()))
+            .collect::<Vec<_>>();
+
+        writeln!(out, "# HELP route_element_processing_seconds Time taken by each call to the element's process.").unwrap();
+        writeln!(out, "# TYPE route_element_processing_seconds summary").unwrap();
+        for (element, processing, _) in &latencies {
+            write_summary(&mut out, "route_element_processing_seconds", element, processing);
+        }
+        writeln!(out, "# HELP route_element_queueing_seconds Time packets spent in the element's queue.").unwrap();
+        writeln!(out, "# TYPE route_element_queueing_seconds summary").unwrap();
+        for (element, _, queueing) in &latencies {
+            write_summary(&mut out, "route_element_queueing_seconds", element, queueing);
+        }
+        out
+    }
+}
+
+/// Requests longer than this are not worth reading to the end of.
+const MAX_REQUEST: usize = 8192;
+
+/// Answers a single HTTP request, with the metrics for `GET /metrics` and a
+/// 404 for anything else. We only need enough of HTTP for a scraper.
+async fn respond(mut stream: TcpStream, metrics: &RouterMetrics) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer).await?;
+        if read == 0 || request.len() > MAX_REQUEST {
+            return Ok(())
+        }
+        request.extend_from_slice(&buffer[..read]);
+    }
+
+    let request_line = String::from_utf8_lossy(&request);
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = metrics.to_prometheus();
+            format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(), body)
+        },
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Serves `metrics` at `/metrics` to every connection on `listener`, until
+/// accepting a connection fails. Each request is answered on its own task.
+pub async fn serve_prometheus(listener: TcpListener, metrics: RouterMetrics) -> io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            // A scraper hanging up early is its own problem, not ours.
+            let _ = respond(stream, &metrics).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{AsyncElement, AsyncElementLink, Verdict};
+    use crate::router::RouterGraph;
+    use crate::utils::test::packet_generators::immediate_stream;
+    use crate::utils::test::packet_collectors::ExhaustiveDrain;
+    use tokio::runtime::Handle;
+
+    struct DropOdd;
+
+    impl AsyncElement for DropOdd {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            if packet % 2 == 0 { Verdict::Pass(packet) } else { Verdict::Drop }
+        }
+    }
+
+    async fn run_router() -> RouterMetrics {
+        let mut router = RouterGraph::new();
+        let src = router.add_source("src");
+        let link = AsyncElementLink::instrumented(immediate_stream(0..10), DropOdd, 4);
+        let (filter, provider) = router.add_async_link("\"filter\"", src, link);
+        router.add_sink("drain", filter, ExhaustiveDrain::new(0, Box::pin(provider)));
+
+        let metrics = router.metrics();
+        router.spawn(&Handle::current()).await.unwrap();
+        metrics
+    }
+
+    #[tokio::test]
+    async fn renders_link_counters_and_latencies() {
+        let exposition = run_router().await.to_prometheus();
+        let lines = exposition.lines().collect::<Vec<&str>>();
+
+        assert!(lines.contains(&"# TYPE route_link_packets_in_total counter"));
+        assert!(lines.contains(&"route_link_packets_in_total{element=\"\\\"filter\\\"\",port=\"0\"} 5"));
+        assert!(lines.contains(&"route_link_drops_total{element=\"\\\"filter\\\"\",port=\"0\"} 5"));
+        assert!(lines.contains(&"route_link_queue_capacity{element=\"\\\"filter\\\"\",port=\"0\"} 4"));
+        assert!(lines.contains(&"route_element_processing_seconds_count{element=\"\\\"filter\\\"\"} 10"));
+        assert!(lines.contains(&"route_element_queueing_seconds_count{element=\"\\\"filter\\\"\"} 5"));
+        assert!(lines.iter().any(|line| line.starts_with("route_element_processing_seconds{element=\"\\\"filter\\\"\",quantile=\"0.99\"} ")));
+    }
+
+    #[tokio::test]
+    async fn serves_metrics_over_http() {
+        let metrics = run_router().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve_prometheus(listener, metrics));
+
+        let get = |path: &'static str| async move {
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        };
+
+        let response = get("/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n# HELP route_link_packets_in_total"));
+        assert!(get("/").await.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
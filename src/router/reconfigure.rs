@@ -0,0 +1,467 @@
+use futures::{Stream, ready};
+use std::collections::VecDeque;
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use crate::api::ElementStream;
+use crate::router::{RouterError, RouterGraph, RouterHandle, ValidationReport};
+
+/// Locks `mutex`, carrying on with whatever state a panicking task left
+/// behind, as the HandlerTable does.
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+struct InputState<I> {
+    stream: ElementStream<I>,
+    /// The generation of subgraph currently allowed to pull from the stream.
+    active: u64,
+    /// Tasks of generations waiting to be let in, or to find out they have
+    /// been retired.
+    wakers: Vec<(u64, Waker)>
+}
+
+/// The input stream of one generation of subgraph behind a HotSwap. Only the
+/// active generation pulls packets from the HotSwap's input, so each packet
+/// goes to exactly one generation.
+pub struct SwapInput<I> {
+    state: Arc<Mutex<InputState<I>>>,
+    finished: Arc<AtomicBool>,
+    generation: u64
+}
+
+impl<I> Stream for SwapInput<I> {
+    type Item = I;
+
+    /// Implement Poll for Stream for SwapInput
+    ///
+    /// There are three cases:
+    /// ###
+    /// #1 A later generation has been cut over to, or the HotSwap's input
+    /// has run dry. We return Ready(None), and our subgraph enters tear-down,
+    /// draining whatever it still holds out to the SwapOutput.
+    ///
+    /// #2 We have not been cut over to yet. We leave our waker to be woken
+    /// once we are, and sleep.
+    ///
+    /// #3 We are the active generation, and pull from the input. We leave our
+    /// waker too, as the input will only wake whichever task last polled it,
+    /// and we need to find out if we are retired while it is Pending.
+    /// ###
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let input = self.get_mut();
+        let mut state = lock(&input.state);
+        if input.finished.load(Ordering::SeqCst) || input.generation < state.active {
+            return Poll::Ready(None)
+        }
+
+        let generation = input.generation;
+        state.wakers.retain(|(waiting, _)| *waiting != generation);
+        state.wakers.push((generation, cx.waker().clone()));
+        if generation > state.active {
+            return Poll::Pending
+        }
+
+        let packet = ready!(state.stream.as_mut().poll_next(cx));
+        if packet.is_none() {
+            input.finished.store(true, Ordering::SeqCst);
+        }
+        Poll::Ready(packet)
+    }
+}
+
+struct OutputState<O> {
+    /// The output streams of every generation that has not finished
+    /// draining, oldest first.
+    streams: VecDeque<ElementStream<O>>,
+    waker: Option<Waker>
+}
+
+/// The output of a HotSwap, to be handed to whatever comes after it. It
+/// provides every packet from one generation of subgraph before moving on
+/// to the next, so packets leave in the order they came in, even while an
+/// old generation drains.
+pub struct SwapOutput<O> {
+    state: Arc<Mutex<OutputState<O>>>,
+    finished: Arc<AtomicBool>
+}
+
+impl<O> Stream for SwapOutput<O> {
+    type Item = O;
+
+    /// Implement Poll for Stream for SwapOutput
+    ///
+    /// We poll the oldest generation's output. Once it returns Ready(None)
+    /// it has drained, and we move on to the next. With no generation left,
+    /// we return Ready(None) if the HotSwap's input has run dry, or sleep
+    /// until a generation is cut over to.
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let output = self.get_mut();
+        let mut state = lock(&output.state);
+        loop {
+            match state.streams.front_mut() {
+                Some(stream) => match stream.as_mut().poll_next(cx) {
+                    Poll::Ready(None) => {
+                        state.streams.pop_front();
+                    },
+                    poll => return poll
+                },
+                None if output.finished.load(Ordering::SeqCst) => return Poll::Ready(None),
+                None => {
+                    state.waker = Some(cx.waker().clone());
+                    return Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReconfigureError {
+    /// The replacement could not be built, say because its configuration
+    /// does not parse.
+    Build(String),
+    /// The replacement was built, but does not validate.
+    Invalid(ValidationReport),
+    /// The named element of the replacement failed to initialize.
+    Initialize { element: String, message: String },
+    /// A newer replacement has already been cut over to.
+    Superseded,
+    /// The named element of the replacement would not take the state saved
+    /// from the element it replaces.
+    State { element: String, message: String },
+    /// The replacement is running, but the generation it replaced did not
+    /// drain in time, and was aborted.
+    Drain(RouterError)
+}
+
+impl fmt::Display for ReconfigureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReconfigureError::Build(message) => write!(f, "could not build replacement: {}", message),
+            ReconfigureError::Invalid(report) => write!(f, "replacement does not validate: {}", report),
+            ReconfigureError::Initialize { element, message } =>
+                write!(f, "{} of the replacement failed to initialize: {}", element, message),
+            ReconfigureError::Superseded => write!(f, "a newer replacement is already running"),
+            ReconfigureError::State { element, message } =>
+                write!(f, "could not carry state over to {}: {}", element, message),
+            ReconfigureError::Drain(err) => write!(f, "replaced, but the old graph did not drain: {}", err)
+        }
+    }
+}
+
+impl std::error::Error for ReconfigureError {}
+
+/// A subgraph that has been built and validated, ready to be cut over to.
+pub struct Replacement<O> {
+    graph: RouterGraph,
+    output: ElementStream<O>,
+    generation: u64
+}
+
+impl<O> Replacement<O> {
+    pub fn graph(&self) -> &RouterGraph {
+        &self.graph
+    }
+}
+
+/// A HotSwap stands in for a subgraph of a running router, which can be
+/// replaced without stopping the router or losing packets. Packets from its
+/// input are fed to the running generation of subgraph, and leave through
+/// the SwapOutput.
+///
+/// Replacing the subgraph takes two steps. `prepare` builds and validates
+/// the replacement, and checks that its elements initialized, leaving the
+/// running subgraph untouched if any of that fails.
+/// `cut_over` then carries state over from the running subgraph's elements,
+/// starts the replacement, and switches the input over to it in one go. The
+/// old subgraph sees its input end, and drains into the SwapOutput, which
+/// only moves on to the replacement's packets once it has.
+pub struct HotSwap<I, O> {
+    input: Arc<Mutex<InputState<I>>>,
+    output: Arc<Mutex<OutputState<O>>>,
+    finished: Arc<AtomicBool>,
+    /// The generation handed to the last replacement prepared.
+    generation: u64,
+    running: Option<RouterHandle>
+}
+
+impl<I: Send + 'static, O> HotSwap<I, O> {
+    /// Makes a HotSwap taking packets from `input`, along with its output.
+    /// Nothing flows until the first subgraph is cut over to.
+    pub fn new(input: ElementStream<I>) -> (Self, SwapOutput<O>) {
+        let finished = Arc::new(AtomicBool::new(false));
+        let output = Arc::new(Mutex::new(OutputState { streams: VecDeque::new(), waker: None }));
+        let hot_swap = HotSwap {
+            input: Arc::new(Mutex::new(InputState { stream: input, active: 0, wakers: vec![] })),
+            output: Arc::clone(&output),
+            finished: Arc::clone(&finished),
+            generation: 0,
+            running: None
+        };
+        (hot_swap, SwapOutput { state: output, finished })
+    }
+
+    /// Builds a replacement subgraph with `build`, which is handed the
+    /// replacement's input stream, and returns its graph along with its
+    /// output stream, say by way of `config::build_subgraph`. The graph must
+    /// validate, and all of its elements must have initialized.
+    pub fn prepare<F, E>(&mut self, build: F) -> Result<Replacement<O>, ReconfigureError>
+        where F: FnOnce(ElementStream<I>) -> Result<(RouterGraph, ElementStream<O>), E>,
+              E: fmt::Display
+    {
+        self.generation += 1;
+        let input = SwapInput {
+            state: Arc::clone(&self.input),
+            finished: Arc::clone(&self.finished),
+            generation: self.generation
+        };
+        let (graph, output) = build(Box::pin(input)).map_err(|err| ReconfigureError::Build(err.to_string()))?;
+        graph.validate().map_err(ReconfigureError::Invalid)?;
+        // Spawning a graph whose elements failed to initialize starts none of
+        // its tasks, so cutting over to it would leave the input undrained.
+        if let Some((node, message)) = &graph.failed {
+            return Err(ReconfigureError::Initialize { element: graph.node(*node).name.clone(), message: message.clone() })
+        }
+        Ok(Replacement { graph, output, generation: self.generation })
+    }
+
+    /// Swaps the running subgraph for `replacement`, spawning its tasks onto
+    /// the runtime behind `handle`, and waits up to `drain_timeout` for the
+    /// old subgraph to drain. If the state can not be carried over, nothing
+    /// changes.
+    pub async fn cut_over(&mut self, replacement: Replacement<O>, handle: &Handle, drain_timeout: Duration)
+        -> Result<(), ReconfigureError>
+    {
+        let Replacement { graph, output, generation } = replacement;
+        if generation < lock(&self.input).active {
+            return Err(ReconfigureError::Superseded)
+        }
+        if let Some(running) = &self.running {
+            transfer_state(running.graph(), &graph)?;
+        }
+        let replacement = graph.spawn(handle);
+
+        {
+            let mut output_state = lock(&self.output);
+            output_state.streams.push_back(output);
+            if let Some(waker) = output_state.waker.take() {
+                waker.wake();
+            }
+        }
+        {
+            let mut input_state = lock(&self.input);
+            input_state.active = generation;
+            for (_, waker) in input_state.wakers.drain(..) {
+                waker.wake();
+            }
+        }
+
+        match self.running.replace(replacement) {
+            Some(old) => old.shutdown(drain_timeout).await.map_err(ReconfigureError::Drain),
+            None => Ok(())
+        }
+    }
+
+    /// The graph of the running subgraph, if one has been cut over to.
+    pub fn running(&self) -> Option<&RouterGraph> {
+        self.running.as_ref().map(RouterHandle::graph)
+    }
+
+    /// Waits for the running subgraph to finish, once the HotSwap's input
+    /// has run dry.
+    pub async fn join(self) -> Result<(), RouterError> {
+        match self.running {
+            Some(running) => running.await,
+            None => Ok(())
+        }
+    }
+}
+
+/// Hands every element of `to` that carries state over the state saved from
+/// the element of the same name in `from`, if there is one.
+fn transfer_state(from: &RouterGraph, to: &RouterGraph) -> Result<(), ReconfigureError> {
+    for (node, state) in &to.states {
+        let name = &to.node(*node).name;
+        let old = from.states.iter().find(|(old, _)| from.node(*old).name == *name);
+        if let Some((_, old)) = old {
+            let saved = lock(old).save_state();
+            lock(state).restore_state(saved)
+                .map_err(|message| ReconfigureError::State { element: name.clone(), message })?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{
+        Element, ElementLink, Verdict, Timers, Handlers, HandlerInfo, TransferState, Shared, Schema, ArgType
+    };
+    use crate::config::build_subgraph;
+    use crate::router::{NodeKind, Registry, SyncNode, HandlersNode, StateNode};
+    use crate::utils::test::packet_collectors::ExhaustiveCollector;
+    use futures::channel::mpsc;
+    use std::any::Any;
+
+    struct Offset(i32);
+
+    impl Element for Offset {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            Verdict::Pass(packet + self.0)
+        }
+    }
+
+    struct Count(u64);
+
+    impl Element for Count {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            self.0 += 1;
+            Verdict::Pass(packet)
+        }
+    }
+
+    impl Handlers for Count {
+        fn handlers(&self) -> Vec<HandlerInfo> {
+            vec![HandlerInfo::read("count")]
+        }
+
+        fn read_handler(&self, _name: &str) -> Result<String, String> {
+            Ok(self.0.to_string())
+        }
+    }
+
+    impl TransferState for Count {
+        fn save_state(&self) -> Box<dyn Any + Send> {
+            Box::new(self.0)
+        }
+
+        fn restore_state(&mut self, state: Box<dyn Any + Send>) -> Result<(), String> {
+            self.0 = *state.downcast::<u64>().map_err(|_| "expected a count".to_string())?;
+            Ok(())
+        }
+    }
+
+    /// Fails to initialize, as an element bound to a missing device would.
+    struct Unplugged;
+
+    impl Element for Unplugged {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            Verdict::Pass(packet)
+        }
+
+        fn initialize(&mut self, _timers: &Timers) -> Result<(), String> {
+            Err("no such device eth8".to_string())
+        }
+    }
+
+    fn registry() -> Registry {
+        let mut registry = Registry::new();
+        registry.register_element("Offset", Schema::new().required("N", ArgType::Int), |args| Ok(Offset(args.int("N") as i32)));
+        registry.register("Count", Schema::new(), |_| {
+            let count = Shared::new(Count(0));
+            let node = StateNode::new(SyncNode::new(count.clone()), count.state());
+            Ok(Box::new(HandlersNode::new(node, count.handlers())))
+        });
+        registry
+    }
+
+    async fn wait_for(packets: &Arc<Mutex<Vec<i32>>>, len: usize) {
+        while packets.lock().unwrap().len() < len {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn swaps_subgraphs_without_losing_packets() {
+        let registry = registry();
+        let (sender, receiver) = mpsc::unbounded::<i32>();
+        let (mut hot_swap, output) = HotSwap::<i32, i32>::new(Box::pin(receiver));
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        let collector = tokio::spawn(ExhaustiveCollector::new(0, Box::pin(output), Arc::clone(&packets)));
+
+        let first = hot_swap.prepare(|input| build_subgraph("Input -> counter :: Count -> Output;", &registry, input)).unwrap();
+        hot_swap.cut_over(first, &Handle::current(), Duration::from_secs(1)).await.unwrap();
+        for packet in 0..10 {
+            sender.unbounded_send(packet).unwrap();
+        }
+        wait_for(&packets, 10).await;
+
+        // Neither of these touch the running subgraph.
+        match hot_swap.prepare(|input| build_subgraph::<i32, i32>("Input -> Frobnicate -> Output;", &registry, input)) {
+            Err(ReconfigureError::Build(message)) => assert_eq!(message, "1:10: unknown element class Frobnicate"),
+            _ => panic!("expected the replacement not to build")
+        }
+        let invalid = hot_swap.prepare(|input| {
+            let mut graph = RouterGraph::new();
+            graph.add_node("lonely", NodeKind::Sync, 1, 1);
+            Ok::<_, String>((graph, input))
+        });
+        assert!(matches!(invalid, Err(ReconfigureError::Invalid(_))));
+
+        let second = hot_swap.prepare(|input| {
+            build_subgraph("Input -> counter :: Count -> Offset(1000) -> Output;", &registry, input)
+        }).unwrap();
+        hot_swap.cut_over(second, &Handle::current(), Duration::from_secs(1)).await.unwrap();
+        for packet in 10..20 {
+            sender.unbounded_send(packet).unwrap();
+        }
+        drop(sender);
+        collector.await.unwrap();
+
+        let mut expected = (0..10).collect::<Vec<i32>>();
+        expected.extend(1010..1020);
+        assert_eq!(*packets.lock().unwrap(), expected);
+        assert_eq!(hot_swap.running().unwrap().handlers().read("counter.count"), Ok("20".to_string()));
+        hot_swap.join().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn keeps_running_subgraph_when_replacement_fails_to_initialize() {
+        let registry = registry();
+        let (sender, receiver) = mpsc::unbounded::<i32>();
+        let (mut hot_swap, output) = HotSwap::<i32, i32>::new(Box::pin(receiver));
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        let collector = tokio::spawn(ExhaustiveCollector::new(0, Box::pin(output), Arc::clone(&packets)));
+
+        let first = hot_swap.prepare(|input| build_subgraph("Input -> Offset(100) -> Output;", &registry, input)).unwrap();
+        hot_swap.cut_over(first, &Handle::current(), Duration::from_secs(1)).await.unwrap();
+
+        let unplugged = hot_swap.prepare(|input| {
+            let mut graph = RouterGraph::new();
+            let source = graph.add_source("Input");
+            let (nic, link) = graph.add_element_link("nic", source, ElementLink::new(input, Unplugged));
+            graph.add_sink("Output", nic, async {});
+            Ok::<_, String>((graph, Box::pin(link) as ElementStream<i32>))
+        });
+        match unplugged {
+            Err(ReconfigureError::Initialize { element, message }) => {
+                assert_eq!(element, "nic");
+                assert_eq!(message, "no such device eth8");
+            },
+            _ => panic!("expected the replacement not to initialize")
+        }
+        for packet in 0..10 {
+            sender.unbounded_send(packet).unwrap();
+        }
+        drop(sender);
+        collector.await.unwrap();
+
+        assert_eq!(*packets.lock().unwrap(), (100..110).collect::<Vec<i32>>());
+        hot_swap.join().await.unwrap();
+    }
+}
@@ -0,0 +1,624 @@
+use std::any::{Any, TypeId, type_name};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::api::{
+    ElementStream, Element, ElementLink, AsyncElement, AsyncElementLink, FutureElement, FutureElementLink,
+    ClassifyElement, ClassifyElementLink, JoinLink, JoinScheduler, RoundRobin, TeeLink, TeePolicy,
+    OverflowPolicy, Args, Schema, LinkMetrics, LinkLatency, SharedHandlers, SharedState
+};
+use crate::router::{NodeKind, ShutdownRequest, Task};
+
+/// The type of packet flowing over a port, compared at runtime when
+/// elements are connected without their types being known at compile time.
+#[derive(Clone, Copy, Debug, Eq)]
+pub struct PacketType {
+    id: TypeId,
+    name: &'static str
+}
+
+impl PacketType {
+    pub fn of<T: 'static>() -> Self {
+        PacketType { id: TypeId::of::<T>(), name: type_name::<T>() }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+impl PartialEq for PacketType {
+    fn eq(&self, other: &PacketType) -> bool {
+        self.id == other.id
+    }
+}
+
+impl fmt::Display for PacketType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// An ElementStream whose packet type has been erased, so that links built
+/// at runtime, say from a configuration file, can be handed to each other
+/// without the caller knowing their types.
+pub struct AnyStream {
+    type_name: &'static str,
+    stream: Box<dyn Any + Send>
+}
+
+impl AnyStream {
+    pub fn new<T: 'static>(stream: ElementStream<T>) -> Self {
+        AnyStream {
+            type_name: type_name::<T>(),
+            stream: Box::new(stream)
+        }
+    }
+
+    /// Name of the type of packet this stream yields.
+    pub fn type_name(&self) -> &'static str {
+        self.type_name
+    }
+
+    /// Gets the typed stream back, or hands the AnyStream back if it does
+    /// not yield packets of type `T`.
+    pub fn downcast<T: 'static>(self) -> Result<ElementStream<T>, AnyStream> {
+        let type_name = self.type_name;
+        match self.stream.downcast::<ElementStream<T>>() {
+            Ok(stream) => Ok(*stream),
+            Err(stream) => Err(AnyStream { type_name, stream })
+        }
+    }
+}
+
+impl fmt::Debug for AnyStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AnyStream<{}>", self.type_name)
+    }
+}
+
+/// Downcasts the input at `port`, with an error message naming both types
+/// if it does not carry the packets we expect.
+fn downcast_input<T: 'static>(input: AnyStream, port: usize) -> Result<ElementStream<T>, String> {
+    input.downcast::<T>().map_err(|input| {
+        format!("input {} expects packets of type {}, but is connected to a stream of {}",
+            port, type_name::<T>(), input.type_name())
+    })
+}
+
+fn single_input<T: 'static>(mut inputs: Vec<AnyStream>) -> Result<ElementStream<T>, String> {
+    match inputs.len() {
+        1 => downcast_input(inputs.remove(0), 0),
+        n => Err(format!("expected exactly one input, got {}", n))
+    }
+}
+
+/// What a DynElement turns into once its inputs are connected: the streams
+/// on each of its output ports, and whatever tasks have to be spawned to
+/// drive it. `metrics` holds the LinkMetrics of each output port, or is left
+/// empty if the element keeps none, `latency` is set if the element's link
+/// is instrumented, `handlers` if the element has any, and `state` if it
+/// carries state over when reconfigured.
+#[derive(Default)]
+pub struct Built {
+    pub outputs: Vec<AnyStream>,
+    pub tasks: Vec<Task>,
+    pub metrics: Vec<LinkMetrics>,
+    pub latency: Option<LinkLatency>,
+    pub handlers: Option<SharedHandlers>,
+    pub state: Option<SharedState>
+}
+
+/// A DynElement is an element, along with the link it should be wrapped in,
+/// that can be wired up without knowing its packet types at compile time.
+/// This is what the constructors in a Registry produce.
+pub trait DynElement: Send {
+    fn kind(&self) -> NodeKind;
+
+    /// Number of input ports, or None if it takes as many as are connected.
+    fn inputs(&self) -> Option<usize>;
+
+    /// Number of output ports, or None if it has as many as are connected.
+    fn outputs(&self) -> Option<usize>;
+
+    /// Type of packet taken on every input port, or None if it has none.
+    fn input_type(&self) -> Option<PacketType>;
+
+    /// Type of packet provided on every output port, or None if it has none.
+    fn output_type(&self) -> Option<PacketType>;
+
+    /// Called before `build` with the router's ShutdownRequest. Sources stop
+    /// producing packets once it is requested, everything else can ignore it.
+    fn stop_on(&mut self, _shutdown: &ShutdownRequest) {}
+
+    /// Wraps the element in its link, pulling from `inputs`, one per input
+    /// port, and providing `num_outputs` output streams.
+    fn build(self: Box<Self>, inputs: Vec<AnyStream>, num_outputs: usize) -> Result<Built, String>;
+}
+
+/// A source of packets, such as a packet generator or a network interface.
+pub struct SourceNode<T> {
+    stream: ElementStream<T>,
+    shutdown: Option<ShutdownRequest>
+}
+
+impl<T> SourceNode<T> {
+    pub fn new(stream: ElementStream<T>) -> Self {
+        SourceNode { stream, shutdown: None }
+    }
+}
+
+impl<T: 'static> DynElement for SourceNode<T> {
+    fn kind(&self) -> NodeKind { NodeKind::Source }
+    fn inputs(&self) -> Option<usize> { Some(0) }
+    fn outputs(&self) -> Option<usize> { Some(1) }
+    fn input_type(&self) -> Option<PacketType> { None }
+    fn output_type(&self) -> Option<PacketType> { Some(PacketType::of::<T>()) }
+
+    fn stop_on(&mut self, shutdown: &ShutdownRequest) {
+        self.shutdown = Some(shutdown.clone());
+    }
+
+    fn build(self: Box<Self>, _inputs: Vec<AnyStream>, _num_outputs: usize) -> Result<Built, String> {
+        let stream: ElementStream<T> = match self.shutdown {
+            Some(shutdown) => Box::pin(shutdown.valve(self.stream)),
+            None => self.stream
+        };
+        Ok(Built {
+            outputs: vec![AnyStream::new(stream)],
+            tasks: vec![],
+            metrics: vec![],
+            latency: None,
+            handlers: None,
+            state: None
+        })
+    }
+}
+
+/// An Element, to be wrapped in an ElementLink.
+pub struct SyncNode<E: Element> {
+    element: E,
+    instrumented: bool
+}
+
+impl<E: Element> SyncNode<E> {
+    pub fn new(element: E) -> Self {
+        SyncNode { element, instrumented: false }
+    }
+
+    /// Builds an instrumented ElementLink, recording processing latency.
+    pub fn instrumented(self) -> Self {
+        SyncNode { instrumented: true, ..self }
+    }
+}
+
+impl<E> DynElement for SyncNode<E>
+    where E: Element + Send + 'static,
+          E::Input: Send + 'static,
+          E::Output: Send + 'static
+{
+    fn kind(&self) -> NodeKind { NodeKind::Sync }
+    fn inputs(&self) -> Option<usize> { Some(1) }
+    fn outputs(&self) -> Option<usize> { Some(1) }
+    fn input_type(&self) -> Option<PacketType> { Some(PacketType::of::<E::Input>()) }
+    fn output_type(&self) -> Option<PacketType> { Some(PacketType::of::<E::Output>()) }
+
+    fn build(self: Box<Self>, inputs: Vec<AnyStream>, _num_outputs: usize) -> Result<Built, String> {
+        let input_stream = single_input::<E::Input>(inputs)?;
+        let mut link = if self.instrumented {
+            ElementLink::instrumented(input_stream, self.element)
+        } else {
+            ElementLink::new(input_stream, self.element)
+        };
+        link.initialize()?;
+        let metrics = vec![link.metrics()];
+        let latency = link.latency();
+        Ok(Built {
+            outputs: vec![AnyStream::new::<E::Output>(Box::pin(link))],
+            tasks: vec![],
+            metrics,
+            latency,
+            handlers: None,
+            state: None
+        })
+    }
+}
+
+/// An AsyncElement, to be wrapped in an AsyncElementLink.
+pub struct AsyncNode<E: AsyncElement> {
+    element: E,
+    queue_capacity: usize,
+    instrumented: bool,
+    overflow: OverflowPolicy
+}
+
+impl<E: AsyncElement> AsyncNode<E> {
+    pub fn new(element: E, queue_capacity: usize) -> Self {
+        AsyncNode { element, queue_capacity, instrumented: false, overflow: OverflowPolicy::Block }
+    }
+
+    /// Builds an AsyncElementLink that follows `policy` when its queue is
+    /// full.
+    pub fn overflow(self, policy: OverflowPolicy) -> Self {
+        AsyncNode { overflow: policy, ..self }
+    }
+
+    /// Builds an instrumented AsyncElementLink, recording processing and
+    /// queueing latency.
+    pub fn instrumented(self) -> Self {
+        AsyncNode { instrumented: true, ..self }
+    }
+}
+
+impl<E> DynElement for AsyncNode<E>
+    where E: AsyncElement + Send + 'static,
+          E::Input: Send + 'static,
+          E::Output: Send + 'static
+{
+    fn kind(&self) -> NodeKind { NodeKind::Async }
+    fn inputs(&self) -> Option<usize> { Some(1) }
+    fn outputs(&self) -> Option<usize> { Some(1) }
+    fn input_type(&self) -> Option<PacketType> { Some(PacketType::of::<E::Input>()) }
+    fn output_type(&self) -> Option<PacketType> { Some(PacketType::of::<E::Output>()) }
+
+    fn build(self: Box<Self>, inputs: Vec<AnyStream>, _num_outputs: usize) -> Result<Built, String> {
+        let input_stream = single_input::<E::Input>(inputs)?;
+        let mut link = if self.instrumented {
+            AsyncElementLink::instrumented(input_stream, self.element, self.queue_capacity)
+        } else {
+            AsyncElementLink::new(input_stream, self.element, self.queue_capacity)
+        }.overflow(self.overflow);
+        link.initialize()?;
+        Ok(Built {
+            metrics: vec![link.consumer.metrics()],
+            latency: link.consumer.latency(),
+            handlers: None,
+            state: None,
+            outputs: vec![AnyStream::new::<E::Output>(Box::pin(link.provider))],
+            tasks: vec![Box::pin(link.consumer)]
+        })
+    }
+}
+
+/// A FutureElement, to be wrapped in a FutureElementLink.
+pub struct FutureNode<E: FutureElement> {
+    element: E,
+    queue_capacity: usize,
+    concurrency: usize,
+    unordered: bool,
+    timeout: Option<Duration>
+}
+
+impl<E: FutureElement> FutureNode<E> {
+    pub fn new(element: E, queue_capacity: usize, concurrency: usize) -> Self {
+        FutureNode { element, queue_capacity, concurrency, unordered: false, timeout: None }
+    }
+
+    /// Builds a FutureElementLink that hands on each packet as soon as it
+    /// has been processed.
+    pub fn unordered(self) -> Self {
+        FutureNode { unordered: true, ..self }
+    }
+
+    /// Builds a FutureElementLink that drops packets taking longer than
+    /// `timeout` to process.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        FutureNode { timeout: Some(timeout), ..self }
+    }
+}
+
+impl<E> DynElement for FutureNode<E>
+    where E: FutureElement + Send + 'static,
+          E::Input: Send + 'static,
+          E::Output: Send + 'static
+{
+    fn kind(&self) -> NodeKind { NodeKind::Async }
+    fn inputs(&self) -> Option<usize> { Some(1) }
+    fn outputs(&self) -> Option<usize> { Some(1) }
+    fn input_type(&self) -> Option<PacketType> { Some(PacketType::of::<E::Input>()) }
+    fn output_type(&self) -> Option<PacketType> { Some(PacketType::of::<E::Output>()) }
+
+    fn build(self: Box<Self>, inputs: Vec<AnyStream>, _num_outputs: usize) -> Result<Built, String> {
+        let input_stream = single_input::<E::Input>(inputs)?;
+        let mut link = FutureElementLink::new(input_stream, self.element, self.queue_capacity, self.concurrency);
+        if self.unordered {
+            link = link.unordered();
+        }
+        if let Some(timeout) = self.timeout {
+            link = link.timeout(timeout);
+        }
+        link.initialize()?;
+        Ok(Built {
+            metrics: vec![link.consumer.metrics()],
+            latency: None,
+            handlers: None,
+            state: None,
+            outputs: vec![AnyStream::new::<E::Output>(Box::pin(link.provider))],
+            tasks: vec![Box::pin(link.consumer)]
+        })
+    }
+}
+
+/// A ClassifyElement, to be wrapped in a ClassifyElementLink.
+pub struct ClassifyNode<E: ClassifyElement> {
+    element: E,
+    queue_capacity: usize,
+    num_ports: usize
+}
+
+impl<E: ClassifyElement> ClassifyNode<E> {
+    pub fn new(element: E, queue_capacity: usize, num_ports: usize) -> Self {
+        ClassifyNode { element, queue_capacity, num_ports }
+    }
+}
+
+impl<E> DynElement for ClassifyNode<E>
+    where E: ClassifyElement + Send + 'static,
+          E::Packet: Send + 'static
+{
+    fn kind(&self) -> NodeKind { NodeKind::Classify }
+    fn inputs(&self) -> Option<usize> { Some(1) }
+    fn outputs(&self) -> Option<usize> { Some(self.num_ports) }
+    fn input_type(&self) -> Option<PacketType> { Some(PacketType::of::<E::Packet>()) }
+    fn output_type(&self) -> Option<PacketType> { Some(PacketType::of::<E::Packet>()) }
+
+    fn build(self: Box<Self>, inputs: Vec<AnyStream>, _num_outputs: usize) -> Result<Built, String> {
+        let link = ClassifyElementLink::new(single_input::<E::Packet>(inputs)?, self.element, self.queue_capacity, self.num_ports);
+        Ok(Built {
+            metrics: (0..self.num_ports).map(|port| link.consumer.metrics(port)).collect(),
+            outputs: link.providers.into_iter().map(|provider| AnyStream::new::<E::Packet>(Box::pin(provider))).collect(),
+            tasks: vec![Box::pin(link.consumer)],
+            latency: None,
+            handlers: None,
+            state: None
+        })
+    }
+}
+
+/// A JoinLink merging as many inputs as are connected to it. The scheduler
+/// is made once the number of inputs is known.
+pub struct JoinNode<T> {
+    scheduler: fn(usize) -> Box<dyn JoinScheduler<T> + Send>
+}
+
+impl<T> JoinNode<T> {
+    pub fn new(scheduler: fn(usize) -> Box<dyn JoinScheduler<T> + Send>) -> Self {
+        JoinNode { scheduler }
+    }
+
+    pub fn round_robin() -> Self {
+        JoinNode::new(|num_inputs| Box::new(RoundRobin::new(num_inputs)))
+    }
+}
+
+impl<T: Send + 'static> DynElement for JoinNode<T> {
+    fn kind(&self) -> NodeKind { NodeKind::Join }
+    fn inputs(&self) -> Option<usize> { None }
+    fn outputs(&self) -> Option<usize> { Some(1) }
+    fn input_type(&self) -> Option<PacketType> { Some(PacketType::of::<T>()) }
+    fn output_type(&self) -> Option<PacketType> { Some(PacketType::of::<T>()) }
+
+    fn build(self: Box<Self>, inputs: Vec<AnyStream>, _num_outputs: usize) -> Result<Built, String> {
+        let num_inputs = inputs.len();
+        let input_streams = inputs.into_iter().enumerate()
+            .map(|(port, input)| downcast_input::<T>(input, port))
+            .collect::<Result<Vec<_>, _>>()?;
+        let link = JoinLink::new(input_streams, (self.scheduler)(num_inputs));
+        Ok(Built {
+            outputs: vec![AnyStream::new::<T>(Box::pin(link))],
+            tasks: vec![],
+            metrics: vec![],
+            latency: None,
+            handlers: None,
+            state: None
+        })
+    }
+}
+
+/// A TeeLink with as many branches as are connected to it. Its outputs
+/// carry `Arc<T>`.
+pub struct TeeNode<T> {
+    queue_capacity: usize,
+    policy: TeePolicy,
+    phantom: PhantomData<fn(T)>
+}
+
+impl<T> TeeNode<T> {
+    pub fn new(queue_capacity: usize, policy: TeePolicy) -> Self {
+        TeeNode { queue_capacity, policy, phantom: PhantomData }
+    }
+}
+
+impl<T: Send + Sync + 'static> DynElement for TeeNode<T> {
+    fn kind(&self) -> NodeKind { NodeKind::Tee }
+    fn inputs(&self) -> Option<usize> { Some(1) }
+    fn outputs(&self) -> Option<usize> { None }
+    fn input_type(&self) -> Option<PacketType> { Some(PacketType::of::<T>()) }
+    fn output_type(&self) -> Option<PacketType> { Some(PacketType::of::<Arc<T>>()) }
+
+    fn build(self: Box<Self>, inputs: Vec<AnyStream>, num_outputs: usize) -> Result<Built, String> {
+        let link = TeeLink::new(single_input::<T>(inputs)?, self.queue_capacity, num_outputs, self.policy);
+        Ok(Built {
+            metrics: (0..num_outputs).map(|branch| link.consumer.metrics(branch)).collect(),
+            outputs: link.providers.into_iter().map(|provider| AnyStream::new::<Arc<T>>(Box::pin(provider))).collect(),
+            tasks: vec![Box::pin(link.consumer)],
+            latency: None,
+            handlers: None,
+            state: None
+        })
+    }
+}
+
+/// The end of the graph, such as a drain or a network interface's transmit
+/// side. `sink` is handed the input stream, and returns the task to spawn.
+pub struct SinkNode<T, F> {
+    sink: F,
+    phantom: PhantomData<fn(T)>
+}
+
+impl<T, F> SinkNode<T, F> {
+    pub fn new(sink: F) -> Self {
+        SinkNode { sink, phantom: PhantomData }
+    }
+}
+
+impl<T, F, S> DynElement for SinkNode<T, F>
+    where T: 'static,
+          F: FnOnce(ElementStream<T>) -> S + Send,
+          S: Future<Output = ()> + Send + 'static
+{
+    fn kind(&self) -> NodeKind { NodeKind::Sink }
+    fn inputs(&self) -> Option<usize> { Some(1) }
+    fn outputs(&self) -> Option<usize> { Some(0) }
+    fn input_type(&self) -> Option<PacketType> { Some(PacketType::of::<T>()) }
+    fn output_type(&self) -> Option<PacketType> { None }
+
+    fn build(self: Box<Self>, inputs: Vec<AnyStream>, _num_outputs: usize) -> Result<Built, String> {
+        let task = (self.sink)(single_input::<T>(inputs)?);
+        Ok(Built {
+            outputs: vec![],
+            tasks: vec![Box::pin(task)],
+            metrics: vec![],
+            latency: None,
+            handlers: None,
+            state: None
+        })
+    }
+}
+
+/// Wraps another DynElement to make the handlers of its element reachable
+/// from the router, for elements that were wrapped in a Shared.
+pub struct HandlersNode<N: DynElement> {
+    node: N,
+    handlers: SharedHandlers
+}
+
+impl<N: DynElement> HandlersNode<N> {
+    pub fn new(node: N, handlers: SharedHandlers) -> Self {
+        HandlersNode { node, handlers }
+    }
+}
+
+impl<N: DynElement> DynElement for HandlersNode<N> {
+    fn kind(&self) -> NodeKind { self.node.kind() }
+    fn inputs(&self) -> Option<usize> { self.node.inputs() }
+    fn outputs(&self) -> Option<usize> { self.node.outputs() }
+    fn input_type(&self) -> Option<PacketType> { self.node.input_type() }
+    fn output_type(&self) -> Option<PacketType> { self.node.output_type() }
+
+    fn stop_on(&mut self, shutdown: &ShutdownRequest) {
+        self.node.stop_on(shutdown);
+    }
+
+    fn build(self: Box<Self>, inputs: Vec<AnyStream>, num_outputs: usize) -> Result<Built, String> {
+        let built = Box::new(self.node).build(inputs, num_outputs)?;
+        Ok(Built { handlers: Some(self.handlers), ..built })
+    }
+}
+
+/// Wraps another DynElement to carry its element's state over when the
+/// router is reconfigured, for elements that were wrapped in a Shared.
+pub struct StateNode<N: DynElement> {
+    node: N,
+    state: SharedState
+}
+
+impl<N: DynElement> StateNode<N> {
+    pub fn new(node: N, state: SharedState) -> Self {
+        StateNode { node, state }
+    }
+}
+
+impl<N: DynElement> DynElement for StateNode<N> {
+    fn kind(&self) -> NodeKind { self.node.kind() }
+    fn inputs(&self) -> Option<usize> { self.node.inputs() }
+    fn outputs(&self) -> Option<usize> { self.node.outputs() }
+    fn input_type(&self) -> Option<PacketType> { self.node.input_type() }
+    fn output_type(&self) -> Option<PacketType> { self.node.output_type() }
+
+    fn stop_on(&mut self, shutdown: &ShutdownRequest) {
+        self.node.stop_on(shutdown);
+    }
+
+    fn build(self: Box<Self>, inputs: Vec<AnyStream>, num_outputs: usize) -> Result<Built, String> {
+        let built = Box::new(self.node).build(inputs, num_outputs)?;
+        Ok(Built { state: Some(self.state), ..built })
+    }
+}
+
+/// Builds a DynElement from its arguments, once they have been checked
+/// against the class's Schema. An Err carries a message explaining what was
+/// wrong with the arguments.
+pub type Constructor = Box<dyn Fn(&Args) -> Result<Box<dyn DynElement>, String> + Send + Sync>;
+
+struct Class {
+    schema: Schema,
+    constructor: Constructor
+}
+
+/// The Registry maps element class names, as used in configuration files,
+/// to the arguments they take and the constructors that build them.
+#[derive(Default)]
+pub struct Registry {
+    classes: HashMap<String, Class>
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry::default()
+    }
+
+    /// Registers `constructor` under the class name `name`, replacing any
+    /// class previously registered under that name.
+    pub fn register<F>(&mut self, name: &str, schema: Schema, constructor: F)
+        where F: Fn(&Args) -> Result<Box<dyn DynElement>, String> + Send + Sync + 'static
+    {
+        self.classes.insert(name.to_string(), Class { schema, constructor: Box::new(constructor) });
+    }
+
+    /// Registers an Element class, to be wrapped in an ElementLink.
+    pub fn register_element<E, F>(&mut self, name: &str, schema: Schema, constructor: F)
+        where E: Element + Send + 'static,
+              E::Input: Send + 'static,
+              E::Output: Send + 'static,
+              F: Fn(&Args) -> Result<E, String> + Send + Sync + 'static
+    {
+        self.register(name, schema, move |args| {
+            Ok(Box::new(SyncNode::new(constructor(args)?)))
+        });
+    }
+
+    /// Registers an AsyncElement class, to be wrapped in an AsyncElementLink
+    /// with a queue of `queue_capacity` packets.
+    pub fn register_async_element<E, F>(&mut self, name: &str, schema: Schema, queue_capacity: usize, constructor: F)
+        where E: AsyncElement + Send + 'static,
+              E::Input: Send + 'static,
+              E::Output: Send + 'static,
+              F: Fn(&Args) -> Result<E, String> + Send + Sync + 'static
+    {
+        self.register(name, schema, move |args| {
+            Ok(Box::new(AsyncNode::new(constructor(args)?, queue_capacity)))
+        });
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.classes.contains_key(name)
+    }
+
+    /// The arguments taken by class `name`, if it is registered.
+    pub fn schema(&self, name: &str) -> Option<&Schema> {
+        self.classes.get(name).map(|class| &class.schema)
+    }
+
+    /// Builds an element of class `name`, after checking `args` against its
+    /// schema. Returns None if no such class is registered.
+    pub fn construct(&self, name: &str, args: &[String]) -> Option<Result<Box<dyn DynElement>, String>> {
+        self.classes.get(name).map(|class| {
+            let args = class.schema.parse(args)?;
+            (class.constructor)(&args)
+        })
+    }
+}
@@ -0,0 +1,154 @@
+use futures::Stream;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use tokio::sync::Notify;
+
+#[derive(Debug, Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    notify: Notify
+}
+
+/// A ShutdownRequest is shared between a router and whoever may ask it to
+/// shut down, such as the control socket. Sources are wrapped in a Valve
+/// cut off by the request, and once they stop, everything already in the
+/// graph drains out through the usual tear-down.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownRequest(Arc<ShutdownState>);
+
+impl ShutdownRequest {
+    pub fn new() -> Self {
+        ShutdownRequest::default()
+    }
+
+    pub fn request(&self) {
+        self.0.requested.store(true, Ordering::SeqCst);
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.0.requested.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once shutdown has been requested, straight away if it
+    /// already has been.
+    pub async fn requested(&self) {
+        // The Notified future has to exist before we check the flag, or we
+        // could miss a request landing in between.
+        let notified = self.0.notify.notified();
+        if self.is_requested() {
+            return
+        }
+        notified.await
+    }
+
+    /// Wraps a source's stream so that it ends once shutdown is requested.
+    pub fn valve<S: Stream + Unpin>(&self, stream: S) -> Valve<S> {
+        let shutdown = self.clone();
+        Valve {
+            stream: Some(stream),
+            requested: Box::pin(async move { shutdown.requested().await })
+        }
+    }
+}
+
+/// A Valve passes packets through from its stream until shutdown is
+/// requested, and then returns Ready(None), as if the stream had run dry.
+/// The stream itself is dropped at that point, so whatever it holds on to,
+/// such as a socket, is let go of straight away.
+pub struct Valve<S> {
+    stream: Option<S>,
+    requested: Pin<Box<dyn Future<Output = ()> + Send>>
+}
+
+impl<S: Stream + Unpin> Stream for Valve<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        let valve = self.get_mut();
+        if valve.stream.is_some() && valve.requested.as_mut().poll(cx).is_ready() {
+            valve.stream = None;
+        }
+        match valve.stream.as_mut() {
+            Some(stream) => Pin::new(stream).poll_next(cx),
+            None => Poll::Ready(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::{AsyncElement, AsyncElementLink, Verdict};
+    use crate::router::{NodeKind, RouterError, RouterGraph};
+    use crate::utils::test::packet_collectors::ExhaustiveCollector;
+    use futures::stream;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use tokio::runtime::Handle;
+
+    struct Identity;
+
+    impl AsyncElement for Identity {
+        type Input = i32;
+        type Output = i32;
+
+        fn process(&mut self, packet: Self::Input) -> Verdict<Self::Output> {
+            Verdict::Pass(packet)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drains_queues_on_shutdown() {
+        let mut router = RouterGraph::new();
+        let shutdown = router.shutdown_request();
+        let src = router.add_source("src");
+        let source = shutdown.valve(stream::iter(0..));
+        let link = AsyncElementLink::new(Box::pin(source), Identity, 4);
+        let (node, provider) = router.add_async_link("identity", src, link);
+        let packets = Arc::new(Mutex::new(Vec::new()));
+        router.add_sink("drain", node, ExhaustiveCollector::new(0, Box::pin(provider), Arc::clone(&packets)));
+
+        let metrics = router.metrics();
+        let handle = router.spawn(&Handle::current());
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            shutdown.request();
+        });
+        handle.run(Duration::from_secs(5)).await.unwrap();
+
+        // Every packet that made it past the valve came out the other end.
+        let packets = packets.lock().unwrap();
+        assert!(!packets.is_empty());
+        assert_eq!(*packets, (0..packets.len() as i32).collect::<Vec<i32>>());
+        assert_eq!(metrics.links[0].2.snapshot().queue_depth, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn aborts_tasks_that_do_not_drain() {
+        let mut router = RouterGraph::new();
+        let src = router.add_source("src");
+        let source = router.shutdown_request().valve(stream::iter(0..));
+        let link = AsyncElementLink::new(Box::pin(source), Identity, 2);
+        let (node, provider) = router.add_async_link("identity", src, link);
+
+        // Never pulls from its provider, so the consumer sleeps on a full
+        // queue and never notices the valve closing.
+        let stuck = router.add_node("stuck", NodeKind::Sink, 1, 0);
+        router.connect(node, stuck, 0);
+        router.add_task(stuck, async move {
+            let _provider = provider;
+            futures::future::pending::<()>().await
+        });
+
+        let handle = router.spawn(&Handle::current());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        match handle.shutdown(Duration::from_millis(50)).await {
+            Err(RouterError::DrainTimedOut(names)) => assert_eq!(names, vec!["identity", "stuck"]),
+            other => panic!("expected the drain to time out, got {:?}", other)
+        }
+    }
+}
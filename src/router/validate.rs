@@ -0,0 +1,252 @@
+use std::fmt;
+use crate::router::{NodeId, RouterGraph};
+
+fn direction(output: bool) -> &'static str {
+    if output { "output" } else { "input" }
+}
+
+/// Something wrong with a RouterGraph, naming the elements involved.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// A port that nothing is connected to. An unconnected input has nothing
+    /// to pull from, and an unconnected output is never pulled.
+    Unconnected { element: String, port: usize, output: bool },
+    /// A port with more than one connection. Every stream can only be pulled
+    /// by one element, so only one of them could actually be built.
+    ConnectedTwice { element: String, port: usize, output: bool },
+    /// A connection between an output and an input expecting a different
+    /// type of packet.
+    TypeMismatch { from: String, to: String, output_type: String, input_type: String },
+    /// A cycle of elements, in order, each of which pulls from the one
+    /// before it. No element in the cycle can make progress until another
+    /// one does.
+    Cycle(Vec<String>),
+    /// An element with no task of its own, and no task downstream of it to
+    /// pull packets through it, such as a chain of ElementLinks without an
+    /// AsyncElementLink or sink at the end.
+    Undriven(String)
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Unconnected { element, port, output } =>
+                write!(f, "{} port {} of {} is not connected", direction(*output), port, element),
+            Problem::ConnectedTwice { element, port, output } =>
+                write!(f, "{} port {} of {} is connected more than once", direction(*output), port, element),
+            Problem::TypeMismatch { from, to, output_type, input_type } =>
+                write!(f, "{} provides {}, but {} takes {}", from, output_type, to, input_type),
+            Problem::Cycle(elements) => write!(f, "cycle through {}", elements.join(" -> ")),
+            Problem::Undriven(element) => write!(f, "{} is not driven by any task", element)
+        }
+    }
+}
+
+/// Every Problem found by `RouterGraph::validate`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ValidationReport {
+    pub problems: Vec<Problem>
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "router graph has {} problem(s):", self.problems.len())?;
+        for problem in &self.problems {
+            write!(f, "\n  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationReport {}
+
+fn check_ports(graph: &RouterGraph, problems: &mut Vec<Problem>) {
+    let nodes = graph.nodes();
+    let mut inputs = nodes.iter().map(|node| vec![0; node.inputs]).collect::<Vec<Vec<usize>>>();
+    let mut outputs = nodes.iter().map(|node| vec![0; node.outputs]).collect::<Vec<Vec<usize>>>();
+    for edge in graph.edges() {
+        outputs[edge.from.node.0][edge.from.port] += 1;
+        inputs[edge.to.0][edge.to_port] += 1;
+    }
+
+    for (index, node) in nodes.iter().enumerate() {
+        let ports = inputs[index].iter().map(|count| (count, false))
+            .enumerate()
+            .chain(outputs[index].iter().map(|count| (count, true)).enumerate());
+        for (port, (count, output)) in ports {
+            let element = node.name.clone();
+            match count {
+                0 => problems.push(Problem::Unconnected { element, port, output }),
+                1 => (),
+                _ => problems.push(Problem::ConnectedTwice { element, port, output })
+            }
+        }
+    }
+}
+
+fn check_types(graph: &RouterGraph, problems: &mut Vec<Problem>) {
+    for edge in graph.edges() {
+        let from = graph.node(edge.from.node);
+        let to = graph.node(edge.to);
+        if let (Some(output_type), Some(input_type)) = (from.output_type, to.input_type) {
+            if output_type != input_type {
+                problems.push(Problem::TypeMismatch {
+                    from: from.name.clone(),
+                    to: to.name.clone(),
+                    output_type: output_type.name().to_string(),
+                    input_type: input_type.name().to_string()
+                });
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    Unvisited,
+    OnStack,
+    Done
+}
+
+/// Depth first search along the direction packets flow, reporting a cycle
+/// whenever we come back to a node still on the stack.
+fn find_cycles(graph: &RouterGraph, node: NodeId, visits: &mut Vec<Visit>, stack: &mut Vec<NodeId>, problems: &mut Vec<Problem>) {
+    visits[node.0] = Visit::OnStack;
+    stack.push(node);
+    for edge in graph.edges().iter().filter(|edge| edge.from.node == node) {
+        match visits[edge.to.0] {
+            Visit::Unvisited => find_cycles(graph, edge.to, visits, stack, problems),
+            Visit::OnStack => {
+                let start = stack.iter().position(|id| *id == edge.to).unwrap();
+                let elements = stack[start..].iter().map(|id| graph.node(*id).name.clone()).collect();
+                problems.push(Problem::Cycle(elements));
+            },
+            Visit::Done => ()
+        }
+    }
+    stack.pop();
+    visits[node.0] = Visit::Done;
+}
+
+fn check_cycles(graph: &RouterGraph, problems: &mut Vec<Problem>) {
+    let mut visits = vec![Visit::Unvisited; graph.nodes().len()];
+    let mut stack = vec![];
+    for index in 0..graph.nodes().len() {
+        if visits[index] == Visit::Unvisited {
+            find_cycles(graph, NodeId(index), &mut visits, &mut stack, problems);
+        }
+    }
+}
+
+/// A node is driven if it has a task of its own, or if anything pulling
+/// from it is driven. We spread that upstream until nothing changes.
+fn check_driven(graph: &RouterGraph, problems: &mut Vec<Problem>) {
+    let mut driven = graph.nodes().iter().map(|node| node.kind.has_task()).collect::<Vec<bool>>();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for edge in graph.edges() {
+            if driven[edge.to.0] && !driven[edge.from.node.0] {
+                driven[edge.from.node.0] = true;
+                changed = true;
+            }
+        }
+    }
+
+    for (node, driven) in graph.nodes().iter().zip(driven) {
+        if !driven {
+            problems.push(Problem::Undriven(node.name.clone()));
+        }
+    }
+}
+
+pub fn validate(graph: &RouterGraph) -> Result<(), ValidationReport> {
+    let mut problems = vec![];
+    check_ports(graph, &mut problems);
+    check_types(graph, &mut problems);
+    check_cycles(graph, &mut problems);
+    check_driven(graph, &mut problems);
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(ValidationReport { problems })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::{NodeKind, PacketType};
+
+    fn problems(graph: &RouterGraph) -> Vec<Problem> {
+        graph.validate().err().map_or(vec![], |report| report.problems)
+    }
+
+    #[test]
+    fn valid_graph_has_no_problems() {
+        let mut graph = RouterGraph::new();
+        let src = graph.add_source("src");
+        let elem = graph.add_node("elem", NodeKind::Sync, 1, 1);
+        graph.connect(src, elem, 0);
+        let queue = graph.add_node("queue", NodeKind::Async, 1, 1);
+        graph.connect(elem, queue, 0);
+        let join = graph.add_join_link("join", vec![queue.into()]);
+        let drain = graph.add_node("drain", NodeKind::Sink, 1, 0);
+        graph.connect(join, drain, 0);
+
+        assert_eq!(graph.validate(), Ok(()));
+    }
+
+    #[test]
+    fn reports_dangling_and_doubly_connected_ports() {
+        let mut graph = RouterGraph::new();
+        let src = graph.add_source("src");
+        let classifier = graph.add_node("classifier", NodeKind::Classify, 1, 2);
+        graph.connect(src, classifier, 0);
+        let drain = graph.add_node("drain", NodeKind::Sink, 1, 0);
+        graph.connect(classifier.port(0), drain, 0);
+        graph.connect(classifier.port(0), drain, 0);
+
+        assert_eq!(problems(&graph), vec![
+            Problem::ConnectedTwice { element: "classifier".to_string(), port: 0, output: true },
+            Problem::Unconnected { element: "classifier".to_string(), port: 1, output: true },
+            Problem::ConnectedTwice { element: "drain".to_string(), port: 0, output: false }
+        ]);
+    }
+
+    #[test]
+    fn reports_type_mismatches() {
+        let mut graph = RouterGraph::new();
+        let src = graph.add_source("src");
+        graph.set_packet_types(src, None, Some(PacketType::of::<u8>()));
+        let drain = graph.add_node("drain", NodeKind::Sink, 1, 0);
+        graph.set_packet_types(drain, Some(PacketType::of::<i32>()), None);
+        graph.connect(src, drain, 0);
+
+        assert_eq!(problems(&graph), vec![Problem::TypeMismatch {
+            from: "src".to_string(),
+            to: "drain".to_string(),
+            output_type: "u8".to_string(),
+            input_type: "i32".to_string()
+        }]);
+    }
+
+    #[test]
+    fn reports_cycles_and_undriven_chains() {
+        let mut graph = RouterGraph::new();
+        let src = graph.add_source("src");
+        let join = graph.add_node("join", NodeKind::Join, 2, 1);
+        graph.connect(src, join, 0);
+        let elem = graph.add_node("elem", NodeKind::Sync, 1, 1);
+        graph.connect(join, elem, 0);
+        graph.connect(elem, join, 1);
+
+        assert_eq!(problems(&graph), vec![
+            Problem::Cycle(vec!["join".to_string(), "elem".to_string()]),
+            Problem::Undriven("src".to_string()),
+            Problem::Undriven("join".to_string()),
+            Problem::Undriven("elem".to_string())
+        ]);
+    }
+}
@@ -1,6 +1,10 @@
 use crate::api::ElementStream;
-use futures::{Async, Poll, Future};
+use futures::ready;
 use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 
 pub struct ExhaustiveDrain<T: Debug> {
     id: usize,
@@ -14,20 +18,52 @@ impl<T: Debug> ExhaustiveDrain<T> {
 }
 
 impl<T: Debug> Future for ExhaustiveDrain<T> {
-    type Item = ();
-    type Error = ();
+    type Output = ();
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         // println!("Drain #{} poll", self.id);
 
         loop {
-            match try_ready!(self.stream.poll()) {
+            match ready!(self.stream.as_mut().poll_next(cx)) {
                 Some(value) => {
                     println!("Drain #{} received packet: {:?}", self.id, value);
                 },
                 None => {
                     println!("Drain #{} received none. End of packet stream", self.id);
-                    return Ok(Async::Ready(()))
+                    return Poll::Ready(())
+                }
+            }
+        }
+    }
+}
+
+/// Drains a stream like ExhaustiveDrain, but keeps every packet it receives
+/// in a shared Vec, so a test can inspect what made it through the pipeline
+/// once the runtime has shut down.
+pub struct ExhaustiveCollector<T: Debug> {
+    id: usize,
+    stream: ElementStream<T>,
+    packet_dump: Arc<Mutex<Vec<T>>>
+}
+
+impl<T: Debug> ExhaustiveCollector<T> {
+    pub fn new(id: usize, stream: ElementStream<T>, packet_dump: Arc<Mutex<Vec<T>>>) -> Self {
+        ExhaustiveCollector { id, stream, packet_dump }
+    }
+}
+
+impl<T: Debug> Future for ExhaustiveCollector<T> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        loop {
+            match ready!(self.stream.as_mut().poll_next(cx)) {
+                Some(value) => {
+                    self.packet_dump.lock().unwrap().push(value);
+                },
+                None => {
+                    println!("Collector #{} received none. End of packet stream", self.id);
+                    return Poll::Ready(())
                 }
             }
         }
@@ -1,16 +1,18 @@
 use crate::api::ElementStream;
-use futures::{stream, Stream, Async, Poll};
-use tokio::timer::Interval;
+use futures::{stream, Stream, ready};
+use tokio::time::{interval, Interval};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Duration;
 
 
 // Immediately yields a collection of packets to be poll'd.
-// Thin wrapper around iter_ok.
+// Thin wrapper around stream::iter.
 pub fn immediate_stream<I>(collection: I) -> ElementStream<I::Item>
     where I: IntoIterator,
           I::IntoIter: Send + 'static
 {
-    Box::new(stream::iter_ok::<_, ()>(collection))
+    Box::pin(stream::iter(collection))
 }
 
 /*
@@ -18,7 +20,7 @@ pub fn immediate_stream<I>(collection: I) -> ElementStream<I::Item>
 
     Generates a series of monotonically increasing integers, starting at 0.
     `iterations` "packets" are generated in the stream. One is yielded every
-    `duration`.
+    `duration`. Must be created from within a tokio runtime.
 */
 
 pub struct LinearIntervalGenerator {
@@ -30,7 +32,7 @@ pub struct LinearIntervalGenerator {
 impl LinearIntervalGenerator {
     pub fn new(duration: Duration, iterations: usize) -> Self {
         LinearIntervalGenerator {
-            interval: Interval::new_interval(duration),
+            interval: interval(duration),
             iterations,
             seq_num: 0
         }
@@ -39,14 +41,13 @@ impl LinearIntervalGenerator {
 
 impl Stream for LinearIntervalGenerator {
     type Item = i32;
-    type Error = ();
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, ()> {
-        try_ready!(self.interval.poll().map_err(|_| ()));
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        ready!(self.interval.poll_tick(cx));
         if self.seq_num as usize > self.iterations {
-            Ok(Async::Ready(None))
+            Poll::Ready(None)
         } else {
-            let next_packet = Ok(Async::Ready(Some(self.seq_num)));
+            let next_packet = Poll::Ready(Some(self.seq_num));
             self.seq_num += 1;
             next_packet
         }